@@ -0,0 +1,56 @@
+use std::fs;
+use std::path::Path;
+
+// static/ is embedded at compile time, a vendored library missing there would only show up as a 404 at
+// runtime. release builds fail, dev builds (and SKIP_VENDOR_CHECK=1) only warn so a checkout without the
+// files still builds and tests
+const VENDOR_PREFIX: &str = "/static/vendor/";
+
+fn main() {
+    println!("cargo:rerun-if-changed=templates");
+    println!("cargo:rerun-if-changed=static/vendor");
+    println!("cargo:rerun-if-env-changed=SKIP_VENDOR_CHECK");
+    println!("cargo:rerun-if-env-changed=PROFILE");
+
+    let mut referenced = Vec::new();
+    collect_references(Path::new("templates"), &mut referenced);
+    referenced.sort();
+    referenced.dedup();
+
+    let missing: Vec<_> = referenced
+        .iter()
+        .filter(|file| fs::metadata(Path::new("static/vendor").join(file)).map_or(true, |meta| meta.len() == 0))
+        .collect();
+    if missing.is_empty() {
+        return;
+    }
+
+    let message = format!(
+        "static/vendor/ is missing {}, run ./scripts/vendor.sh",
+        missing.iter().map(|file| file.as_str()).collect::<Vec<_>>().join(", ")
+    );
+    let release = std::env::var("PROFILE").is_ok_and(|profile| profile == "release");
+    if !release || std::env::var_os("SKIP_VENDOR_CHECK").is_some() {
+        println!("cargo:warning={}", message);
+    } else {
+        panic!("{}", message);
+    }
+}
+
+// file names after /static/vendor/ in every template
+fn collect_references(dir: &Path, referenced: &mut Vec<String>) {
+    let Ok(entries) = fs::read_dir(dir) else { return };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            collect_references(&path, referenced);
+            continue;
+        }
+        let Ok(text) = fs::read_to_string(&path) else { continue };
+        for (start, _) in text.match_indices(VENDOR_PREFIX) {
+            let rest = &text[start + VENDOR_PREFIX.len()..];
+            let end = rest.find(['"', '\'', '?', '#', ' ', '>']).unwrap_or(rest.len());
+            referenced.push(rest[..end].to_string());
+        }
+    }
+}
@@ -0,0 +1,40 @@
+-- no notifications for a device, a schedule (rule) or both until ends_at
+CREATE TABLE alert_silences (
+    id SERIAL PRIMARY KEY,
+    fk_device_id INTEGER REFERENCES devices(id),
+    fk_schedule_id INTEGER REFERENCES noise_schedules(id) ON DELETE CASCADE,
+    reason TEXT,
+    starts_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    ends_at TIMESTAMPTZ NOT NULL,
+    fk_created_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    cancelled_at TIMESTAMPTZ,
+    fk_cancelled_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    CHECK (fk_device_id IS NOT NULL OR fk_schedule_id IS NOT NULL),
+    CHECK (ends_at > starts_at)
+);
+
+CREATE INDEX idx_alert_silences_ends_at ON alert_silences(ends_at);
+
+-- a device going over the limit of a schedule. crossings while the alert is open only bump it,
+-- so a noisy night is one alert and one notification
+CREATE TABLE alerts (
+    id SERIAL PRIMARY KEY,
+    fk_device_id INTEGER NOT NULL REFERENCES devices(id),
+    fk_schedule_id INTEGER REFERENCES noise_schedules(id) ON DELETE SET NULL,
+    schedule TEXT NOT NULL,
+    period TEXT NOT NULL,
+    limit_db DOUBLE PRECISION NOT NULL,
+    peak_db DOUBLE PRECISION NOT NULL,
+    occurrences INTEGER NOT NULL DEFAULT 1,
+    started_at TIMESTAMPTZ NOT NULL,
+    last_seen_at TIMESTAMPTZ NOT NULL,
+    -- raised while this silence was active, nobody was notified
+    fk_silence_id INTEGER REFERENCES alert_silences(id) ON DELETE SET NULL,
+    acknowledged_at TIMESTAMPTZ,
+    fk_acknowledged_by INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    acknowledge_comment TEXT
+);
+
+CREATE INDEX idx_alerts_device_time ON alerts(fk_device_id, started_at DESC);
+CREATE INDEX idx_alerts_last_seen_at ON alerts(last_seen_at DESC);
@@ -0,0 +1,48 @@
+-- groups of devices that share settings such as noise limit schedules
+CREATE TABLE device_groups (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL UNIQUE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE TABLE device_group_members (
+    fk_group_id INTEGER NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+    fk_device_id INTEGER NOT NULL REFERENCES devices(id),
+    PRIMARY KEY (fk_group_id, fk_device_id)
+);
+
+CREATE INDEX idx_device_group_members_fk_device_id ON device_group_members(fk_device_id);
+
+-- time-of-day noise limits, evaluated in the schedule's local time zone
+CREATE TABLE noise_schedules (
+    id SERIAL PRIMARY KEY,
+    name TEXT NOT NULL,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- weekdays are iso day numbers (1 = monday) of the day the period starts on,
+-- periods with end_time <= start_time run past midnight into the next day
+CREATE TABLE noise_schedule_periods (
+    id SERIAL PRIMARY KEY,
+    fk_schedule_id INTEGER NOT NULL REFERENCES noise_schedules(id) ON DELETE CASCADE,
+    name TEXT NOT NULL,
+    weekdays SMALLINT[] NOT NULL DEFAULT '{1,2,3,4,5,6,7}',
+    start_time TIME NOT NULL,
+    end_time TIME NOT NULL,
+    limit_db DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX idx_noise_schedule_periods_fk_schedule_id ON noise_schedule_periods(fk_schedule_id);
+
+-- a schedule applies to either a single device or a whole group, device assignments take precedence
+CREATE TABLE noise_schedule_assignments (
+    id SERIAL PRIMARY KEY,
+    fk_schedule_id INTEGER NOT NULL REFERENCES noise_schedules(id) ON DELETE CASCADE,
+    fk_device_id INTEGER REFERENCES devices(id),
+    fk_group_id INTEGER REFERENCES device_groups(id) ON DELETE CASCADE,
+    CHECK ((fk_device_id IS NULL) <> (fk_group_id IS NULL))
+);
+
+CREATE UNIQUE INDEX idx_noise_schedule_assignments_device ON noise_schedule_assignments(fk_device_id) WHERE fk_device_id IS NOT NULL;
+CREATE UNIQUE INDEX idx_noise_schedule_assignments_group ON noise_schedule_assignments(fk_group_id) WHERE fk_group_id IS NOT NULL;
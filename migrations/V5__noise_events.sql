@@ -0,0 +1,15 @@
+-- discrete noise events found by the streaming detector in the ingest path
+CREATE TABLE noise_events (
+    id SERIAL PRIMARY KEY,
+    fk_device_id INTEGER NOT NULL REFERENCES devices(id),
+    started_at TIMESTAMPTZ NOT NULL,
+    ended_at TIMESTAMPTZ NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    peak_db DOUBLE PRECISION NOT NULL,
+    leq_db DOUBLE PRECISION NOT NULL,
+    sel_db DOUBLE PRECISION NOT NULL,
+    background_db DOUBLE PRECISION NOT NULL
+);
+
+CREATE INDEX idx_noise_events_device_time ON noise_events(fk_device_id, started_at DESC);
+CREATE INDEX idx_noise_events_started_at ON noise_events(started_at);
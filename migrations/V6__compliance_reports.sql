@@ -0,0 +1,27 @@
+-- generated report bundles, files live on disk under the reports directory by id
+CREATE TABLE compliance_reports (
+    id SERIAL PRIMARY KEY,
+    fk_group_id INTEGER NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+    fk_schedule_id INTEGER,
+    range_start TIMESTAMPTZ NOT NULL,
+    range_end TIMESTAMPTZ NOT NULL,
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    status TEXT NOT NULL DEFAULT 'pending',
+    error TEXT,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_compliance_reports_created_at ON compliance_reports(created_at DESC);
+
+-- recurring report generation per site, each run covers the period that just ended
+CREATE TABLE report_schedules (
+    id SERIAL PRIMARY KEY,
+    fk_group_id INTEGER NOT NULL REFERENCES device_groups(id) ON DELETE CASCADE,
+    frequency TEXT NOT NULL CHECK (frequency IN ('daily', 'weekly', 'monthly')),
+    timezone TEXT NOT NULL DEFAULT 'UTC',
+    next_run_at TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+ALTER TABLE compliance_reports
+    ADD CONSTRAINT fk_compliance_reports_schedule FOREIGN KEY (fk_schedule_id) REFERENCES report_schedules(id) ON DELETE SET NULL;
@@ -0,0 +1,28 @@
+-- a grant without a group applies everywhere, otherwise only to the devices of that group (site)
+CREATE TABLE user_roles (
+    id SERIAL PRIMARY KEY,
+    fk_user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK (role IN ('admin', 'operator', 'viewer')),
+    fk_group_id INTEGER REFERENCES device_groups(id) ON DELETE CASCADE
+);
+
+CREATE UNIQUE INDEX idx_user_roles_global ON user_roles(fk_user_id, role) WHERE fk_group_id IS NULL;
+CREATE UNIQUE INDEX idx_user_roles_group ON user_roles(fk_user_id, role, fk_group_id) WHERE fk_group_id IS NOT NULL;
+
+-- users from before roles existed keep full access
+INSERT INTO user_roles (fk_user_id, role) SELECT id, 'admin' FROM users;
+
+-- privileged (non read) requests and logins, allowed or denied
+CREATE TABLE audit_log (
+    id BIGSERIAL PRIMARY KEY,
+    fk_user_id INTEGER REFERENCES users(id) ON DELETE SET NULL,
+    username TEXT,
+    action TEXT NOT NULL,
+    path TEXT,
+    permission TEXT,
+    status INTEGER NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_audit_log_created_at ON audit_log(created_at DESC);
+CREATE INDEX idx_audit_log_fk_user_id ON audit_log(fk_user_id);
@@ -0,0 +1,8 @@
+-- shown on the device detail page, calibration is informational, readings are stored as sent
+ALTER TABLE devices ADD COLUMN location TEXT;
+ALTER TABLE devices ADD COLUMN model TEXT;
+ALTER TABLE devices ADD COLUMN serial_number TEXT;
+ALTER TABLE devices ADD COLUMN calibration_offset_db DOUBLE PRECISION;
+ALTER TABLE devices ADD COLUMN calibrated_at TIMESTAMPTZ;
+-- null for devices whose token was issued before this was recorded
+ALTER TABLE devices ADD COLUMN token_issued_at TIMESTAMPTZ;
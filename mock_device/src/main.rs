@@ -1,6 +1,6 @@
 use rand::Rng;
 use reqwest::Client;
-use serde::{Deserialize, Serialize};
+use serde::Serialize;
 use std::fs;
 use std::path::Path;
 use std::time::Duration;
@@ -14,11 +14,6 @@ struct LogData {
     decibels: f64,
 }
 
-#[derive(Deserialize)]
-struct AuthResponse {
-    token: Option<String>,
-}
-
 struct DecibelSimulator {
     t: i32,
     sine_amplitude: f64,
@@ -80,49 +75,27 @@ impl DecibelSimulator {
     }
 }
 
-async fn fetch_token(client: &Client) -> Result<String, Box<dyn std::error::Error>> {
-    let response = client.get(&format!("{}/api/auth", BASE_URL)).send().await?;
-    
-    if !response.status().is_success() {
-        return Err(format!("auth request failed ({})", response.status()).into());
-    }
-
-    // check for token in header first
-    if let Some(token) = response.headers().get("x-device-token") {
-        let token_str = token.to_str()?.to_string();
-        fs::write(TOKEN_FILE, &token_str)?;
-        println!("obtained new token from header");
-        return Ok(token_str);
+// devices are registered by an admin (`dbmonitor device create`), the token comes from DEVICE_TOKEN or token.txt
+fn get_token() -> Result<String, Box<dyn std::error::Error>> {
+    if let Ok(token) = std::env::var("DEVICE_TOKEN")
+        && !token.trim().is_empty()
+    {
+        return Ok(token.trim().to_string());
     }
-
-    // fallback to JSON response
-    let auth_response: AuthResponse = response.json().await?;
-    if let Some(token) = auth_response.token {
-        fs::write(TOKEN_FILE, &token)?;
-        println!("obtained new token from JSON");
-        Ok(token)
-    } else {
-        Err("no token in auth response".into())
-    }
-}
-
-async fn get_token(client: &Client) -> Result<String, Box<dyn std::error::Error>> {
     if Path::new(TOKEN_FILE).exists() {
-        if let Ok(token) = fs::read_to_string(TOKEN_FILE) {
-            let token = token.trim();
-            if !token.is_empty() {
-                return Ok(token.to_string());
-            }
+        let token = fs::read_to_string(TOKEN_FILE)?;
+        if !token.trim().is_empty() {
+            return Ok(token.trim().to_string());
         }
     }
-    fetch_token(client).await
+    Err(format!("no device token, set DEVICE_TOKEN or write it to {}", TOKEN_FILE).into())
 }
 
 async fn post_log(
     client: &Client,
     token: &str,
     simulator: &mut DecibelSimulator,
-) -> Result<String, Box<dyn std::error::Error>> {
+) -> Result<(), Box<dyn std::error::Error>> {
     let decibels = simulator.get_next_decibels();
     let log_data = LogData { decibels };
 
@@ -136,25 +109,23 @@ async fn post_log(
 
     if response.status().is_success() {
         println!("sent {{ decibels: {} }}", decibels);
-        return Ok(token.to_string());
+        return Ok(());
     }
 
     if response.status() == 401 {
-        println!("token rejected – refreshing");
-        let _ = fs::remove_file(TOKEN_FILE);
-        let new_token = fetch_token(client).await?;
-        return Ok(new_token);
+        eprintln!("token rejected, register the device again with `dbmonitor device create`");
+        std::process::exit(1);
     }
 
     eprintln!("log post failed ({})", response.status());
-    Ok(token.to_string())
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let client = Client::new();
     let mut simulator = DecibelSimulator::new();
-    let mut token = get_token(&client).await?;
+    let token = get_token()?;
 
     println!("starting mock device...");
 
@@ -163,9 +134,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     loop {
         interval.tick().await;
         
-        match post_log(&client, &token, &mut simulator).await {
-            Ok(new_token) => token = new_token,
-            Err(err) => eprintln!("unexpected error: {}", err),
+        if let Err(err) = post_log(&client, &token, &mut simulator).await {
+            eprintln!("unexpected error: {}", err);
         }
     }
 } 
\ No newline at end of file
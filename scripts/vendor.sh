@@ -0,0 +1,19 @@
+#!/bin/sh
+# downloads the pinned frontend libraries into static/vendor/, they are embedded at compile time.
+# bump a version here and in templates/ together, file names carry the version so browsers cache them forever
+set -eu
+
+HTMX_VERSION=1.9.6
+CHARTJS_VERSION=4.4.1
+
+dir="$(dirname "$0")/../static/vendor"
+mkdir -p "$dir"
+
+fetch() {
+    echo "$2"
+    curl -fsSL "$1" -o "$dir/$2"
+}
+
+fetch "https://unpkg.com/htmx.org@$HTMX_VERSION/dist/htmx.min.js" "htmx-$HTMX_VERSION.min.js"
+fetch "https://unpkg.com/htmx.org@$HTMX_VERSION/dist/ext/ws.js" "htmx-ws-$HTMX_VERSION.js"
+fetch "https://cdn.jsdelivr.net/npm/chart.js@$CHARTJS_VERSION/dist/chart.umd.js" "chart-$CHARTJS_VERSION.umd.js"
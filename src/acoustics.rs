@@ -0,0 +1,75 @@
+// decibel arithmetic shared by the reporting endpoints, levels are combined on an energy basis
+
+pub fn to_energy(decibels: f64) -> f64 {
+    10f64.powf(decibels / 10.0)
+}
+
+pub fn from_energy(energy: f64) -> f64 {
+    10.0 * energy.log10()
+}
+
+// day-evening-night level with +5 db evening and +10 db night penalties, hours are the period lengths
+pub fn lden(ld: f64, le: f64, ln: f64, hours: (f64, f64, f64)) -> f64 {
+    let (day_hours, evening_hours, night_hours) = hours;
+    let total = day_hours + evening_hours + night_hours;
+
+    from_energy(
+        (day_hours * to_energy(ld)
+            + evening_hours * to_energy(le + 5.0)
+            + night_hours * to_energy(ln + 10.0))
+            / total,
+    )
+}
+
+// day-night level, the evening is folded into the day and only the +10 db night penalty applies
+pub fn ldn(ld: f64, le: f64, ln: f64, hours: (f64, f64, f64)) -> f64 {
+    let (day_hours, evening_hours, night_hours) = hours;
+    let total = day_hours + evening_hours + night_hours;
+
+    from_energy(
+        (day_hours * to_energy(ld)
+            + evening_hours * to_energy(le)
+            + night_hours * to_energy(ln + 10.0))
+            / total,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EU_HOURS: (f64, f64, f64) = (12.0, 4.0, 8.0);
+    // far enough below the other levels to contribute nothing
+    const QUIET: f64 = -100.0;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.01, "{} is not {}", actual, expected);
+    }
+
+    #[test]
+    fn energy_round_trips() {
+        assert_close(from_energy(to_energy(63.2)), 63.2);
+        // two equal sources add 3 db
+        assert_close(from_energy(to_energy(60.0) * 2.0), 63.01);
+    }
+
+    #[test]
+    fn lden_applies_evening_and_night_penalties() {
+        // a constant level comes out 6.4 db higher, 10 log10((12 + 4 * 10^0.5 + 8 * 10) / 24)
+        assert_close(lden(60.0, 60.0, 60.0, EU_HOURS), 66.40);
+        // only the day period, diluted over 24 hours
+        assert_close(lden(60.0, QUIET, QUIET, EU_HOURS), 60.0 + 10.0 * (12.0f64 / 24.0).log10());
+        // evening +5 db
+        assert_close(lden(QUIET, 60.0, QUIET, EU_HOURS), 65.0 + 10.0 * (4.0f64 / 24.0).log10());
+        // night +10 db
+        assert_close(lden(QUIET, QUIET, 60.0, EU_HOURS), 70.0 + 10.0 * (8.0f64 / 24.0).log10());
+    }
+
+    #[test]
+    fn ldn_has_no_evening_penalty() {
+        // 10 log10((16 + 8 * 10) / 24) = 6.02 db above a constant level
+        assert_close(ldn(60.0, 60.0, 60.0, EU_HOURS), 66.02);
+        assert_close(ldn(QUIET, 60.0, QUIET, EU_HOURS), 60.0 + 10.0 * (4.0f64 / 24.0).log10());
+        assert_close(ldn(QUIET, QUIET, 60.0, EU_HOURS), 70.0 + 10.0 * (8.0f64 / 24.0).log10());
+    }
+}
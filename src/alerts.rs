@@ -0,0 +1,121 @@
+use chrono::{DateTime, Duration, Utc};
+use std::sync::LazyLock;
+use tokio::sync::mpsc;
+use crate::database::DbPool;
+use crate::schedule::ApplicableLimit;
+use crate::websocket;
+
+// an alert without a crossing for this long is closed, the next crossing opens a new one
+pub const QUIET_MINUTES: i64 = 15;
+// a device staying over its limit keeps its alert open with a signal this often
+pub const REFRESH_SECONDS: i64 = 60;
+
+// a reading over the limit, `crossing` when the level just went from under to over it
+#[derive(Clone, Debug)]
+pub struct LimitSignal {
+    pub device_id: i32,
+    pub timestamp: DateTime<Utc>,
+    pub decibels: f64,
+    pub limit: ApplicableLimit,
+    pub crossing: bool,
+}
+
+static ALERT_QUEUE: LazyLock<tokio::sync::Mutex<Option<mpsc::UnboundedSender<LimitSignal>>>> =
+    LazyLock::new(|| tokio::sync::Mutex::new(None));
+
+pub async fn init_alert_writer(pool: DbPool) {
+    let (tx, rx) = mpsc::unbounded_channel::<LimitSignal>();
+
+    {
+        let mut queue = ALERT_QUEUE.lock().await;
+        *queue = Some(tx);
+    }
+
+    tokio::spawn(alert_writer(rx, pool));
+}
+
+async fn alert_writer(mut rx: mpsc::UnboundedReceiver<LimitSignal>, pool: DbPool) {
+    while let Some(signal) = rx.recv().await {
+        let client = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to get database connection for alert");
+                continue;
+            }
+        };
+
+        if let Err(e) = store(&client, &signal).await {
+            tracing::error!(error = %e, "alert insert error");
+        }
+    }
+}
+
+// bumps the open alert of the device and schedule, or opens a new one and notifies unless silenced
+async fn store(client: &tokio_postgres::Client, signal: &LimitSignal) -> Result<(), tokio_postgres::Error> {
+    let open_since = signal.timestamp - Duration::minutes(QUIET_MINUTES);
+    let bumped = client
+        .execute(
+            "UPDATE alerts SET last_seen_at = GREATEST(last_seen_at, $3), peak_db = GREATEST(peak_db, $4),
+                    occurrences = occurrences + $5
+             WHERE id = (SELECT id FROM alerts WHERE fk_device_id = $1 AND fk_schedule_id = $2 AND last_seen_at > $6
+                         ORDER BY id DESC LIMIT 1)",
+            &[
+                &signal.device_id,
+                &signal.limit.schedule_id,
+                &signal.timestamp,
+                &signal.decibels,
+                &(signal.crossing as i32),
+                &open_since,
+            ],
+        )
+        .await?;
+    // a duplicate, whoever was notified of the open alert is not notified again
+    if bumped > 0 {
+        return Ok(());
+    }
+
+    let silence_id: Option<i32> = client
+        .query_opt(
+            "SELECT id FROM alert_silences
+             WHERE cancelled_at IS NULL AND starts_at <= $3 AND ends_at > $3
+               AND (fk_device_id IS NULL OR fk_device_id = $1) AND (fk_schedule_id IS NULL OR fk_schedule_id = $2)
+             ORDER BY ends_at DESC LIMIT 1",
+            &[&signal.device_id, &signal.limit.schedule_id, &signal.timestamp],
+        )
+        .await?
+        .map(|row| row.get("id"));
+
+    let alert_id: i32 = client
+        .query_one(
+            "INSERT INTO alerts (fk_device_id, fk_schedule_id, schedule, period, limit_db, peak_db, started_at, last_seen_at, fk_silence_id)
+             VALUES ($1, $2, $3, $4, $5, $6, $7, $7, $8) RETURNING id",
+            &[
+                &signal.device_id,
+                &signal.limit.schedule_id,
+                &signal.limit.schedule,
+                &signal.limit.period,
+                &signal.limit.limit_db,
+                &signal.decibels,
+                &signal.timestamp,
+                &silence_id,
+            ],
+        )
+        .await?
+        .get("id");
+
+    if silence_id.is_none() {
+        websocket::broadcast_limit_alert(alert_id, signal);
+    }
+    Ok(())
+}
+
+pub async fn record(signal: LimitSignal) {
+    let queue = ALERT_QUEUE.lock().await;
+    if let Some(sender) = queue.as_ref() {
+        if sender.send(signal).is_err() {
+            tracing::warn!("alert queue channel closed");
+        }
+    } else {
+        tracing::warn!("alert writer not initialized");
+    }
+}
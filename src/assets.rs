@@ -0,0 +1,125 @@
+use axum::body::Bytes;
+use dashmap::DashMap;
+use rust_embed::RustEmbed;
+use std::borrow::Cow;
+use crate::config;
+use std::io::Write;
+use std::sync::{Arc, LazyLock};
+
+// everything under static/ is compiled into the binary, including the vendored js libraries
+#[derive(RustEmbed)]
+#[folder = "static/"]
+struct Embedded;
+
+// assets.dev_mode (DEV_ASSETS=true) reads static/ from the working directory on every request for live editing
+pub static DEV_MODE: LazyLock<bool> = LazyLock::new(|| config::get().assets.dev_mode);
+
+// versioned file names never change content, anything else is revalidated with its etag
+const IMMUTABLE_PREFIX: &str = "vendor/";
+
+pub struct Asset {
+    pub content_type: &'static str,
+    pub etag: String,
+    pub immutable: bool,
+    pub identity: Bytes,
+    pub gzip: Option<Bytes>,
+    pub brotli: Option<Bytes>,
+}
+
+// compressed variants are built once per asset and kept for the life of the process
+static ASSETS: LazyLock<DashMap<String, Arc<Asset>>> = LazyLock::new(DashMap::new);
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("css") => "text/css; charset=utf-8",
+        Some("js") => "text/javascript; charset=utf-8",
+        Some("html") => "text/html; charset=utf-8",
+        Some("json") => "application/json",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("ico") => "image/x-icon",
+        Some("woff2") => "font/woff2",
+        _ => "application/octet-stream",
+    }
+}
+
+fn compressible(content_type: &str) -> bool {
+    content_type.starts_with("text/") || content_type == "application/json" || content_type == "image/svg+xml"
+}
+
+fn gzip(data: &[u8]) -> Option<Vec<u8>> {
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder.write_all(data).ok()?;
+    encoder.finish().ok()
+}
+
+fn brotli(data: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    {
+        let mut encoder = brotli::CompressorWriter::new(&mut output, 4096, 11, 22);
+        encoder.write_all(data).ok()?;
+    }
+    Some(output)
+}
+
+// a variant is only kept when it is actually smaller
+fn smaller(compressed: Option<Vec<u8>>, original: usize) -> Option<Bytes> {
+    compressed.filter(|compressed| compressed.len() < original).map(Bytes::from)
+}
+
+fn build(path: &str) -> Option<Asset> {
+    let file = Embedded::get(path)?;
+    let content_type = content_type(path);
+    let etag = format!(
+        "\"{}\"",
+        file.metadata.sha256_hash()[..12].iter().map(|byte| format!("{:02x}", byte)).collect::<String>()
+    );
+    let (gzip, brotli) = if compressible(content_type) {
+        (smaller(gzip(&file.data), file.data.len()), smaller(brotli(&file.data), file.data.len()))
+    } else {
+        (None, None)
+    };
+
+    Some(Asset {
+        content_type,
+        etag,
+        immutable: path.starts_with(IMMUTABLE_PREFIX),
+        identity: match file.data {
+            Cow::Borrowed(data) => Bytes::from_static(data),
+            Cow::Owned(data) => Bytes::from(data),
+        },
+        gzip,
+        brotli,
+    })
+}
+
+pub fn get(path: &str) -> Option<Arc<Asset>> {
+    if let Some(asset) = ASSETS.get(path) {
+        return Some(asset.clone());
+    }
+    let asset = Arc::new(build(path)?);
+    ASSETS.insert(path.to_string(), asset.clone());
+    Some(asset)
+}
+
+// uncompressed file straight from disk, dev mode only
+pub async fn read_from_disk(path: &str) -> Option<(&'static str, Vec<u8>)> {
+    if path.split('/').any(|part| part == ".." || part.is_empty()) {
+        return None;
+    }
+    let data = tokio::fs::read(format!("static/{}", path)).await.ok()?;
+    Some((content_type(path), data))
+}
+
+// compresses every embedded asset in the background so the first requests are not the slow ones
+pub fn precompress() {
+    if *DEV_MODE {
+        tracing::info!("serving static assets from disk (DEV_ASSETS)");
+        return;
+    }
+    tokio::task::spawn_blocking(|| {
+        for path in Embedded::iter() {
+            get(&path);
+        }
+    });
+}
@@ -0,0 +1,61 @@
+use std::sync::LazyLock;
+use tokio::sync::mpsc;
+use crate::database::DbPool;
+
+#[derive(Clone, Debug)]
+pub struct AuditEntry {
+    pub user_id: Option<i32>,
+    pub username: Option<String>,
+    pub action: String,
+    pub path: Option<String>,
+    pub permission: Option<&'static str>,
+    pub status: i32,
+}
+
+static AUDIT_QUEUE: LazyLock<tokio::sync::Mutex<Option<mpsc::UnboundedSender<AuditEntry>>>> =
+    LazyLock::new(|| tokio::sync::Mutex::new(None));
+
+pub async fn init_audit_writer(pool: DbPool) {
+    let (tx, rx) = mpsc::unbounded_channel::<AuditEntry>();
+
+    {
+        let mut queue = AUDIT_QUEUE.lock().await;
+        *queue = Some(tx);
+    }
+
+    tokio::spawn(audit_writer(rx, pool));
+}
+
+async fn audit_writer(mut rx: mpsc::UnboundedReceiver<AuditEntry>, pool: DbPool) {
+    while let Some(entry) = rx.recv().await {
+        let client = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to get database connection for audit insert");
+                continue;
+            }
+        };
+
+        if let Err(e) = client
+            .execute(
+                "INSERT INTO audit_log (fk_user_id, username, action, path, permission, status)
+                 VALUES ($1, $2, $3, $4, $5, $6)",
+                &[&entry.user_id, &entry.username, &entry.action, &entry.path, &entry.permission, &entry.status],
+            )
+            .await
+        {
+            tracing::error!(error = %e, "audit insert error");
+        }
+    }
+}
+
+pub async fn record(entry: AuditEntry) {
+    let queue = AUDIT_QUEUE.lock().await;
+    if let Some(sender) = queue.as_ref() {
+        if sender.send(entry).is_err() {
+            tracing::warn!("audit queue channel closed");
+        }
+    } else {
+        tracing::warn!("audit writer not initialized");
+    }
+}
@@ -0,0 +1,473 @@
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use axum::http::{header, HeaderMap};
+use base64::Engine;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use chrono::{Duration, Utc};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use crate::config;
+use crate::database::DbPool;
+
+pub const SESSION_COOKIE: &str = "dbmonitor_session";
+pub const SESSION_DAYS: i64 = 7;
+// api tokens carry a prefix so they are recognisable in scripts and never mistaken for device jwts
+pub const API_TOKEN_PREFIX: &str = "dbm_";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Admin,
+    Operator,
+    Viewer,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Permission {
+    ViewData,
+    RunReports,
+    EditGroups,
+    ManageDevices,
+    ManageRules,
+    ManageUsers,
+    AcknowledgeAlerts,
+}
+
+impl Role {
+    pub fn parse(role: &str) -> Option<Self> {
+        match role {
+            "admin" => Some(Role::Admin),
+            "operator" => Some(Role::Operator),
+            "viewer" => Some(Role::Viewer),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Role::Admin => "admin",
+            Role::Operator => "operator",
+            Role::Viewer => "viewer",
+        }
+    }
+
+    pub fn grants(self, permission: Permission) -> bool {
+        match self {
+            Role::Admin => true,
+            Role::Operator => matches!(
+                permission,
+                Permission::ViewData | Permission::RunReports | Permission::EditGroups | Permission::AcknowledgeAlerts
+            ),
+            Role::Viewer => permission == Permission::ViewData,
+        }
+    }
+}
+
+impl Permission {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Permission::ViewData => "view_data",
+            Permission::RunReports => "run_reports",
+            Permission::EditGroups => "edit_groups",
+            Permission::ManageDevices => "manage_devices",
+            Permission::ManageRules => "manage_rules",
+            Permission::ManageUsers => "manage_users",
+            Permission::AcknowledgeAlerts => "acknowledge_alerts",
+        }
+    }
+
+    // these act on objects that belong to no single site, so a site scoped grant is not enough
+    pub fn global_only(self) -> bool {
+        matches!(self, Permission::ManageDevices | Permission::ManageRules | Permission::ManageUsers)
+    }
+}
+
+// a role, everywhere or limited to one device group (site) and the devices in it
+#[derive(Clone, Debug)]
+pub struct RoleGrant {
+    pub role: Role,
+    pub group_id: Option<i32>,
+    pub device_ids: Vec<i32>,
+}
+
+// where a permission applies for a principal
+#[derive(Clone, Debug)]
+pub enum Scope {
+    None,
+    All,
+    Groups {
+        groups: HashSet<i32>,
+        devices: HashSet<i32>,
+    },
+}
+
+impl Scope {
+    pub fn allows_device(&self, device_id: i32) -> bool {
+        match self {
+            Scope::None => false,
+            Scope::All => true,
+            Scope::Groups { devices, .. } => devices.contains(&device_id),
+        }
+    }
+
+    pub fn allows_group(&self, group_id: i32) -> bool {
+        match self {
+            Scope::None => false,
+            Scope::All => true,
+            Scope::Groups { groups, .. } => groups.contains(&group_id),
+        }
+    }
+
+    // devices a query has to be limited to, none when every device is allowed
+    pub fn device_filter(&self) -> Option<Vec<i32>> {
+        match self {
+            Scope::None => Some(Vec::new()),
+            Scope::All => None,
+            Scope::Groups { devices, .. } => {
+                let mut devices: Vec<i32> = devices.iter().copied().collect();
+                devices.sort_unstable();
+                Some(devices)
+            }
+        }
+    }
+
+    pub fn group_filter(&self) -> Option<Vec<i32>> {
+        match self {
+            Scope::None => Some(Vec::new()),
+            Scope::All => None,
+            Scope::Groups { groups, .. } => Some(groups.iter().copied().collect()),
+        }
+    }
+}
+
+// a logged in user, api tokens authenticate as their owner but can only read
+#[derive(Clone, Debug)]
+pub struct Principal {
+    pub user_id: i32,
+    pub username: String,
+    pub read_only: bool,
+    pub grants: Vec<RoleGrant>,
+}
+
+impl Principal {
+    pub fn scope(&self, permission: Permission) -> Scope {
+        if self.read_only && permission != Permission::ViewData {
+            return Scope::None;
+        }
+
+        let mut groups = HashSet::new();
+        let mut devices = HashSet::new();
+        for grant in self.grants.iter().filter(|grant| grant.role.grants(permission)) {
+            match grant.group_id {
+                None => return Scope::All,
+                Some(group_id) => {
+                    groups.insert(group_id);
+                    devices.extend(grant.device_ids.iter().copied());
+                }
+            }
+        }
+
+        if groups.is_empty() {
+            Scope::None
+        } else {
+            Scope::Groups { groups, devices }
+        }
+    }
+}
+
+pub async fn hash_password(password: &str) -> Result<String, String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map(|hash| hash.to_string())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+pub async fn verify_password(password: &str, password_hash: &str) -> bool {
+    let password = password.to_string();
+    let password_hash = password_hash.to_string();
+    tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&password_hash)
+            .map(|hash| Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+            .unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
+// 256 random bits, url safe so it fits cookies and query strings
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    OsRng.fill_bytes(&mut bytes);
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_secret(secret: &str) -> String {
+    URL_SAFE_NO_PAD.encode(Sha256::digest(secret.as_bytes()))
+}
+
+fn session_cookie(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get_all(header::COOKIE)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(';'))
+        .find_map(|pair| pair.trim().strip_prefix(SESSION_COOKIE)?.strip_prefix('='))
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+// a session cookie or an api token from the authorization header, `access_token` covers
+// websocket clients in browsers, which cannot set headers
+pub async fn authenticate(pool: &DbPool, headers: &HeaderMap, access_token: Option<&str>) -> Option<Principal> {
+    if let Some(session) = session_cookie(headers)
+        && let Some(principal) = session_principal(pool, session).await
+    {
+        return Some(principal);
+    }
+
+    let token = bearer_token(headers).or(access_token)?;
+    api_token_principal(pool, token).await
+}
+
+async fn session_principal(pool: &DbPool, session: &str) -> Option<Principal> {
+    let client = pool.get().await.ok()?;
+    let row = client
+        .query_opt(
+            "SELECT u.id, u.username FROM user_sessions s
+             JOIN users u ON u.id = s.fk_user_id
+             WHERE s.token_hash = $1 AND s.expires_at > NOW() AND u.disabled_at IS NULL",
+            &[&hash_secret(session)],
+        )
+        .await
+        .map_err(|e| tracing::error!(error = %e, "database query error"))
+        .ok()??;
+
+    let user_id = row.get("id");
+    Some(Principal {
+        user_id,
+        username: row.get("username"),
+        read_only: false,
+        grants: load_grants(&client, user_id).await?,
+    })
+}
+
+async fn api_token_principal(pool: &DbPool, token: &str) -> Option<Principal> {
+    if !token.starts_with(API_TOKEN_PREFIX) {
+        return None;
+    }
+
+    let client = pool.get().await.ok()?;
+    let row = client
+        .query_opt(
+            "UPDATE api_tokens t SET last_used_at = NOW()
+             FROM users u
+             WHERE u.id = t.fk_user_id AND t.token_hash = $1 AND t.revoked_at IS NULL AND u.disabled_at IS NULL
+             RETURNING u.id, u.username",
+            &[&hash_secret(token)],
+        )
+        .await
+        .map_err(|e| tracing::error!(error = %e, "database query error"))
+        .ok()??;
+
+    let user_id = row.get("id");
+    Some(Principal {
+        user_id,
+        username: row.get("username"),
+        read_only: true,
+        grants: load_grants(&client, user_id).await?,
+    })
+}
+
+pub async fn load_grants(client: &tokio_postgres::Client, user_id: i32) -> Option<Vec<RoleGrant>> {
+    let rows = client
+        .query(
+            "SELECT r.role, r.fk_group_id,
+                    COALESCE(array_agg(m.fk_device_id) FILTER (WHERE m.fk_device_id IS NOT NULL), '{}') AS device_ids
+             FROM user_roles r
+             LEFT JOIN device_group_members m ON m.fk_group_id = r.fk_group_id
+             WHERE r.fk_user_id = $1
+             GROUP BY r.id, r.role, r.fk_group_id",
+            &[&user_id],
+        )
+        .await
+        .map_err(|e| tracing::error!(error = %e, "database query error"))
+        .ok()?;
+
+    Some(rows
+        .iter()
+        .filter_map(|row| Some(RoleGrant {
+            role: Role::parse(row.get("role"))?,
+            group_id: row.get("fk_group_id"),
+            device_ids: row.get("device_ids"),
+        }))
+        .collect())
+}
+
+// returns the session secret for the cookie, only its hash is stored
+pub async fn create_session(client: &tokio_postgres::Client, user_id: i32) -> Result<String, tokio_postgres::Error> {
+    let secret = generate_secret();
+    let expires_at = Utc::now() + Duration::days(SESSION_DAYS);
+    client
+        .execute(
+            "INSERT INTO user_sessions (token_hash, fk_user_id, expires_at) VALUES ($1, $2, $3)",
+            &[&hash_secret(&secret), &user_id, &expires_at],
+        )
+        .await?;
+    Ok(secret)
+}
+
+pub async fn delete_session(client: &tokio_postgres::Client, headers: &HeaderMap) -> Result<(), tokio_postgres::Error> {
+    if let Some(session) = session_cookie(headers) {
+        client
+            .execute("DELETE FROM user_sessions WHERE token_hash = $1", &[&hash_secret(session)])
+            .await?;
+    }
+    Ok(())
+}
+
+pub fn session_set_cookie(secret: &str) -> String {
+    format!(
+        "{}={}; Path=/; HttpOnly; SameSite=Lax; Max-Age={}",
+        SESSION_COOKIE, secret, SESSION_DAYS * 24 * 3600
+    )
+}
+
+pub fn session_clear_cookie() -> String {
+    format!("{}=; Path=/; HttpOnly; SameSite=Lax; Max-Age=0", SESSION_COOKIE)
+}
+
+pub async fn cleanup_expired_sessions(pool: &DbPool) {
+    let client = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to get database connection for session cleanup");
+            return;
+        }
+    };
+
+    if let Err(e) = client.execute("DELETE FROM user_sessions WHERE expires_at <= NOW()", &[]).await {
+        tracing::error!(error = %e, "session cleanup error");
+    }
+}
+
+// creates the first user from auth.admin_username / auth.admin_password when nobody can log in yet
+pub async fn bootstrap_admin(pool: &DbPool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = pool.get().await?;
+    let users: i64 = client.query_one("SELECT COUNT(*) FROM users", &[]).await?.get(0);
+    if users > 0 {
+        return Ok(());
+    }
+
+    let settings = &config::get().auth;
+    let Some(password) = &settings.admin_password else {
+        tracing::warn!("no users exist, set ADMIN_PASSWORD (and optionally ADMIN_USERNAME) to create the first one");
+        return Ok(());
+    };
+    let username = &settings.admin_username;
+
+    let password_hash = hash_password(password).await?;
+    let row = client
+        .query_one("INSERT INTO users (username, password_hash) VALUES ($1, $2) RETURNING id", &[&username, &password_hash])
+        .await?;
+    let user_id: i32 = row.get("id");
+    client
+        .execute("INSERT INTO user_roles (fk_user_id, role) VALUES ($1, 'admin')", &[&user_id])
+        .await?;
+    tracing::info!(username = %username, "created admin user");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn site(groups: &[i32], devices: &[i32]) -> Scope {
+        Scope::Groups { groups: groups.iter().copied().collect(), devices: devices.iter().copied().collect() }
+    }
+
+    fn grant(role: Role, group_id: Option<i32>, device_ids: &[i32]) -> RoleGrant {
+        RoleGrant { role, group_id, device_ids: device_ids.to_vec() }
+    }
+
+    fn principal(read_only: bool, grants: Vec<RoleGrant>) -> Principal {
+        Principal { user_id: 1, username: "test".to_string(), read_only, grants }
+    }
+
+    #[test]
+    fn all_allows_everything() {
+        assert!(Scope::All.allows_device(42));
+        assert!(Scope::All.allows_group(7));
+        assert_eq!(Scope::All.device_filter(), None);
+        assert_eq!(Scope::All.group_filter(), None);
+    }
+
+    #[test]
+    fn none_allows_nothing() {
+        assert!(!Scope::None.allows_device(42));
+        assert!(!Scope::None.allows_group(7));
+        // an empty filter matches no rows rather than every row
+        assert_eq!(Scope::None.device_filter(), Some(Vec::new()));
+        assert_eq!(Scope::None.group_filter(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn groups_allow_their_devices_only() {
+        let scope = site(&[2], &[5, 3]);
+        assert!(scope.allows_group(2));
+        assert!(!scope.allows_group(1));
+        assert!(scope.allows_device(3));
+        assert!(scope.allows_device(5));
+        assert!(!scope.allows_device(4));
+        assert_eq!(scope.device_filter(), Some(vec![3, 5]));
+        assert_eq!(scope.group_filter(), Some(vec![2]));
+    }
+
+    #[test]
+    fn empty_group_allows_the_group_but_no_device() {
+        let scope = site(&[2], &[]);
+        assert!(scope.allows_group(2));
+        assert!(!scope.allows_device(5));
+        assert_eq!(scope.device_filter(), Some(Vec::new()));
+    }
+
+    #[test]
+    fn global_grant_wins_over_site_grants() {
+        let user = principal(false, vec![grant(Role::Operator, Some(2), &[5]), grant(Role::Viewer, None, &[])]);
+        assert!(matches!(user.scope(Permission::ViewData), Scope::All));
+        // editing groups only comes from the site operator role
+        let scope = user.scope(Permission::EditGroups);
+        assert!(scope.allows_group(2) && scope.allows_device(5));
+        assert!(!scope.allows_group(1) && !scope.allows_device(4));
+        assert!(matches!(user.scope(Permission::ManageDevices), Scope::None));
+    }
+
+    #[test]
+    fn site_grants_combine() {
+        let user = principal(false, vec![grant(Role::Operator, Some(1), &[1, 2]), grant(Role::Viewer, Some(2), &[5])]);
+        let view = user.scope(Permission::ViewData);
+        assert!(view.allows_device(1) && view.allows_device(5));
+        let ack = user.scope(Permission::AcknowledgeAlerts);
+        assert!(ack.allows_device(1) && !ack.allows_device(5));
+    }
+
+    #[test]
+    fn api_tokens_only_read() {
+        let token = principal(true, vec![grant(Role::Admin, None, &[])]);
+        assert!(matches!(token.scope(Permission::ViewData), Scope::All));
+        assert!(matches!(token.scope(Permission::EditGroups), Scope::None));
+        assert!(matches!(token.scope(Permission::ManageUsers), Scope::None));
+    }
+}
@@ -1,7 +1,12 @@
 use chrono::{DateTime, Utc};
+use std::fmt;
 use std::sync::LazyLock;
+use std::sync::atomic::{AtomicI64, AtomicUsize, Ordering};
+use std::time::Instant;
 use tokio::sync::mpsc;
+use crate::config;
 use crate::database::DbPool;
+use crate::telemetry::{self, RequestId};
 use dashmap::DashMap;
 
 #[derive(Clone, Debug)]
@@ -20,11 +25,33 @@ pub struct PendingInsert {
     pub device_id: i32,
     pub decibels: f64,
     pub timestamp: DateTime<Utc>,
+    pub request_id: Option<RequestId>,
+}
+
+// the request ids of a batch as one span field, only formatted when the span is recorded
+struct BatchRequestIds<'a>(&'a [PendingInsert]);
+
+impl fmt::Display for BatchRequestIds<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, request_id) in self.0.iter().filter_map(|insert| insert.request_id.as_ref()).enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", request_id)?;
+        }
+        Ok(())
+    }
 }
 
 static INSERT_QUEUE: LazyLock<tokio::sync::Mutex<Option<mpsc::UnboundedSender<PendingInsert>>>> = 
     LazyLock::new(|| tokio::sync::Mutex::new(None));
 
+// readings queued or batched but not yet written, decremented once their batch is done
+static QUEUE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+// unix millis of the last batch the database accepted, 0 before the first one
+static LAST_FLUSH: AtomicI64 = AtomicI64::new(0);
+static PROCESSOR_STARTED: AtomicI64 = AtomicI64::new(0);
+
 pub async fn init_batch_processor(pool: DbPool) {
     let (tx, rx) = mpsc::unbounded_channel::<PendingInsert>();
     
@@ -33,6 +60,7 @@ pub async fn init_batch_processor(pool: DbPool) {
         *queue = Some(tx);
     }
     
+    PROCESSOR_STARTED.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
     tokio::spawn(batch_insert_processor(rx, pool));
     
     // println!("batch insert processor initializd for high-throughput operations");
@@ -40,8 +68,9 @@ pub async fn init_batch_processor(pool: DbPool) {
 
 async fn batch_insert_processor(mut rx: mpsc::UnboundedReceiver<PendingInsert>, pool: DbPool) {
     let mut batch = Vec::new();
-    let mut interval = tokio::time::interval(std::time::Duration::from_millis(50)); // process every 50ms for high throughput
-    let mut max_wait_timer = tokio::time::interval(std::time::Duration::from_millis(200)); // max 200ms wait for small batches
+    let settings = &config::get().ingest;
+    let mut interval = tokio::time::interval(std::time::Duration::from_millis(settings.flush_interval_ms)); // process often for high throughput
+    let mut max_wait_timer = tokio::time::interval(std::time::Duration::from_millis(settings.max_wait_ms)); // bounded wait for small batches
     // let mut stats_interval = tokio::time::interval(std::time::Duration::from_secs(10)); // stats every 10s
     
     // let mut total_processed = 0u64;
@@ -54,7 +83,7 @@ async fn batch_insert_processor(mut rx: mpsc::UnboundedReceiver<PendingInsert>,
                     batch.push(insert);
                     
                     // for high throughput, process larger batches
-                    if batch.len() >= 200 {
+                    if batch.len() >= settings.batch_size {
                         let _processed = process_batch(&mut batch, &pool).await;
                         // total_processed += processed;
                         // if processed > 0 {
@@ -76,7 +105,7 @@ async fn batch_insert_processor(mut rx: mpsc::UnboundedReceiver<PendingInsert>,
             
             // for low throughput, process batch on timer 
             _ = interval.tick() => {
-                if batch.len() >= 10 {  // minimum batch size for timer processing
+                if batch.len() >= settings.min_timer_batch {  // minimum batch size for timer processing
                     let _processed = process_batch(&mut batch, &pool).await;
                     // total_processed += processed;
                     // if processed > 0 {
@@ -110,14 +139,16 @@ async fn batch_insert_processor(mut rx: mpsc::UnboundedReceiver<PendingInsert>,
     // println!("batch processor shutdown - processed {} total inserts in {} batches", total_processed, total_batches);
 }
 
+#[tracing::instrument(name = "insert_batch", skip_all, fields(size = batch.len(), request_ids = %BatchRequestIds(batch)))]
 async fn process_batch(batch: &mut Vec<PendingInsert>, pool: &DbPool) -> u64 {
     if batch.is_empty() {
         return 0;
     }
     
     let batch_size = batch.len();
+    let start = Instant::now();
     
-    match pool.get().await {
+    let inserted = match pool.get().await {
         Ok(client) => {
             if batch_size == 1 {
                 // single insert for small batches
@@ -131,12 +162,14 @@ async fn process_batch(batch: &mut Vec<PendingInsert>, pool: &DbPool) -> u64 {
                 {
                     Ok(_) => {
                         batch.clear();
-                        return 1;
+                        LAST_FLUSH.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+                        1
                     }
                     Err(e) => {
-                        eprintln!("single insert error: {}", e);
+                        tracing::error!(error = %e, "single insert error");
+                        metrics::counter!(telemetry::INSERT_FAILURES, "reason" => "query").increment(1);
                         batch.clear();
-                        return 0;
+                        0
                     }
                 }
             } else {
@@ -158,24 +191,32 @@ async fn process_batch(batch: &mut Vec<PendingInsert>, pool: &DbPool) -> u64 {
                 
                 match client.execute(&query, &params).await {
                     Ok(rows_inserted) => {
-                        let processed = rows_inserted as u64;
                         batch.clear();
-                        return processed;
+                        LAST_FLUSH.store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+                        rows_inserted
                     }
                     Err(e) => {
-                        eprintln!("batch insert error (size {}): {}", batch_size, e);
+                        tracing::error!(error = %e, "batch insert error");
+                        metrics::counter!(telemetry::INSERT_FAILURES, "reason" => "query").increment(batch_size as u64);
                         batch.clear();
-                        return 0;
+                        0
                     }
                 }
             }
         }
         Err(e) => {
-            eprintln!("failed to get database connection for batch insert: {}", e);
+            tracing::error!(error = %e, "failed to get database connection for batch insert");
+            metrics::counter!(telemetry::INSERT_FAILURES, "reason" => "connection").increment(batch_size as u64);
             // dont clear batch on connection errors - we'll retry next time
             return 0;
         }
-    }
+    };
+    
+    QUEUE_DEPTH.fetch_sub(batch_size, Ordering::Relaxed);
+    tracing::debug!(inserted, elapsed = ?start.elapsed(), "batch written");
+    metrics::histogram!(telemetry::BATCH_SIZE).record(batch_size as f64);
+    metrics::histogram!(telemetry::FLUSH_DURATION).record(start.elapsed().as_secs_f64());
+    inserted
 }
 
 pub async fn update_device_reading(device_id: i32, decibels: f64, timestamp: DateTime<Utc>) {
@@ -188,27 +229,33 @@ pub async fn update_device_reading(device_id: i32, decibels: f64, timestamp: Dat
     ACTIVE_DEVICES.insert(device_id, reading);
 }
 
-pub async fn queue_insert(device_id: i32, decibels: f64, timestamp: DateTime<Utc>) {
+pub async fn queue_insert(device_id: i32, decibels: f64, timestamp: DateTime<Utc>, request_id: Option<RequestId>) {
     let insert = PendingInsert {
         device_id,
         decibels,
         timestamp,
+        request_id,
     };
     
     // get sender and queue the insert
     let queue = INSERT_QUEUE.lock().await;
     if let Some(sender) = queue.as_ref() {
-        if let Err(_) = sender.send(insert) {
-            eprintln!("warning: insert queue channel closed");
+        // counted before sending so the processor never subtracts it first
+        QUEUE_DEPTH.fetch_add(1, Ordering::Relaxed);
+        if sender.send(insert).is_err() {
+            QUEUE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+            tracing::warn!("insert queue channel closed");
+        } else {
+            metrics::counter!(telemetry::READINGS, "device_id" => device_id.to_string()).increment(1);
         }
     } else {
-        eprintln!("warning: batch processor not initialized");
+        tracing::warn!("batch processor not initialized");
     }
 }
 
 pub async fn get_active_devices() -> Vec<DeviceReading> {
     let now = Utc::now();
-    let cutoff = now - chrono::Duration::seconds(60);
+    let cutoff = now - chrono::Duration::seconds(config::get().cache.active_window_seconds);
     
     ACTIVE_DEVICES
         .iter()
@@ -217,9 +264,14 @@ pub async fn get_active_devices() -> Vec<DeviceReading> {
         .collect()
 }
 
+// newest cached reading of a device, also when it is no longer active
+pub fn get_device_reading(device_id: i32) -> Option<DeviceReading> {
+    ACTIVE_DEVICES.get(&device_id).map(|entry| entry.value().clone())
+}
+
 pub async fn cleanup_old_entries() {
     let now = Utc::now();
-    let cutoff = now - chrono::Duration::minutes(5);
+    let cutoff = now - chrono::Duration::seconds(config::get().cache.retention_seconds);
     
     ACTIVE_DEVICES.retain(|_, reading| reading.timestamp > cutoff);
 }
@@ -228,12 +280,26 @@ pub async fn cache_size() -> usize {
     ACTIVE_DEVICES.len()
 }
 
+pub fn queue_depth() -> usize {
+    QUEUE_DEPTH.load(Ordering::Relaxed)
+}
+
+pub fn last_flush() -> Option<DateTime<Utc>> {
+    match LAST_FLUSH.load(Ordering::Relaxed) {
+        0 => None,
+        millis => DateTime::from_timestamp_millis(millis),
+    }
+}
+
+pub fn processor_started() -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(PROCESSOR_STARTED.load(Ordering::Relaxed)).unwrap_or_default()
+}
+
+// a closed channel means the processor task is gone
 pub async fn is_queue_active() -> (usize, bool) {
     let queue = INSERT_QUEUE.lock().await;
     match queue.as_ref() {
-        Some(_sender) => {
-            (0, true) // 
-        }
+        Some(sender) => (queue_depth(), !sender.is_closed()),
         None => (0, false)
     }
 } 
\ No newline at end of file
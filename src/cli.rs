@@ -0,0 +1,346 @@
+use chrono::{DateTime, Duration, Utc};
+use clap::{Args, Subcommand};
+use futures_util::{pin_mut, TryStreamExt};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::PathBuf;
+use tokio_postgres::types::ToSql;
+use crate::config;
+use crate::database::{self, DbPool};
+use crate::token;
+
+type CommandResult = Result<(), Box<dyn std::error::Error + Send + Sync>>;
+
+const CSV_HEADER: &str = "device_id,created_at,decibels";
+// rows per insert while importing, three bind arrays per statement
+const IMPORT_BATCH: usize = 5000;
+// rows per delete, so retention never holds long locks on a busy table
+const RETENTION_BATCH: i64 = 10_000;
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the server, the default without a subcommand
+    Serve,
+    /// Apply or list database migrations without starting the server
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Create, list, rename and delete devices, or print a device token
+    #[command(subcommand)]
+    Device(DeviceCommand),
+    /// Write readings as CSV: device_id,created_at,decibels
+    Export(ExportArgs),
+    /// Load readings from CSV in the export format
+    Import(ImportArgs),
+    /// Delete history older than the retention.* settings
+    #[command(subcommand)]
+    Retention(RetentionCommand),
+    /// Validate the configuration and exit
+    CheckConfig,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MigrateCommand {
+    /// Apply pending migrations
+    Run,
+    /// List applied and pending migrations
+    Status,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum DeviceCommand {
+    /// Register a device and print its token
+    Create {
+        #[arg(long)]
+        name: Option<String>,
+    },
+    /// List devices
+    List {
+        /// Include deleted devices
+        #[arg(long)]
+        all: bool,
+    },
+    /// Rename a device
+    Rename { id: i32, name: String },
+    /// Delete a device and revoke its token, its readings are kept
+    Delete { id: i32 },
+    /// Print the token of a device
+    Token { id: i32 },
+}
+
+#[derive(Args, Debug)]
+pub struct ExportArgs {
+    /// Only these devices, repeatable
+    #[arg(long = "device", value_name = "ID")]
+    pub devices: Vec<i32>,
+    /// Readings at or after this RFC 3339 time
+    #[arg(long)]
+    pub from: Option<DateTime<Utc>>,
+    /// Readings before this RFC 3339 time
+    #[arg(long)]
+    pub to: Option<DateTime<Utc>>,
+    /// Write to a file instead of stdout
+    #[arg(long, short)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args, Debug)]
+pub struct ImportArgs {
+    /// CSV file, - reads stdin
+    pub file: PathBuf,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum RetentionCommand {
+    /// Delete readings, noise events and audit entries past their retention
+    Run {
+        /// Only count what would be deleted
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+// every command except serve, with the configuration already loaded and logging going to stderr
+pub async fn run(command: Command) -> CommandResult {
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::CheckConfig => {
+            println!("configuration ok");
+            Ok(())
+        }
+        Command::Migrate(MigrateCommand::Run) => {
+            let pool = database::init_db().await?;
+            database::run_migrations(&pool).await
+        }
+        Command::Migrate(MigrateCommand::Status) => migrate_status(&database::init_db().await?).await,
+        Command::Device(command) => device(&connect().await?, command).await,
+        Command::Export(args) => export(&connect().await?, args).await,
+        Command::Import(args) => import(&connect().await?, args).await,
+        Command::Retention(RetentionCommand::Run { dry_run }) => retention(&connect().await?, dry_run).await,
+    }
+}
+
+// the pool of the server, refusing to touch a database whose schema is behind this binary
+async fn connect() -> Result<DbPool, Box<dyn std::error::Error + Send + Sync>> {
+    let pool = database::init_db().await?;
+    let pending = database::pending_migrations(&*pool.get().await?).await?;
+    if !pending.is_empty() {
+        return Err(format!("{} pending migrations, run `dbmonitor migrate run` first", pending.len()).into());
+    }
+    Ok(pool)
+}
+
+async fn migrate_status(pool: &DbPool) -> CommandResult {
+    let client = pool.get().await?;
+    for (version, name, applied_on) in database::applied_migrations(&client).await? {
+        println!("{:<36} applied {}", format!("V{}__{}", version, name), applied_on);
+    }
+    let pending = database::pending_migrations(&client).await?;
+    for (version, name) in &pending {
+        println!("{:<36} pending", format!("V{}__{}", version, name));
+    }
+    if pending.is_empty() {
+        println!("schema is current");
+    }
+    Ok(())
+}
+
+async fn device(pool: &DbPool, command: DeviceCommand) -> CommandResult {
+    let client = pool.get().await?;
+    match command {
+        DeviceCommand::Create { name } => {
+            let row = client
+                .query_one("INSERT INTO devices (name, token_issued_at) VALUES ($1, NOW()) RETURNING id", &[&name])
+                .await?;
+            let id: i32 = row.get("id");
+            println!("created device {}", id);
+            println!("token: {}", token::generate_token(id)?);
+        }
+        DeviceCommand::List { all } => {
+            let rows = client
+                .query(
+                    "SELECT d.id, d.name, d.location, d.deleted_at,
+                            (SELECT MAX(created_at) FROM decibel_logs WHERE fk_device_id = d.id) AS last_reading
+                     FROM devices d WHERE $1 OR d.deleted_at IS NULL ORDER BY d.id",
+                    &[&all],
+                )
+                .await?;
+            println!("{:>5}  {:<24} {:<24} {:<25} deleted", "id", "name", "location", "last reading");
+            let time = |at: Option<DateTime<Utc>>| at.map(|at| at.to_rfc3339()).unwrap_or_else(|| "-".to_string());
+            for row in rows {
+                println!(
+                    "{:>5}  {:<24} {:<24} {:<25} {}",
+                    row.get::<_, i32>("id"),
+                    row.get::<_, Option<String>>("name").unwrap_or_else(|| "-".to_string()),
+                    row.get::<_, Option<String>>("location").unwrap_or_else(|| "-".to_string()),
+                    time(row.get("last_reading")),
+                    time(row.get("deleted_at")),
+                );
+            }
+        }
+        DeviceCommand::Rename { id, name } => {
+            let updated = client
+                .execute("UPDATE devices SET name = $2 WHERE id = $1 AND deleted_at IS NULL", &[&id, &name])
+                .await?;
+            if updated == 0 {
+                return Err(format!("device {} not found", id).into());
+            }
+            println!("renamed device {} to {}", id, name);
+        }
+        DeviceCommand::Delete { id } => {
+            let updated = client
+                .execute("UPDATE devices SET deleted_at = NOW() WHERE id = $1 AND deleted_at IS NULL", &[&id])
+                .await?;
+            if updated == 0 {
+                return Err(format!("device {} not found", id).into());
+            }
+            println!("deleted device {}", id);
+        }
+        DeviceCommand::Token { id } => {
+            let exists = client
+                .query_opt("SELECT 1 FROM devices WHERE id = $1 AND deleted_at IS NULL", &[&id])
+                .await?
+                .is_some();
+            if !exists {
+                return Err(format!("device {} not found", id).into());
+            }
+            println!("{}", token::generate_token(id)?);
+        }
+    }
+    Ok(())
+}
+
+// streams the rows, exports do not have to fit in memory
+async fn export(pool: &DbPool, args: ExportArgs) -> CommandResult {
+    let client = pool.get().await?;
+    // rows are streamed to the output, a long export is not a slow query
+    client.batch_execute("SET statement_timeout = 0").await?;
+    let devices = (!args.devices.is_empty()).then_some(args.devices);
+    let params: [&(dyn ToSql + Sync); 3] = [&devices, &args.from, &args.to];
+    let rows = client
+        .query_raw(
+            "SELECT fk_device_id, created_at, decibels FROM decibel_logs
+             WHERE ($1::int[] IS NULL OR fk_device_id = ANY($1))
+               AND ($2::timestamptz IS NULL OR created_at >= $2)
+               AND ($3::timestamptz IS NULL OR created_at < $3)
+             ORDER BY created_at, id",
+            params,
+        )
+        .await?;
+    pin_mut!(rows);
+
+    let mut out: BufWriter<Box<dyn Write>> = BufWriter::new(match &args.output {
+        Some(path) => Box::new(std::fs::File::create(path)?),
+        None => Box::new(std::io::stdout().lock()),
+    });
+    writeln!(out, "{}", CSV_HEADER)?;
+    let mut count = 0u64;
+    while let Some(row) = rows.try_next().await? {
+        let created_at: DateTime<Utc> = row.get("created_at");
+        writeln!(out, "{},{},{}", row.get::<_, i32>("fk_device_id"), created_at.to_rfc3339(), row.get::<_, f64>("decibels"))?;
+        count += 1;
+    }
+    out.flush()?;
+    tracing::info!(readings = count, "export finished");
+    Ok(())
+}
+
+// one transaction, a bad line or unknown device imports nothing. readings go straight to decibel_logs
+// and are not replayed through events, alerts or the live cache
+async fn import(pool: &DbPool, args: ImportArgs) -> CommandResult {
+    let input: Box<dyn BufRead> = if args.file.as_os_str() == "-" {
+        Box::new(BufReader::new(std::io::stdin()))
+    } else {
+        Box::new(BufReader::new(std::fs::File::open(&args.file)?))
+    };
+
+    let mut client = pool.get().await?;
+    let transaction = client.transaction().await?;
+    let statement = transaction
+        .prepare(
+            "INSERT INTO decibel_logs (fk_device_id, created_at, decibels)
+             SELECT * FROM UNNEST($1::int[], $2::timestamptz[], $3::float8[])",
+        )
+        .await?;
+
+    let mut devices = Vec::with_capacity(IMPORT_BATCH);
+    let mut times = Vec::with_capacity(IMPORT_BATCH);
+    let mut levels = Vec::with_capacity(IMPORT_BATCH);
+    let mut count = 0u64;
+    for (index, line) in input.lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+        if index == 0 {
+            if line != CSV_HEADER {
+                return Err(format!("line 1: expected the header {}", CSV_HEADER).into());
+            }
+            continue;
+        }
+        if line.is_empty() {
+            continue;
+        }
+
+        let invalid = || format!("line {}: expected device_id,created_at,decibels, got {:?}", index + 1, line);
+        let mut fields = line.split(',');
+        let (Some(device), Some(time), Some(level), None) = (fields.next(), fields.next(), fields.next(), fields.next()) else {
+            return Err(invalid().into());
+        };
+        devices.push(device.trim().parse::<i32>().map_err(|_| invalid())?);
+        times.push(time.trim().parse::<DateTime<Utc>>().map_err(|_| invalid())?);
+        levels.push(level.trim().parse::<f64>().map_err(|_| invalid())?);
+
+        if devices.len() == IMPORT_BATCH {
+            count += transaction.execute(&statement, &[&devices, &times, &levels]).await?;
+            devices.clear();
+            times.clear();
+            levels.clear();
+        }
+    }
+    if !devices.is_empty() {
+        count += transaction.execute(&statement, &[&devices, &times, &levels]).await?;
+    }
+    transaction.commit().await?;
+
+    println!("imported {} readings", count);
+    Ok(())
+}
+
+async fn retention(pool: &DbPool, dry_run: bool) -> CommandResult {
+    let settings = &config::get().retention;
+    let tables = [
+        ("decibel_logs", "created_at", "retention.readings_days", settings.readings_days),
+        ("noise_events", "started_at", "retention.events_days", settings.events_days),
+        ("audit_log", "created_at", "retention.audit_days", settings.audit_days),
+    ];
+
+    let client = pool.get().await?;
+    for (table, column, key, days) in tables {
+        if days == 0 {
+            println!("{}: kept, {} is 0", table, key);
+            continue;
+        }
+        let cutoff = Utc::now() - Duration::days(days.into());
+
+        if dry_run {
+            let row = client
+                .query_one(&format!("SELECT COUNT(*) FROM {} WHERE {} < $1", table, column), &[&cutoff])
+                .await?;
+            println!("{}: would delete {} rows before {}", table, row.get::<_, i64>(0), cutoff.to_rfc3339());
+            continue;
+        }
+
+        let statement = client
+            .prepare(&format!(
+                "DELETE FROM {table} WHERE id IN (SELECT id FROM {table} WHERE {column} < $1 LIMIT $2)"
+            ))
+            .await?;
+        let mut deleted = 0;
+        loop {
+            let batch = client.execute(&statement, &[&cutoff, &RETENTION_BATCH]).await?;
+            deleted += batch;
+            if batch < RETENTION_BATCH as u64 {
+                break;
+            }
+        }
+        println!("{}: deleted {} rows before {}", table, deleted, cutoff.to_rfc3339());
+    }
+    Ok(())
+}
@@ -1,45 +1,36 @@
-use std::env;
-use tokio_postgres::NoTls;
+use tokio_postgres::config::SslMode;
+use tokio_postgres_rustls::MakeRustlsConnect;
 use bb8::Pool;
 use bb8_postgres::PostgresConnectionManager;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::CertificateDer;
+use rustls::{ClientConfig, RootCertStore};
 use std::collections::HashSet;
-use std::time::Instant;
-
-pub type DbPool = Pool<PostgresConnectionManager<NoTls>>;
-
-pub async fn init_db() -> Result<DbPool, Box<dyn std::error::Error + Send + Sync>> {
-    let db_host = env::var("DB_HOST").unwrap_or_else(|_| "localhost".to_string());
-    let db_user = env::var("DB_USER").unwrap_or_else(|_| "postgres".to_string());
-    let db_password = env::var("DB_PASSWORD").unwrap_or_else(|_| "postgres".to_string());
-    let db_name = env::var("DB_NAME").unwrap_or_else(|_| "dbmonitor".to_string());
-    
-    let connection_string = format!(
-        "host={} user={} password={} dbname={}",
-        db_host, db_user, db_password, db_name
-    );
-    
-    println!("connecting to pgsql database at {}...", db_host);
-    
-    let manager = PostgresConnectionManager::new_from_stringlike(connection_string, NoTls)?;
-    
-    let pool = Pool::builder()
-        .max_size(20)
-        .min_idle(Some(2))
-        .max_lifetime(Some(std::time::Duration::from_secs(3600))) // 1 hour
-        .idle_timeout(Some(std::time::Duration::from_secs(600)))  // 10 min
-        .connection_timeout(std::time::Duration::from_secs(30))
-        .build(manager)
-        .await?;
-    
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+use crate::config::{self, DatabaseConfig};
+
+pub type DbPool = Pool<PostgresConnectionManager<MakeRustlsConnect>>;
+
+type InitResult<T> = Result<T, Box<dyn std::error::Error + Send + Sync>>;
+
+static INGEST_POOL: OnceLock<DbPool> = OnceLock::new();
+
+// the pool for api requests, reports and admin commands
+pub async fn init_db() -> InitResult<DbPool> {
+    let settings = &config::get().database;
+    let pool = build_pool(settings, &settings.application_name, settings.max_connections, settings.min_idle).await?;
+
     { // test connection and get version
         let conn = pool.get().await?;
         let rows = conn.query("SELECT version()", &[]).await?;
         
         if let Some(row) = rows.first() {
             let version: &str = row.get(0);
-            println!("{} connected with {} active connections", 
-                version.split_whitespace().take(2).collect::<Vec<_>>().join(" v").to_lowercase(),
-                pool.state().connections
+            tracing::info!(
+                version = %version.split_whitespace().take(2).collect::<Vec<_>>().join(" v").to_lowercase(),
+                connections = pool.state().connections,
+                "database connected"
             );
         }
     }
@@ -47,6 +38,111 @@ pub async fn init_db() -> Result<DbPool, Box<dyn std::error::Error + Send + Sync
     Ok(pool)
 }
 
+// the pool of the batch writer and the event and alert writers, kept apart so a burst of api queries
+// cannot hold every connection while readings pile up
+pub async fn init_ingest_db() -> InitResult<DbPool> {
+    let settings = &config::get().database;
+    let application_name = format!("{}-ingest", settings.application_name);
+    let pool = build_pool(settings, &application_name, settings.ingest_max_connections, 1).await?;
+    if INGEST_POOL.set(pool.clone()).is_err() {
+        tracing::warn!("ingest pool already initialized");
+    }
+    Ok(pool)
+}
+
+// for health checks and metrics, None before init_ingest_db and in admin commands
+pub fn ingest_pool() -> Option<&'static DbPool> {
+    INGEST_POOL.get()
+}
+
+async fn build_pool(settings: &DatabaseConfig, application_name: &str, max_size: u32, min_idle: u32) -> InitResult<DbPool> {
+    let pg_config = connection_config(settings, application_name)?;
+    tracing::info!(
+        host = ?pg_config.get_hosts(),
+        port = ?pg_config.get_ports(),
+        ssl_mode = ?pg_config.get_ssl_mode(),
+        application_name,
+        "connecting to pgsql database"
+    );
+
+    let tls = tls_config(settings, pg_config.get_ssl_mode())?;
+    let manager = PostgresConnectionManager::new(pg_config, MakeRustlsConnect::new(tls));
+
+    let pool = Pool::builder()
+        .max_size(max_size)
+        .min_idle(Some(min_idle.min(max_size)))
+        .max_lifetime(Some(Duration::from_secs(settings.max_lifetime_seconds)))
+        .idle_timeout(Some(Duration::from_secs(settings.idle_timeout_seconds)))
+        .connection_timeout(Duration::from_secs(settings.connection_timeout_seconds))
+        .build(manager)
+        .await?;
+
+    Ok(pool)
+}
+
+fn connection_config(settings: &DatabaseConfig, application_name: &str) -> InitResult<tokio_postgres::Config> {
+    let mut pg_config = match &settings.url {
+        Some(url) => url.parse::<tokio_postgres::Config>()?,
+        None => {
+            let mut pg_config = tokio_postgres::Config::new();
+            pg_config
+                .host(&settings.host)
+                .port(settings.port)
+                .user(&settings.user)
+                .password(&settings.password)
+                .dbname(&settings.name)
+                .ssl_mode(match settings.ssl_mode.as_str() {
+                    "require" => SslMode::Require,
+                    "prefer" => SslMode::Prefer,
+                    _ => SslMode::Disable,
+                });
+            pg_config
+        }
+    };
+
+    // an application_name in the url wins, it is what the operator sees in pg_stat_activity
+    if pg_config.get_application_name().is_none() {
+        pg_config.application_name(application_name);
+    }
+    if settings.statement_timeout_ms > 0 {
+        let options = match pg_config.get_options() {
+            Some(options) => format!("{} -c statement_timeout={}", options, settings.statement_timeout_ms),
+            None => format!("-c statement_timeout={}", settings.statement_timeout_ms),
+        };
+        pg_config.options(&options);
+    }
+    if pg_config.get_connect_timeout().is_none() {
+        pg_config.connect_timeout(Duration::from_secs(settings.connection_timeout_seconds));
+    }
+    Ok(pg_config)
+}
+
+// server certificates are always verified, against ssl_root_cert when set and the system roots otherwise
+fn tls_config(settings: &DatabaseConfig, ssl_mode: SslMode) -> InitResult<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    match &settings.ssl_root_cert {
+        _ if ssl_mode == SslMode::Disable => {}
+        Some(path) => {
+            for cert in CertificateDer::pem_file_iter(path)? {
+                roots.add(cert?)?;
+            }
+        }
+        None => {
+            let native = rustls_native_certs::load_native_certs();
+            for e in &native.errors {
+                tracing::warn!(error = %e, "skipping a system root certificate");
+            }
+            roots.add_parsable_certificates(native.certs);
+        }
+    }
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    Ok(ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()?
+        .with_root_certificates(roots)
+        .with_no_client_auth())
+}
+
 mod embedded {
     use refinery::embed_migrations;
     embed_migrations!("migrations");
@@ -55,7 +151,7 @@ mod embedded {
 pub async fn run_migrations(db_pool: &DbPool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut conn = db_pool.get().await?;
 
-    println!("determining migrations...");
+    tracing::info!("determining migrations");
     let start = Instant::now();
 
     // fetch versions that were already applied before running the migrations so we can later determine which ones are new
@@ -69,7 +165,11 @@ pub async fn run_migrations(db_pool: &DbPool) -> Result<(), Box<dyn std::error::
         .map(|row| row.get::<_, i32>("version"))
         .collect();
 
-    embedded::migrations::runner().run_async(&mut *conn).await?;
+    // a migration rewriting a large table may run longer than database.statement_timeout_ms
+    conn.batch_execute("SET statement_timeout = 0").await?;
+    let migrated = embedded::migrations::runner().run_async(&mut *conn).await;
+    conn.batch_execute("RESET statement_timeout").await?;
+    migrated?;
 
     // fetch history again to determine which migrations were applied in this run
     let post_rows = conn
@@ -93,24 +193,62 @@ pub async fn run_migrations(db_pool: &DbPool) -> Result<(), Box<dyn std::error::
     let total_migrations = previously_applied.len() + newly_applied.len();
 
     if newly_applied.is_empty() {
-        println!(
-            "no new migrations found in {:?} ({} already applied, {} total)",
-            start.elapsed(),
-            previously_applied.len(),
-            total_migrations
+        tracing::info!(
+            elapsed = ?start.elapsed(),
+            already_applied = previously_applied.len(),
+            total = total_migrations,
+            "no new migrations found"
         );
     } else {
-        println!(
-            "{} migrations applied in {:?} ({} already applied, {} total)",
-            newly_applied.len(),
-            start.elapsed(),
-            previously_applied.len(),
-            total_migrations
+        tracing::info!(
+            applied = newly_applied.len(),
+            elapsed = ?start.elapsed(),
+            already_applied = previously_applied.len(),
+            total = total_migrations,
+            "migrations applied"
         );
         for (ver, name) in newly_applied {
-            println!("  • V{}__{}", ver, name);
+            tracing::info!(version = ver, name = %name, "applied migration");
         }
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+// migrations recorded in refinery_schema_history as (version, name, applied_on), empty on a fresh database
+pub async fn applied_migrations(client: &tokio_postgres::Client) -> Result<Vec<(i32, String, String)>, tokio_postgres::Error> {
+    let exists: bool = client
+        .query_one("SELECT to_regclass('refinery_schema_history') IS NOT NULL", &[])
+        .await?
+        .get(0);
+    if !exists {
+        return Ok(Vec::new());
+    }
+
+    Ok(client
+        .query(
+            "SELECT version, COALESCE(name, '') AS name, COALESCE(applied_on, '') AS applied_on
+             FROM refinery_schema_history ORDER BY version",
+            &[],
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get("version"), row.get("name"), row.get("applied_on")))
+        .collect())
+}
+
+// embedded migrations missing from refinery_schema_history, empty once the schema is current
+pub async fn pending_migrations(client: &tokio_postgres::Client) -> Result<Vec<(u32, String)>, tokio_postgres::Error> {
+    let applied: HashSet<i32> = applied_migrations(client)
+        .await?
+        .into_iter()
+        .map(|(version, _, _)| version)
+        .collect();
+
+    Ok(embedded::migrations::runner()
+        .get_migrations()
+        .iter()
+        .filter(|migration| !applied.contains(&(migration.version() as i32)))
+        .map(|migration| (migration.version(), migration.name().to_string()))
+        .collect())
+}
@@ -0,0 +1,235 @@
+use chrono::{DateTime, Duration, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use dashmap::DashMap;
+use std::sync::LazyLock;
+use crate::config;
+use crate::schedule;
+
+// defaults of the dose.* settings, 3 db exchange rate as in iso 1999 with an 85 db criterion over 8 hours
+pub const DEFAULT_EXCHANGE_RATE: f64 = 3.0;
+pub const DEFAULT_CRITERION_LEVEL: f64 = 85.0;
+pub const DEFAULT_THRESHOLD_LEVEL: f64 = 80.0;
+pub const CRITERION_HOURS: f64 = 8.0;
+
+// a reading is held for at most this long when the next one is late
+pub const MAX_HOLD_SECONDS: f64 = 60.0;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DoseParams {
+    pub exchange_rate: f64,
+    pub criterion_level: f64,
+    pub threshold_level: f64,
+}
+
+impl Default for DoseParams {
+    fn default() -> Self {
+        Self {
+            exchange_rate: DEFAULT_EXCHANGE_RATE,
+            criterion_level: DEFAULT_CRITERION_LEVEL,
+            threshold_level: DEFAULT_THRESHOLD_LEVEL,
+        }
+    }
+}
+
+impl DoseParams {
+    // the configured parameters of the live gauge
+    pub fn live() -> Self {
+        let settings = &config::get().dose;
+        Self {
+            exchange_rate: settings.exchange_rate,
+            criterion_level: settings.criterion_level,
+            threshold_level: settings.threshold_level,
+        }
+    }
+
+    // permitted exposure time at a level, halved for every exchange rate step above the criterion
+    pub fn allowed_seconds(&self, decibels: f64) -> f64 {
+        CRITERION_HOURS * 3600.0 / 2f64.powf((decibels - self.criterion_level) / self.exchange_rate)
+    }
+
+    // dose fraction (1.0 = 100%) contributed by `seconds` of exposure at `decibels`
+    pub fn dose_fraction(&self, decibels: f64, seconds: f64) -> f64 {
+        if decibels < self.threshold_level {
+            return 0.0;
+        }
+        seconds / self.allowed_seconds(decibels)
+    }
+
+    // 8 hour time weighted average level producing the given dose
+    pub fn twa(&self, dose_percent: f64) -> Option<f64> {
+        if dose_percent <= 0.0 {
+            return None;
+        }
+        Some(self.criterion_level + self.exchange_rate / 2f64.log10() * (dose_percent / 100.0).log10())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct LiveDose {
+    pub device_id: i32,
+    pub shift_start: DateTime<Utc>,
+    pub dose_percent: f64,
+    pub twa: Option<f64>,
+    pub exposure_seconds: f64,
+}
+
+#[derive(Clone, Debug)]
+struct DoseAccumulator {
+    shift_start: DateTime<Utc>,
+    last_decibels: f64,
+    last_timestamp: DateTime<Utc>,
+    dose: f64,
+    exposure_seconds: f64,
+}
+
+static LIVE_DOSES: LazyLock<DashMap<i32, DoseAccumulator>> = LazyLock::new(|| {
+    DashMap::new()
+});
+
+// start of the shift containing `timestamp` for shifts of `shift_hours` anchored at `anchor` local time
+pub fn shift_start(timestamp: DateTime<Utc>, tz: Tz, anchor: NaiveTime, shift_hours: i64) -> DateTime<Utc> {
+    let local = timestamp.with_timezone(&tz);
+    let anchor_naive = local.date_naive().and_time(anchor);
+    let mut start = tz
+        .from_local_datetime(&anchor_naive)
+        .earliest()
+        .unwrap_or_else(|| tz.from_utc_datetime(&anchor_naive))
+        .with_timezone(&Utc);
+
+    if start > timestamp {
+        start -= Duration::days(1);
+    }
+    while start + Duration::hours(shift_hours) <= timestamp {
+        start += Duration::hours(shift_hours);
+    }
+    start
+}
+
+// live shifts follow dose.shift_start in the device's schedule time zone, utc without a schedule
+fn live_shift_start(device_id: i32, timestamp: DateTime<Utc>) -> DateTime<Utc> {
+    let settings = &config::get().dose;
+    let tz = schedule::schedule_for_device(device_id).map_or(Tz::UTC, |schedule| schedule.timezone);
+    shift_start(timestamp, tz, settings.shift_start, settings.shift_hours)
+}
+
+// integrates a new reading into the device's running dose, holding the previous level until now
+pub fn record_reading(device_id: i32, decibels: f64, timestamp: DateTime<Utc>) {
+    let params = DoseParams::live();
+    let shift_start = live_shift_start(device_id, timestamp);
+
+    let mut entry = LIVE_DOSES.entry(device_id).or_insert_with(|| DoseAccumulator {
+        shift_start,
+        last_decibels: decibels,
+        last_timestamp: timestamp,
+        dose: 0.0,
+        exposure_seconds: 0.0,
+    });
+    let acc = entry.value_mut();
+
+    if acc.shift_start != shift_start {
+        *acc = DoseAccumulator {
+            shift_start,
+            last_decibels: decibels,
+            last_timestamp: timestamp,
+            dose: 0.0,
+            exposure_seconds: 0.0,
+        };
+        return;
+    }
+
+    let elapsed = (timestamp - acc.last_timestamp).num_microseconds().unwrap_or(0) as f64 / 1_000_000.0;
+    let held = elapsed.clamp(0.0, MAX_HOLD_SECONDS);
+
+    acc.dose += params.dose_fraction(acc.last_decibels, held);
+    acc.exposure_seconds += held;
+    acc.last_decibels = decibels;
+    acc.last_timestamp = timestamp.max(acc.last_timestamp);
+}
+
+pub fn live_dose(device_id: i32) -> Option<LiveDose> {
+    let acc = LIVE_DOSES.get(&device_id)?;
+    if acc.shift_start != live_shift_start(device_id, Utc::now()) {
+        return None;
+    }
+
+    let dose_percent = acc.dose * 100.0;
+    Some(LiveDose {
+        device_id,
+        shift_start: acc.shift_start,
+        dose_percent,
+        twa: DoseParams::live().twa(dose_percent),
+        exposure_seconds: acc.exposure_seconds,
+    })
+}
+
+pub fn live_doses() -> Vec<LiveDose> {
+    let mut device_ids: Vec<i32> = LIVE_DOSES.iter().map(|entry| *entry.key()).collect();
+    device_ids.sort_unstable();
+    device_ids.into_iter().filter_map(live_dose).collect()
+}
+
+// drops accumulators from previous shifts
+pub fn cleanup_old_entries() {
+    let now = Utc::now();
+    LIVE_DOSES.retain(|device_id, acc| acc.shift_start == live_shift_start(*device_id, now));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EIGHT_HOURS: f64 = 8.0 * 3600.0;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-6, "{} is not {}", actual, expected);
+    }
+
+    // iso 1999 style, 3 db exchange rate and an 85 db criterion
+    fn iso() -> DoseParams {
+        DoseParams::default()
+    }
+
+    // osha style, 5 db exchange rate and a 90 db criterion
+    fn osha() -> DoseParams {
+        DoseParams { exchange_rate: 5.0, criterion_level: 90.0, threshold_level: 80.0 }
+    }
+
+    #[test]
+    fn eight_hours_at_the_criterion_is_full_dose() {
+        for params in [iso(), osha()] {
+            assert_close(params.allowed_seconds(params.criterion_level), EIGHT_HOURS);
+            assert_close(params.dose_fraction(params.criterion_level, EIGHT_HOURS), 1.0);
+            assert_close(params.twa(100.0).unwrap(), params.criterion_level);
+        }
+    }
+
+    #[test]
+    fn each_exchange_rate_step_halves_the_allowed_time() {
+        for params in [iso(), osha()] {
+            let step = params.criterion_level + params.exchange_rate;
+            assert_close(params.allowed_seconds(step), EIGHT_HOURS / 2.0);
+            assert_close(params.dose_fraction(step, EIGHT_HOURS), 2.0);
+            assert_close(params.twa(200.0).unwrap(), step);
+            assert_close(params.twa(50.0).unwrap(), params.criterion_level - params.exchange_rate);
+        }
+    }
+
+    #[test]
+    fn levels_below_the_threshold_add_no_dose() {
+        assert_eq!(iso().dose_fraction(79.9, EIGHT_HOURS), 0.0);
+        assert_eq!(iso().twa(0.0), None);
+    }
+
+    #[test]
+    fn shifts_follow_the_anchor_in_local_time() {
+        let anchor = NaiveTime::from_hms_opt(6, 0, 0).unwrap();
+        let berlin: Tz = "Europe/Berlin".parse().unwrap();
+        let at = |s: &str| s.parse::<DateTime<Utc>>().unwrap();
+
+        // 03:00 utc is 05:00 in berlin, still the night shift that started at 22:00 local
+        assert_eq!(shift_start(at("2026-07-06T03:00:00Z"), berlin, anchor, 8), at("2026-07-05T20:00:00Z"));
+        assert_eq!(shift_start(at("2026-07-06T04:00:00Z"), berlin, anchor, 8), at("2026-07-06T04:00:00Z"));
+        assert_eq!(shift_start(at("2026-07-06T03:00:00Z"), Tz::UTC, anchor, 8), at("2026-07-05T22:00:00Z"));
+        assert_eq!(shift_start(at("2026-07-06T13:59:59Z"), Tz::UTC, anchor, 8), at("2026-07-06T06:00:00Z"));
+    }
+}
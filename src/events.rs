@@ -0,0 +1,320 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::sync::LazyLock;
+use tokio::sync::mpsc;
+use crate::acoustics;
+use crate::config::{self, EventsConfig};
+use crate::database::DbPool;
+use crate::websocket;
+
+// thresholds and the background time constants are events.* in the configuration
+
+// a reading is held for at most this long, a longer silence closes the open event
+pub const MAX_HOLD_SECONDS: f64 = 5.0;
+
+#[derive(Clone, Debug)]
+pub struct NoiseEvent {
+    pub device_id: i32,
+    pub started_at: DateTime<Utc>,
+    pub ended_at: DateTime<Utc>,
+    pub peak_db: f64,
+    pub leq_db: f64,
+    pub sel_db: f64,
+    pub background_db: f64,
+}
+
+impl NoiseEvent {
+    pub fn duration_ms(&self) -> i64 {
+        (self.ended_at - self.started_at).num_milliseconds()
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ActiveEvent {
+    started_at: DateTime<Utc>,
+    peak_db: f64,
+    energy_seconds: f64, // sum of energy * held seconds, the sound exposure
+    seconds: f64,
+    background_energy: f64, // when the event started, the background keeps adapting during it
+}
+
+#[derive(Clone, Debug)]
+struct DetectorState {
+    background_energy: f64,
+    last_decibels: f64,
+    last_timestamp: DateTime<Utc>,
+    active: Option<ActiveEvent>,
+}
+
+static DETECTORS: LazyLock<DashMap<i32, DetectorState>> = LazyLock::new(|| {
+    DashMap::new()
+});
+
+static EVENT_QUEUE: LazyLock<tokio::sync::Mutex<Option<mpsc::UnboundedSender<NoiseEvent>>>> =
+    LazyLock::new(|| tokio::sync::Mutex::new(None));
+
+pub async fn init_event_writer(pool: DbPool) {
+    let (tx, rx) = mpsc::unbounded_channel::<NoiseEvent>();
+
+    {
+        let mut queue = EVENT_QUEUE.lock().await;
+        *queue = Some(tx);
+    }
+
+    tokio::spawn(event_writer(rx, pool));
+}
+
+async fn event_writer(mut rx: mpsc::UnboundedReceiver<NoiseEvent>, pool: DbPool) {
+    while let Some(event) = rx.recv().await {
+        let client = match pool.get().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                tracing::error!(error = %e, "failed to get database connection for event insert");
+                continue;
+            }
+        };
+
+        let duration_ms = event.duration_ms() as i32;
+        if let Err(e) = client
+            .execute(
+                "INSERT INTO noise_events (fk_device_id, started_at, ended_at, duration_ms, peak_db, leq_db, sel_db, background_db)
+                 VALUES ($1, $2, $3, $4, $5, $6, $7, $8)",
+                &[
+                    &event.device_id,
+                    &event.started_at,
+                    &event.ended_at,
+                    &duration_ms,
+                    &event.peak_db,
+                    &event.leq_db,
+                    &event.sel_db,
+                    &event.background_db,
+                ],
+            )
+            .await
+        {
+            tracing::error!(error = %e, "event insert error");
+        }
+    }
+}
+
+fn close_event(
+    device_id: i32,
+    active: ActiveEvent,
+    ended_at: DateTime<Utc>,
+    settings: &EventsConfig,
+) -> Option<NoiseEvent> {
+    let event = NoiseEvent {
+        device_id,
+        started_at: active.started_at,
+        ended_at,
+        peak_db: active.peak_db,
+        leq_db: acoustics::from_energy(active.energy_seconds / active.seconds.max(f64::EPSILON)),
+        sel_db: acoustics::from_energy(active.energy_seconds),
+        background_db: acoustics::from_energy(active.background_energy),
+    };
+
+    (event.duration_ms() >= settings.min_duration_ms && active.seconds > 0.0).then_some(event)
+}
+
+impl DetectorState {
+    fn new(decibels: f64, timestamp: DateTime<Utc>) -> Self {
+        DetectorState {
+            background_energy: acoustics::to_energy(decibels),
+            last_decibels: decibels,
+            last_timestamp: timestamp,
+            active: None,
+        }
+    }
+
+    // feeds one reading through the detector, returns an event once it has ended
+    fn feed(&mut self, device_id: i32, decibels: f64, timestamp: DateTime<Utc>, settings: &EventsConfig) -> Option<NoiseEvent> {
+        let elapsed = (timestamp - self.last_timestamp).num_microseconds().unwrap_or(0) as f64 / 1_000_000.0;
+        let held = elapsed.clamp(0.0, MAX_HOLD_SECONDS);
+        let mut finished = None;
+
+        // the previous level is held until this reading
+        if let Some(active) = self.active.as_mut() {
+            active.energy_seconds += acoustics::to_energy(self.last_decibels) * held;
+            active.seconds += held;
+        }
+
+        // a silent device ends its event where the last reading stopped being held
+        if elapsed > MAX_HOLD_SECONDS && let Some(active) = self.active.take() {
+            let ended_at = self.last_timestamp + chrono::Duration::milliseconds((MAX_HOLD_SECONDS * 1000.0) as i64);
+            finished = close_event(device_id, active, ended_at, settings);
+        }
+
+        let threshold = acoustics::from_energy(self.background_energy) + settings.rise_db;
+
+        if decibels >= threshold {
+            match self.active.as_mut() {
+                Some(active) => active.peak_db = active.peak_db.max(decibels),
+                None => {
+                    self.active = Some(ActiveEvent {
+                        started_at: timestamp,
+                        peak_db: decibels,
+                        energy_seconds: 0.0,
+                        seconds: 0.0,
+                        background_energy: self.background_energy,
+                    });
+                }
+            }
+        } else if let Some(active) = self.active.take() {
+            finished = close_event(device_id, active, timestamp, settings);
+        }
+
+        // short events barely move the background, a level that stays up is learned and ends the event
+        let time_constant = if self.active.is_some() {
+            settings.event_background_time_constant_seconds
+        } else {
+            settings.background_time_constant_seconds
+        };
+        let alpha = 1.0 - (-held / time_constant).exp();
+        self.background_energy += alpha * (acoustics::to_energy(decibels) - self.background_energy);
+
+        self.last_decibels = decibels;
+        self.last_timestamp = timestamp.max(self.last_timestamp);
+
+        finished
+    }
+}
+
+fn detect(device_id: i32, decibels: f64, timestamp: DateTime<Utc>) -> Option<NoiseEvent> {
+    DETECTORS
+        .entry(device_id)
+        .or_insert_with(|| DetectorState::new(decibels, timestamp))
+        .feed(device_id, decibels, timestamp, &config::get().events)
+}
+
+async fn emit(event: NoiseEvent) {
+    websocket::broadcast_event(&event);
+
+    let queue = EVENT_QUEUE.lock().await;
+    if let Some(sender) = queue.as_ref() {
+        if sender.send(event).is_err() {
+            tracing::warn!("event queue channel closed");
+        }
+    } else {
+        tracing::warn!("event writer not initialized");
+    }
+}
+
+pub async fn record_reading(device_id: i32, decibels: f64, timestamp: DateTime<Utc>) {
+    if let Some(event) = detect(device_id, decibels, timestamp) {
+        emit(event).await;
+    }
+}
+
+// closes events of devices that stopped reporting mid-event and forgets idle detectors
+pub async fn flush_stale() {
+    let now = Utc::now();
+    let mut finished = Vec::new();
+
+    DETECTORS.retain(|device_id, state| {
+        let idle = (now - state.last_timestamp).num_seconds() as f64;
+        if idle <= MAX_HOLD_SECONDS {
+            return true;
+        }
+        if let Some(mut active) = state.active.take() {
+            active.energy_seconds += acoustics::to_energy(state.last_decibels) * MAX_HOLD_SECONDS;
+            active.seconds += MAX_HOLD_SECONDS;
+            let ended_at = state.last_timestamp + chrono::Duration::milliseconds((MAX_HOLD_SECONDS * 1000.0) as i64);
+            finished.extend(close_event(*device_id, active, ended_at, &config::get().events));
+        }
+        idle < 300.0
+    });
+
+    for event in finished {
+        emit(event).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STEP_MS: i64 = 100;
+
+    struct Feed {
+        state: DetectorState,
+        timestamp: DateTime<Utc>,
+        settings: EventsConfig,
+    }
+
+    impl Feed {
+        // a detector that has settled on `decibels` for a minute
+        fn settled(decibels: f64) -> Self {
+            let timestamp = "2026-07-06T12:00:00Z".parse().unwrap();
+            let mut feed = Feed { state: DetectorState::new(decibels, timestamp), timestamp, settings: EventsConfig::default() };
+            assert!(feed.hold(decibels, 60_000).is_empty());
+            feed
+        }
+
+        // one reading every 100 ms at `decibels` for `ms`, returns the events that ended
+        fn hold(&mut self, decibels: f64, ms: i64) -> Vec<NoiseEvent> {
+            let mut events = Vec::new();
+            for _ in 0..ms / STEP_MS {
+                self.timestamp += chrono::Duration::milliseconds(STEP_MS);
+                events.extend(self.state.feed(1, decibels, self.timestamp, &self.settings));
+            }
+            events
+        }
+    }
+
+    #[test]
+    fn event_opens_and_closes() {
+        let mut feed = Feed::settled(50.0);
+        let start = feed.timestamp + chrono::Duration::milliseconds(STEP_MS);
+        assert!(feed.hold(70.0, 2_000).is_empty());
+        assert!(feed.state.active.is_some());
+
+        let events = feed.hold(50.0, 1_000);
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.started_at, start);
+        assert_eq!(event.duration_ms(), 2_000);
+        assert_eq!(event.peak_db, 70.0);
+        assert!((event.leq_db - 70.0).abs() < 0.01);
+        assert!((event.background_db - 50.0).abs() < 0.01);
+        assert!(feed.state.active.is_none());
+    }
+
+    #[test]
+    fn short_event_is_dropped() {
+        let mut feed = Feed::settled(50.0);
+        assert!(feed.hold(75.0, 300).is_empty());
+        assert!(feed.state.active.is_some());
+        assert!(feed.hold(50.0, 1_000).is_empty());
+        assert!(feed.state.active.is_none());
+    }
+
+    #[test]
+    fn rise_below_threshold_is_no_event() {
+        let mut feed = Feed::settled(50.0);
+        assert!(feed.hold(59.0, 5_000).is_empty());
+        assert!(feed.state.active.is_none());
+    }
+
+    #[test]
+    fn lasting_step_up_ends_the_event() {
+        let mut feed = Feed::settled(50.0);
+        let events = feed.hold(65.0, 120_000);
+        assert_eq!(events.len(), 1);
+        assert!(feed.state.active.is_none());
+        // the new level became the background
+        assert!(feed.hold(65.0, 60_000).is_empty());
+        assert!(feed.state.active.is_none());
+    }
+
+    #[test]
+    fn silent_device_closes_the_event_after_the_hold() {
+        let mut feed = Feed::settled(50.0);
+        let start = feed.timestamp + chrono::Duration::milliseconds(STEP_MS);
+        feed.hold(70.0, 1_000);
+        feed.timestamp += chrono::Duration::seconds(30);
+        let event = feed.state.feed(1, 50.0, feed.timestamp, &feed.settings).expect("event closed");
+        assert_eq!(event.started_at, start);
+        // the last reading is held for MAX_HOLD_SECONDS
+        assert_eq!(event.duration_ms(), 900 + (MAX_HOLD_SECONDS * 1000.0) as i64);
+    }
+}
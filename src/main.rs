@@ -1,6 +1,6 @@
 use axum::{
     middleware as axum_mw,
-    routing::{get, post},
+    routing::{delete, get, post, put},
     Router,
 };
 
@@ -10,44 +10,165 @@ mod database;
 mod token;
 mod websocket;
 mod cache;
+mod schedule;
+mod acoustics;
+mod dose;
+mod events;
+mod report;
+mod auth;
+mod audit;
+mod alerts;
+mod assets;
+mod telemetry;
+mod config;
+mod cli;
+mod tls;
+use clap::Parser;
 use middleware as mw;
+use auth::Permission;
 
 #[tokio::main]
 async fn main() {
+    let mut cli = config::Cli::parse();
+    let settings = match config::load(&cli) {
+        Ok(settings) => settings,
+        Err(e) => {
+            // logging is configured by the file that failed, so this goes straight to stderr
+            eprintln!("invalid configuration:\n{}", e);
+            std::process::exit(2);
+        }
+    };
+    if cli.print_config {
+        print!("{}", settings.redacted_toml());
+        return;
+    }
+    config::set(settings);
+
+    match cli.command.take().unwrap_or(cli::Command::Serve) {
+        cli::Command::Serve => serve().await,
+        command => {
+            telemetry::init_logging(true);
+            if let Err(e) = cli::run(command).await {
+                eprintln!("error: {}", e);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+async fn serve() {
+    let settings = config::get();
+    let bind = settings.server.bind;
+    let cleanup_interval = settings.cache.cleanup_interval_seconds;
+
+    telemetry::init_logging(false);
+    telemetry::init().expect("installing the metrics recorder failed");
     let db_pool = database::init_db().await.expect("database connection failed");
     database::run_migrations(&db_pool).await.expect("database migrations failed");
     
-    cache::init_batch_processor(db_pool.clone()).await;
+    let ingest_pool = database::init_ingest_db().await.expect("database connection failed");
+    
+    cache::init_batch_processor(ingest_pool.clone()).await;
+    events::init_event_writer(ingest_pool.clone()).await;
+    audit::init_audit_writer(db_pool.clone()).await;
+    alerts::init_alert_writer(ingest_pool).await;
+    schedule::reload(&db_pool).await.expect("loading noise schedules failed");
+    report::start_scheduler(db_pool.clone());
+    auth::bootstrap_admin(&db_pool).await.expect("creating the first user failed");
+    assets::precompress();
     
     // cache cleanup task
-    tokio::spawn(async {
-        let mut interval = tokio::time::interval(std::time::Duration::from_secs(300));
+    let cleanup_pool = db_pool.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(cleanup_interval));
         loop {
             interval.tick().await;
             cache::cleanup_old_entries().await;
+            dose::cleanup_old_entries();
+            events::flush_stale().await;
+            websocket::cleanup_old_entries();
+            auth::cleanup_expired_sessions(&cleanup_pool).await;
         }
     });
     
-    // jwt required routes
+    // device jwt routes, ingestion only
     let log_routes = Router::new()
-        .route("/api/logs", get(routes::api::get_logs))
         .route("/api/logs", post(routes::api::add_log))
-        .layer(axum_mw::from_fn(mw::device_auth));
+        .layer(axum_mw::from_fn_with_state(db_pool.clone(), mw::device_auth));
+
+    // logged in users, every route declares the permission it needs. api tokens only get view_data
+    let require = |permission| axum_mw::from_fn_with_state(permission, mw::require_permission);
+    let user_routes = Router::new()
+        .route("/", get(routes::pages::dashboard).layer(require(Permission::ViewData)))
+        .route("/api/logs", get(routes::api::get_logs).layer(require(Permission::ViewData)))
+        .route("/api/db-status", get(routes::api::db_status).layer(require(Permission::ViewData)))
+        .route("/api/cache-status", get(routes::api::cache_status).layer(require(Permission::ViewData)))
+        .route("/metrics", get(routes::api::metrics).layer(require(Permission::ViewData)))
+        .route("/api/me", get(routes::users::me).layer(require(Permission::ViewData)))
+        .route("/api/users", get(routes::users::list_users).layer(require(Permission::ManageUsers)))
+        .route("/api/users", post(routes::users::create_user).layer(require(Permission::ManageUsers)))
+        .route("/api/users/{id}/roles", put(routes::users::set_roles).layer(require(Permission::ManageUsers)))
+        .route("/api/audit", get(routes::users::audit_log).layer(require(Permission::ManageUsers)))
+        .route("/api/tokens", get(routes::users::list_tokens).layer(require(Permission::ViewData)))
+        .route("/api/tokens", post(routes::users::create_token).layer(require(Permission::ViewData)))
+        .route("/api/tokens/{id}", delete(routes::users::revoke_token).layer(require(Permission::ViewData)))
+        .route("/devices/{id}", get(routes::pages::device_detail).layer(require(Permission::ViewData)))
+        .route("/api/auth", post(routes::api::auth).layer(require(Permission::ManageDevices)))
+        .route("/api/devices/{id}", put(routes::devices::update_device).layer(require(Permission::ManageDevices)))
+        .route("/api/groups", get(routes::groups::list_groups).layer(require(Permission::ViewData)))
+        .route("/api/groups", post(routes::groups::create_group).layer(require(Permission::ManageDevices)))
+        .route("/api/groups/{id}", delete(routes::groups::delete_group).layer(require(Permission::ManageDevices)))
+        .route("/api/groups/{id}/devices", put(routes::groups::set_group_devices).layer(require(Permission::EditGroups)))
+        .route("/api/schedules", get(routes::schedules::list_schedules).layer(require(Permission::ViewData)))
+        .route("/api/schedules", post(routes::schedules::create_schedule).layer(require(Permission::ManageRules)))
+        .route("/api/schedules/{id}", put(routes::schedules::update_schedule).layer(require(Permission::ManageRules)))
+        .route("/api/schedules/{id}", delete(routes::schedules::delete_schedule).layer(require(Permission::ManageRules)))
+        .route("/api/schedules/{id}/assignments", put(routes::schedules::set_assignments).layer(require(Permission::ManageRules)))
+        .route("/api/schedules/{id}/compliance", get(routes::schedules::compliance).layer(require(Permission::ViewData)))
+        .route("/api/reports/lden", get(routes::reports::lden).layer(require(Permission::ViewData)))
+        .route("/api/dose", get(routes::reports::dose).layer(require(Permission::ViewData)))
+        .route("/api/dose/live", get(routes::reports::live_dose).layer(require(Permission::ViewData)))
+        .route("/api/history", get(routes::history::history).layer(require(Permission::ViewData)))
+        .route("/api/heatmap", get(routes::history::heatmap).layer(require(Permission::ViewData)))
+        .route("/compare", get(routes::pages::compare).layer(require(Permission::ViewData)))
+        .route("/api/events", get(routes::events::list_events).layer(require(Permission::ViewData)))
+        .route("/api/reports", get(routes::reports::list_reports).layer(require(Permission::ViewData)))
+        .route("/api/reports", post(routes::reports::create_report).layer(require(Permission::RunReports)))
+        .route("/api/report-schedules", get(routes::reports::list_report_schedules).layer(require(Permission::ViewData)))
+        .route("/api/report-schedules", post(routes::reports::create_report_schedule).layer(require(Permission::ManageRules)))
+        .route("/api/report-schedules/{id}", delete(routes::reports::delete_report_schedule).layer(require(Permission::ManageRules)))
+        .route("/reports", get(routes::reports::reports_index).layer(require(Permission::ViewData)))
+        .route("/reports/{id}/{file}", get(routes::reports::report_file).layer(require(Permission::ViewData)))
+        .route("/api/alerts", get(routes::alerts::list_alerts).layer(require(Permission::ViewData)))
+        .route("/api/alerts/{id}/acknowledge", post(routes::alerts::acknowledge_alert).layer(require(Permission::AcknowledgeAlerts)))
+        .route("/api/silences", get(routes::alerts::list_silences).layer(require(Permission::ViewData)))
+        .route("/api/silences", post(routes::alerts::create_silence).layer(require(Permission::AcknowledgeAlerts)))
+        .route("/api/silences/{id}", delete(routes::alerts::cancel_silence).layer(require(Permission::AcknowledgeAlerts)))
+        .route("/fragments/alerts", get(routes::alerts::alerts_fragment).layer(require(Permission::ViewData)))
+        .route("/api/stream", get(websocket::stream_handler).layer(require(Permission::ViewData)))
+        .route("/fragments/active-devices", get(routes::api::active_devices_fragment).layer(require(Permission::ViewData)))
+        .layer(axum_mw::from_fn_with_state(db_pool.clone(), mw::user_auth));
 
     let app = Router::new()
-        .route("/", get(routes::pages::dashboard))
-        .route("/api/db-status", get(routes::api::db_status))
-        .route("/api/cache-status", get(routes::api::cache_status))
-        .route("/api/auth", get(routes::api::auth)) // need to add password or something to this route
-        .route("/fragments/active-devices", get(routes::api::active_devices_fragment))
-        .route("/static/computed.css", get(routes::pages::serve_css))
-        .route("/ws", get(websocket::websocket_handler))
+        .route("/login", get(routes::users::login_page).post(routes::users::login))
+        .route("/logout", post(routes::users::logout))
+        .route("/healthz", get(routes::health::healthz))
+        .route("/readyz", get(routes::health::readyz))
+        .route("/static/{*path}", get(routes::pages::serve_static))
+        .route("/ws", get(websocket::websocket_handler)) // authenticates the upgrade itself
+        .merge(user_routes)
         .merge(log_routes)
         .fallback(routes::pages::not_found)
         .layer(axum_mw::from_fn(mw::logger))
         .with_state(db_pool);
 
-    let listener = tokio::net::TcpListener::bind("192.168.1.134:3010").await.unwrap();
-    println!("server running on http://192.168.1.134:3010");
-    axum::serve(listener, app).await.unwrap();
+    if settings.tls.enabled() {
+        let listener = tls::bind(bind).await.expect("starting tls failed");
+        tracing::info!("server running on https://{}", bind);
+        axum::serve(listener, app.into_make_service_with_connect_info::<tls::TlsPeer>()).await.unwrap();
+    } else {
+        let listener = tokio::net::TcpListener::bind(bind).await.unwrap();
+        tracing::info!("server running on http://{}", bind);
+        axum::serve(listener, app).await.unwrap();
+    }
 }
\ No newline at end of file
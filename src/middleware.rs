@@ -1,53 +1,283 @@
 use axum::{
-    extract::Request,
+    extract::{ConnectInfo, MatchedPath, Query, RawPathParams, Request, State},
     middleware::Next,
-    response::Response,
-    http::StatusCode,
+    response::{Redirect, Response},
+    http::{header, Method, StatusCode, Uri},
     response::IntoResponse,
+    RequestExt,
 };
+use serde::Deserialize;
 use std::time::Instant;
+use tracing::Instrument;
+use crate::audit::{self, AuditEntry};
+use crate::auth::{self, Permission, Principal, Scope};
+use crate::database::DbPool;
+use crate::telemetry::{self, RequestId};
+use crate::tls::TlsPeer;
 use crate::token;
 
-pub async fn logger(request: Request, next: Next) -> Response {
+// tags every request with a `telemetry::RequestId`, runs it inside a span carrying the id so handler
+// logs are attributed to it, and logs one line per request. successful ingestion is sampled
+pub async fn logger(mut request: Request, next: Next) -> Response {
     let start = Instant::now();
     let method = request.method().clone();
-    let uri = request.uri().clone();
+    let uri = loggable_uri(request.uri());
+    // the route template keeps label cardinality bounded, unmatched paths share one label
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| "unmatched".to_string());
+    let request_id = RequestId::from_headers(request.headers());
+    request.extensions_mut().insert(request_id.clone());
 
-    let response = next.run(request).await;
+    let span = tracing::info_span!("request", request_id = %request_id, method = %method, route = %route);
+    let mut response = next.run(request).instrument(span.clone()).await;
     let duration = start.elapsed();
     let status = response.status();
 
-    println!(
-        "{} {} {} - {:?}",
-        method,
-        uri,
-        status,
-        duration
-    );
+    let labels = [
+        ("method", method.to_string()),
+        ("route", route),
+        ("status", status.as_u16().to_string()),
+    ];
+    if telemetry::should_log_request(&method, &labels[1].1, status) {
+        span.in_scope(|| {
+            if status.is_server_error() {
+                tracing::error!(uri = %uri, status = status.as_u16(), latency = ?duration, "request failed");
+            } else {
+                tracing::info!(uri = %uri, status = status.as_u16(), latency = ?duration, "request");
+            }
+        });
+    }
+    metrics::counter!(telemetry::REQUESTS, &labels).increment(1);
+    metrics::histogram!(telemetry::REQUEST_DURATION, &labels).record(duration.as_secs_f64());
 
+    response.headers_mut().insert(telemetry::REQUEST_ID_HEADER, request_id.header_value());
     response
 }
 
-// verifies `Authorization: Bearer <token>` header and injects `device_id: i32` into request extensions
-pub async fn device_auth(mut req: Request, next: Next) -> Response {
-    let Some(auth_header) = req.headers().get("Authorization") else {
-        return (StatusCode::UNAUTHORIZED, "missing authorization header").into_response();
+// the request uri with the value of `access_token` (api tokens on `/ws`) replaced, tokens must not end up in logs
+fn loggable_uri(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
     };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| if pair.starts_with("access_token=") { "access_token=redacted" } else { pair })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+// verifies `Authorization: Bearer <token>` header and injects `device_id: i32` into request extensions.
+// without the header a client certificate naming the device (see `tls::TlsPeer`) is accepted instead.
+// deleted devices are refused either way
+pub async fn device_auth(State(pool): State<DbPool>, mut req: Request, next: Next) -> Response {
+    let device_id = match req.headers().get("Authorization") {
+        Some(auth_header) => {
+            let Ok(auth_str) = auth_header.to_str() else {
+                return (StatusCode::UNAUTHORIZED, "invalid authorization header").into_response();
+            };
 
-    let Ok(auth_str) = auth_header.to_str() else {
-        return (StatusCode::UNAUTHORIZED, "invalid authorization header").into_response();
+            let token = auth_str.strip_prefix("Bearer ").unwrap_or("");
+            if token.is_empty() {
+                return (StatusCode::UNAUTHORIZED, "invalid bearer token").into_response();
+            }
+
+            match token::verify_token(token) {
+                Ok(claims) => claims.device_id,
+                Err(_) => return (StatusCode::UNAUTHORIZED, "invalid or expired token").into_response(),
+            }
+        }
+        None => {
+            let certified = req
+                .extensions()
+                .get::<ConnectInfo<TlsPeer>>()
+                .and_then(|ConnectInfo(peer)| peer.device_id);
+            match certified {
+                Some(device_id) => device_id,
+                None => return (StatusCode::UNAUTHORIZED, "missing authorization header").into_response(),
+            }
+        }
     };
 
-    let token = auth_str.strip_prefix("Bearer ").unwrap_or("");
-    if token.is_empty() {
-        return (StatusCode::UNAUTHORIZED, "invalid bearer token").into_response();
+    match token::device_active(&pool, device_id).await {
+        Some(true) => {
+            req.extensions_mut().insert(device_id);
+            next.run(req).await
+        }
+        Some(false) => (StatusCode::UNAUTHORIZED, "device deleted or unknown").into_response(),
+        None => (StatusCode::SERVICE_UNAVAILABLE, "database unavailable").into_response(),
     }
+}
 
-    match token::verify_token(token) {
-        Ok(claims) => {
-            req.extensions_mut().insert(claims.device_id);
-            next.run(req).await
+// requires a session cookie or api token and injects the `auth::Principal`, browsers
+// asking for a page are sent to the login form instead of getting a bare 401
+pub async fn user_auth(State(pool): State<DbPool>, mut req: Request, next: Next) -> Response {
+    let Some(principal) = auth::authenticate(&pool, req.headers(), None).await else {
+        let wants_html = req
+            .headers()
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+        let is_htmx = req.headers().contains_key("HX-Request");
+
+        if req.method() == Method::GET && wants_html && !is_htmx {
+            let next_path = req.uri().path_and_query().map(|p| p.as_str()).unwrap_or("/");
+            return Redirect::to(&format!("/login?next={}", urlencode(next_path))).into_response();
         }
-        Err(_) => (StatusCode::UNAUTHORIZED, "invalid or expired token").into_response(),
+        return (StatusCode::UNAUTHORIZED, "login required").into_response();
+    };
+
+    if principal.read_only && !matches!(*req.method(), Method::GET | Method::HEAD) {
+        return (StatusCode::FORBIDDEN, "api tokens are read-only").into_response();
     }
-} 
\ No newline at end of file
+
+    req.extensions_mut().insert(principal);
+    next.run(req).await
+}
+
+fn urlencode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct ScopeTarget {
+    device_id: Option<i32>,
+    group_id: Option<i32>,
+}
+
+// the device and group a request names, in its query or as the `{id}` of a device or group route
+async fn scope_target(req: &mut Request) -> (Option<i32>, Option<i32>) {
+    let target = Query::<ScopeTarget>::try_from_uri(req.uri())
+        .map(|Query(target)| (target.device_id, target.group_id))
+        .unwrap_or((None, None));
+
+    let Some(matched) = req.extensions().get::<MatchedPath>().map(|path| path.as_str().to_string()) else {
+        return target;
+    };
+    let is_group_route = matched.starts_with("/api/groups/{id}");
+    let is_device_route = matched.starts_with("/devices/{id}") || matched.starts_with("/api/devices/{id}");
+    if !is_group_route && !is_device_route {
+        return target;
+    }
+
+    let path_id = req
+        .extract_parts::<RawPathParams>()
+        .await
+        .ok()
+        .and_then(|params| params.iter().find(|(name, _)| *name == "id").and_then(|(_, id)| id.parse().ok()));
+    if is_group_route {
+        (target.0, path_id.or(target.1))
+    } else {
+        (path_id.or(target.0), target.1)
+    }
+}
+
+// every user route declares the permission it needs. site scoped grants only pass for the devices
+// and groups they cover, the resolved `auth::Scope` is injected so handlers can filter listings.
+// requests that change something are written to the audit log, allowed or not
+pub async fn require_permission(State(permission): State<Permission>, mut req: Request, next: Next) -> Response {
+    let Some(principal) = req.extensions().get::<Principal>().cloned() else {
+        return (StatusCode::UNAUTHORIZED, "login required").into_response();
+    };
+
+    let privileged = !matches!(*req.method(), Method::GET | Method::HEAD);
+    let action = format!(
+        "{} {}",
+        req.method(),
+        req.extensions().get::<MatchedPath>().map(|path| path.as_str()).unwrap_or(req.uri().path())
+    );
+    let path = req.uri().path().to_string();
+
+    let scope = principal.scope(permission);
+    let allowed = match &scope {
+        Scope::None => false,
+        Scope::All => true,
+        Scope::Groups { .. } if permission.global_only() => false,
+        Scope::Groups { .. } => {
+            let (device_id, group_id) = scope_target(&mut req).await;
+            device_id.is_none_or(|id| scope.allows_device(id)) && group_id.is_none_or(|id| scope.allows_group(id))
+        }
+    };
+
+    let response = if allowed {
+        req.extensions_mut().insert(scope);
+        next.run(req).await
+    } else {
+        (StatusCode::FORBIDDEN, format!("missing permission {}", permission.as_str())).into_response()
+    };
+
+    if privileged {
+        audit::record(AuditEntry {
+            user_id: Some(principal.user_id),
+            username: Some(principal.username),
+            action,
+            path: Some(path),
+            permission: Some(permission.as_str()),
+            status: response.status().as_u16() as i32,
+        }).await;
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::Body;
+    use axum::routing::{get, put};
+    use axum::Router;
+    use tower::ServiceExt;
+
+    // runs `scope_target` behind the router, which is what fills in the matched path and path parameters
+    async fn target(method: Method, uri: &str) -> (Option<i32>, Option<i32>) {
+        let app = Router::new()
+            .route("/api/groups/{id}/devices", put(|| async {}))
+            .route("/devices/{id}", get(|| async {}))
+            .route("/api/history", get(|| async {}))
+            .route_layer(axum::middleware::from_fn(|mut req: Request, _next: Next| async move {
+                let mut response = ().into_response();
+                response.extensions_mut().insert(scope_target(&mut req).await);
+                response
+            }));
+        let request = Request::builder().method(method).uri(uri).body(Body::empty()).unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        *response.extensions().get().expect("scope_target ran")
+    }
+
+    #[tokio::test]
+    async fn group_route_id_is_a_group() {
+        assert_eq!(target(Method::PUT, "/api/groups/2/devices").await, (None, Some(2)));
+        // the path wins over a query naming another group
+        assert_eq!(target(Method::PUT, "/api/groups/2/devices?group_id=9&device_id=4").await, (Some(4), Some(2)));
+    }
+
+    #[tokio::test]
+    async fn device_route_id_is_a_device() {
+        assert_eq!(target(Method::GET, "/devices/5").await, (Some(5), None));
+        assert_eq!(target(Method::GET, "/devices/5?device_id=9&group_id=2").await, (Some(5), Some(2)));
+    }
+
+    #[tokio::test]
+    async fn other_routes_use_the_query() {
+        assert_eq!(target(Method::GET, "/api/history?range=day").await, (None, None));
+        assert_eq!(target(Method::GET, "/api/history?device_id=5").await, (Some(5), None));
+        assert_eq!(target(Method::GET, "/api/history?group_id=2").await, (None, Some(2)));
+    }
+
+    #[test]
+    fn logged_uri_hides_access_tokens() {
+        let uri: Uri = "/ws?format=json&access_token=dbm_secret&v=2".parse().unwrap();
+        assert_eq!(loggable_uri(&uri), "/ws?format=json&access_token=redacted&v=2");
+        let uri: Uri = "/api/history?range=day&device_id=4".parse().unwrap();
+        assert_eq!(loggable_uri(&uri), "/api/history?range=day&device_id=4");
+        assert_eq!(loggable_uri(&"/healthz".parse().unwrap()), "/healthz");
+    }
+}
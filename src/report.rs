@@ -0,0 +1,549 @@
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::path::PathBuf;
+use crate::config;
+use crate::database::DbPool;
+use crate::schedule;
+
+type ReportError = Box<dyn std::error::Error + Send + Sync>;
+
+pub const REPORT_FILES: &[&str] = &["report.html", "summary.csv", "daily.csv", "hourly.csv", "exceedances.csv", "events.csv"];
+pub const FREQUENCIES: &[&str] = &["daily", "weekly", "monthly"];
+
+const SCHEDULER_INTERVAL_SECONDS: u64 = 60;
+const MAX_LISTED_EVENTS: i64 = 200;
+const CHART_COLORS: &[&str] = &["#7c6cf0", "#c05ee0", "#4f46c8", "#d48ae8", "#5b3fd9", "#e0607e"];
+
+#[derive(Clone, Debug)]
+pub struct ReportRequest {
+    pub group_id: i32,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub timezone: Tz,
+    pub schedule_id: Option<i32>,
+}
+
+struct DeviceSummary {
+    device_id: i32,
+    name: String,
+    readings: i64,
+    leq: Option<f64>,
+    lmax: Option<f64>,
+    lmin: Option<f64>,
+    l10: Option<f64>,
+    l50: Option<f64>,
+    l90: Option<f64>,
+    uptime: f64,
+}
+
+struct ReportEvent {
+    device_id: i32,
+    started_at: DateTime<Utc>,
+    duration_ms: i32,
+    peak_db: f64,
+    leq_db: f64,
+    sel_db: f64,
+}
+
+struct ReportData {
+    group_name: String,
+    devices: Vec<DeviceSummary>,
+    daily: Vec<(i32, NaiveDate, f64, f64, i64)>,
+    hourly: Vec<(i32, NaiveDateTime, f64)>,
+    exceedances: Vec<(String, schedule::PeriodCompliance)>,
+    events: Vec<ReportEvent>,
+}
+
+// generated bundles are written to reports.dir/<report id>/
+pub fn report_dir(report_id: i32) -> PathBuf {
+    config::get().reports.dir.join(report_id.to_string())
+}
+
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+fn escape_csv(text: &str) -> String {
+    if text.contains([',', '"', '\n']) {
+        format!("\"{}\"", text.replace('"', "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn level(value: Option<f64>) -> String {
+    value.map(|v| format!("{:.1}", v)).unwrap_or_default()
+}
+
+// records the report, renders it to disk and marks it ready or failed
+pub async fn generate(pool: &DbPool, request: ReportRequest) -> Result<i32, ReportError> {
+    let report_id: i32 = {
+        let client = pool.get().await?;
+        client
+            .query_one(
+                "INSERT INTO compliance_reports (fk_group_id, fk_schedule_id, range_start, range_end, timezone)
+                 VALUES ($1, $2, $3, $4, $5) RETURNING id",
+                &[&request.group_id, &request.schedule_id, &request.from, &request.to, &request.timezone.name()],
+            )
+            .await?
+            .get("id")
+    };
+
+    let result = build(pool, report_id, &request).await;
+
+    let client = pool.get().await?;
+    match &result {
+        Ok(()) => {
+            client
+                .execute("UPDATE compliance_reports SET status = 'ready' WHERE id = $1", &[&report_id])
+                .await?;
+        }
+        Err(e) => {
+            tracing::error!(report_id, error = %e, "report generation failed");
+            client
+                .execute(
+                    "UPDATE compliance_reports SET status = 'failed', error = $2 WHERE id = $1",
+                    &[&report_id, &e.to_string()],
+                )
+                .await?;
+        }
+    }
+
+    result.map(|_| report_id)
+}
+
+async fn build(pool: &DbPool, report_id: i32, request: &ReportRequest) -> Result<(), ReportError> {
+    let data = collect(pool, request).await?;
+    let dir = report_dir(report_id);
+    tokio::fs::create_dir_all(&dir).await?;
+
+    tokio::fs::write(dir.join("report.html"), render_html(report_id, request, &data)?).await?;
+    tokio::fs::write(dir.join("summary.csv"), summary_csv(&data)).await?;
+    tokio::fs::write(dir.join("daily.csv"), daily_csv(&data)).await?;
+    tokio::fs::write(dir.join("hourly.csv"), hourly_csv(&data)).await?;
+    tokio::fs::write(dir.join("exceedances.csv"), exceedances_csv(&data)).await?;
+    tokio::fs::write(dir.join("events.csv"), events_csv(&data)).await?;
+
+    Ok(())
+}
+
+async fn collect(pool: &DbPool, request: &ReportRequest) -> Result<ReportData, ReportError> {
+    let client = pool.get().await?;
+    let tz_name = request.timezone.name();
+
+    let group = client
+        .query_opt("SELECT name FROM device_groups WHERE id = $1", &[&request.group_id])
+        .await?
+        .ok_or("group not found")?;
+
+    let device_rows = client
+        .query(
+            "SELECT d.id, d.name FROM devices d
+             JOIN device_group_members m ON m.fk_device_id = d.id
+             WHERE m.fk_group_id = $1 AND d.deleted_at IS NULL
+             ORDER BY d.id",
+            &[&request.group_id],
+        )
+        .await?;
+    let device_ids: Vec<i32> = device_rows.iter().map(|row| row.get("id")).collect();
+
+    // uptime counts minutes with at least one reading, up to now for ranges that have not ended yet
+    let covered_minutes = (request.to.min(Utc::now()) - request.from).num_minutes().max(1) as f64;
+
+    let summary_rows = client
+        .query(
+            "SELECT fk_device_id, COUNT(*) AS readings,
+                    10 * LOG(AVG(POWER(10, decibels / 10))) AS leq,
+                    MAX(decibels) AS lmax, MIN(decibels) AS lmin,
+                    percentile_cont(ARRAY[0.9, 0.5, 0.1]) WITHIN GROUP (ORDER BY decibels) AS percentiles,
+                    COUNT(DISTINCT date_trunc('minute', created_at)) AS minutes
+             FROM decibel_logs
+             WHERE fk_device_id = ANY($1) AND created_at >= $2 AND created_at < $3
+             GROUP BY fk_device_id",
+            &[&device_ids, &request.from, &request.to],
+        )
+        .await?;
+
+    let devices = device_rows
+        .iter()
+        .map(|device| {
+            let device_id: i32 = device.get("id");
+            let name: Option<String> = device.get("name");
+            let name = name.unwrap_or_else(|| format!("Device {}", device_id));
+            match summary_rows.iter().find(|row| row.get::<_, i32>("fk_device_id") == device_id) {
+                Some(row) => {
+                    let percentiles: Vec<f64> = row.get("percentiles");
+                    DeviceSummary {
+                        device_id,
+                        name,
+                        readings: row.get("readings"),
+                        leq: Some(row.get("leq")),
+                        lmax: Some(row.get("lmax")),
+                        lmin: Some(row.get("lmin")),
+                        l10: percentiles.first().copied(),
+                        l50: percentiles.get(1).copied(),
+                        l90: percentiles.get(2).copied(),
+                        uptime: (row.get::<_, i64>("minutes") as f64 / covered_minutes).min(1.0),
+                    }
+                }
+                None => DeviceSummary {
+                    device_id,
+                    name,
+                    readings: 0,
+                    leq: None,
+                    lmax: None,
+                    lmin: None,
+                    l10: None,
+                    l50: None,
+                    l90: None,
+                    uptime: 0.0,
+                },
+            }
+        })
+        .collect();
+
+    let daily = client
+        .query(
+            "SELECT fk_device_id, (created_at AT TIME ZONE $4)::date AS day,
+                    10 * LOG(AVG(POWER(10, decibels / 10))) AS leq, MAX(decibels) AS lmax, COUNT(*) AS readings
+             FROM decibel_logs
+             WHERE fk_device_id = ANY($1) AND created_at >= $2 AND created_at < $3
+             GROUP BY fk_device_id, day
+             ORDER BY fk_device_id, day",
+            &[&device_ids, &request.from, &request.to, &tz_name],
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get("fk_device_id"), row.get("day"), row.get("leq"), row.get("lmax"), row.get("readings")))
+        .collect();
+
+    let hourly = client
+        .query(
+            "SELECT fk_device_id, date_trunc('hour', created_at AT TIME ZONE $4) AS hour,
+                    10 * LOG(AVG(POWER(10, decibels / 10))) AS leq
+             FROM decibel_logs
+             WHERE fk_device_id = ANY($1) AND created_at >= $2 AND created_at < $3
+             GROUP BY fk_device_id, hour
+             ORDER BY fk_device_id, hour",
+            &[&device_ids, &request.from, &request.to, &tz_name],
+        )
+        .await?
+        .iter()
+        .map(|row| (row.get("fk_device_id"), row.get("hour"), row.get("leq")))
+        .collect();
+
+    // devices are grouped by the schedule that applies to them
+    let mut by_schedule: BTreeMap<i32, (String, Vec<i32>)> = BTreeMap::new();
+    for device_id in &device_ids {
+        if let Some(device_schedule) = schedule::schedule_for_device(*device_id) {
+            by_schedule
+                .entry(device_schedule.id)
+                .or_insert_with(|| (device_schedule.name.clone(), Vec::new()))
+                .1
+                .push(*device_id);
+        }
+    }
+    let mut exceedances = Vec::new();
+    for (schedule_id, (name, ids)) in by_schedule {
+        for period in schedule::compliance(&client, schedule_id, &ids, request.from, request.to).await? {
+            exceedances.push((name.clone(), period));
+        }
+    }
+
+    let events = client
+        .query(
+            "SELECT fk_device_id, started_at, duration_ms, peak_db, leq_db, sel_db
+             FROM noise_events
+             WHERE fk_device_id = ANY($1) AND started_at >= $2 AND started_at < $3
+             ORDER BY peak_db DESC
+             LIMIT $4",
+            &[&device_ids, &request.from, &request.to, &MAX_LISTED_EVENTS],
+        )
+        .await?
+        .iter()
+        .map(|row| ReportEvent {
+            device_id: row.get("fk_device_id"),
+            started_at: row.get("started_at"),
+            duration_ms: row.get("duration_ms"),
+            peak_db: row.get("peak_db"),
+            leq_db: row.get("leq_db"),
+            sel_db: row.get("sel_db"),
+        })
+        .collect();
+
+    Ok(ReportData {
+        group_name: group.get("name"),
+        devices,
+        daily,
+        hourly,
+        exceedances,
+        events,
+    })
+}
+
+fn summary_csv(data: &ReportData) -> String {
+    let mut csv = String::from("device_id,name,readings,leq,lmax,lmin,l10,l50,l90,uptime_percent\n");
+    for d in &data.devices {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{},{},{},{},{},{},{:.1}",
+            d.device_id, escape_csv(&d.name), d.readings, level(d.leq), level(d.lmax), level(d.lmin),
+            level(d.l10), level(d.l50), level(d.l90), d.uptime * 100.0
+        );
+    }
+    csv
+}
+
+fn daily_csv(data: &ReportData) -> String {
+    let mut csv = String::from("device_id,date,leq,lmax,readings\n");
+    for (device_id, day, leq, lmax, readings) in &data.daily {
+        let _ = writeln!(csv, "{},{},{:.1},{:.1},{}", device_id, day, leq, lmax, readings);
+    }
+    csv
+}
+
+fn hourly_csv(data: &ReportData) -> String {
+    let mut csv = String::from("device_id,hour,leq\n");
+    for (device_id, hour, leq) in &data.hourly {
+        let _ = writeln!(csv, "{},{},{:.1}", device_id, hour.format("%Y-%m-%d %H:%M"), leq);
+    }
+    csv
+}
+
+fn exceedances_csv(data: &ReportData) -> String {
+    let mut csv = String::from("schedule,device_id,period,limit_db,max_db,seconds_total,seconds_over,percent_over,events\n");
+    for (name, c) in &data.exceedances {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{:.1},{:.1},{:.0},{:.0},{:.2},{}",
+            escape_csv(name), c.device_id, escape_csv(&c.period), c.limit_db, c.max_db,
+            c.seconds_total, c.seconds_over, c.fraction_over() * 100.0, c.events
+        );
+    }
+    csv
+}
+
+fn events_csv(data: &ReportData) -> String {
+    let mut csv = String::from("device_id,started_at,duration_ms,peak_db,leq_db,sel_db\n");
+    for e in &data.events {
+        let _ = writeln!(
+            csv,
+            "{},{},{},{:.1},{:.1},{:.1}",
+            e.device_id, e.started_at.to_rfc3339(), e.duration_ms, e.peak_db, e.leq_db, e.sel_db
+        );
+    }
+    csv
+}
+
+// hourly leq per device as an inline svg line chart, 30-110 db on the y axis
+fn hourly_svg(data: &ReportData, request: &ReportRequest) -> String {
+    const WIDTH: f64 = 900.0;
+    const HEIGHT: f64 = 280.0;
+    const LEFT: f64 = 44.0;
+    const BOTTOM: f64 = 28.0;
+    const TOP: f64 = 12.0;
+    const MIN_DB: f64 = 30.0;
+    const MAX_DB: f64 = 110.0;
+
+    let start = request.from.with_timezone(&request.timezone).naive_local();
+    let hours = ((request.to - request.from).num_hours().max(1)) as f64;
+    let x = |hour: &NaiveDateTime| LEFT + (*hour - start).num_minutes() as f64 / 60.0 / hours * (WIDTH - LEFT - 8.0);
+    let y = |db: f64| TOP + (MAX_DB - db.clamp(MIN_DB, MAX_DB)) / (MAX_DB - MIN_DB) * (HEIGHT - TOP - BOTTOM);
+
+    let mut svg = format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {w} {h}" width="100%" role="img" aria-label="Hourly Leq">"#,
+        w = WIDTH, h = HEIGHT
+    );
+
+    let mut tick = MIN_DB;
+    while tick <= MAX_DB {
+        let _ = write!(
+            svg,
+            r##"<line x1="{l}" x2="{r}" y1="{y:.1}" y2="{y:.1}" stroke="#e3e3ee"/><text x="{tx}" y="{ty:.1}" font-size="10" text-anchor="end" fill="#667">{t}</text>"##,
+            l = LEFT, r = WIDTH - 8.0, y = y(tick), tx = LEFT - 6.0, ty = y(tick) + 3.0, t = tick
+        );
+        tick += 10.0;
+    }
+
+    // label each local midnight when the range is short enough to stay readable
+    let days = (hours / 24.0).ceil() as u64;
+    let step = days.div_ceil(14).max(1);
+    let mut day = start.date();
+    let mut index = 0;
+    while day.and_hms_opt(0, 0, 0).map(|d| (d - start).num_hours() as f64 <= hours).unwrap_or(false) {
+        let midnight = day.and_hms_opt(0, 0, 0).unwrap();
+        if midnight >= start && index % step == 0 {
+            let _ = write!(
+                svg,
+                r##"<text x="{x:.1}" y="{y}" font-size="10" text-anchor="middle" fill="#667">{label}</text>"##,
+                x = x(&midnight), y = HEIGHT - 8.0, label = midnight.format("%m-%d")
+            );
+        }
+        index += 1;
+        day = day + Days::new(1);
+    }
+
+    for (i, device) in data.devices.iter().enumerate() {
+        let points: Vec<String> = data
+            .hourly
+            .iter()
+            .filter(|(device_id, _, _)| *device_id == device.device_id)
+            .map(|(_, hour, leq)| format!("{:.1},{:.1}", x(hour), y(*leq)))
+            .collect();
+        if points.is_empty() {
+            continue;
+        }
+        let _ = write!(
+            svg,
+            r#"<polyline fill="none" stroke="{}" stroke-width="1.5" points="{}"><title>{}</title></polyline>"#,
+            CHART_COLORS[i % CHART_COLORS.len()], points.join(" "), escape_html(&device.name)
+        );
+    }
+
+    svg.push_str("</svg>");
+    svg
+}
+
+#[derive(askama::Template)]
+#[template(path = "report.html")]
+struct ReportTemplate<'a> {
+    id: i32,
+    request: &'a ReportRequest,
+    data: &'a ReportData,
+    generated: DateTime<Utc>,
+    chart: String,
+}
+
+impl ReportTemplate<'_> {
+    fn local(&self, t: &DateTime<Utc>) -> String {
+        t.with_timezone(&self.request.timezone).format("%Y-%m-%d %H:%M").to_string()
+    }
+
+    fn level(&self, value: &Option<f64>) -> String {
+        level(*value)
+    }
+
+    fn chart_color(&self, i: &usize) -> &'static str {
+        CHART_COLORS[i % CHART_COLORS.len()]
+    }
+}
+
+fn render_html(report_id: i32, request: &ReportRequest, data: &ReportData) -> Result<String, askama::Error> {
+    askama::Template::render(&ReportTemplate {
+        id: report_id,
+        request,
+        data,
+        generated: Utc::now(),
+        chart: hourly_svg(data, request),
+    })
+}
+
+pub fn local_midnight(tz: Tz, date: NaiveDate) -> DateTime<Utc> {
+    local_datetime(tz, date, NaiveTime::MIN)
+}
+
+// the first instant of a local time, a time skipped by a dst change is read as utc
+pub fn local_datetime(tz: Tz, date: NaiveDate, time: NaiveTime) -> DateTime<Utc> {
+    let naive = date.and_time(time);
+    tz.from_local_datetime(&naive)
+        .earliest()
+        .unwrap_or_else(|| tz.from_utc_datetime(&naive))
+        .with_timezone(&Utc)
+}
+
+// first local period boundary strictly after `after`: midnight, monday or the first of the month
+pub fn next_run_after(frequency: &str, tz: Tz, after: DateTime<Utc>) -> DateTime<Utc> {
+    let today = after.with_timezone(&tz).date_naive();
+    let mut candidate = match frequency {
+        "weekly" => today - Days::new(today.weekday().num_days_from_monday() as u64),
+        "monthly" => today.with_day(1).unwrap(),
+        _ => today,
+    };
+
+    loop {
+        let boundary = local_midnight(tz, candidate);
+        if boundary > after {
+            return boundary;
+        }
+        candidate = match frequency {
+            "weekly" => candidate + Days::new(7),
+            "monthly" => candidate + Months::new(1),
+            _ => candidate + Days::new(1),
+        };
+    }
+}
+
+// start of the period that ends at the run time
+pub fn period_start(frequency: &str, tz: Tz, end: DateTime<Utc>) -> DateTime<Utc> {
+    let end_date = end.with_timezone(&tz).date_naive();
+    let start_date = match frequency {
+        "weekly" => end_date - Days::new(7),
+        "monthly" => end_date - Months::new(1),
+        _ => end_date - Days::new(1),
+    };
+    local_midnight(tz, start_date)
+}
+
+async fn run_due_schedules(pool: &DbPool) -> Result<(), ReportError> {
+    let due = {
+        let client = pool.get().await?;
+        client
+            .query(
+                "SELECT id, fk_group_id, frequency, timezone, next_run_at FROM report_schedules WHERE next_run_at <= NOW()",
+                &[],
+            )
+            .await?
+    };
+
+    for row in due {
+        let schedule_id: i32 = row.get("id");
+        let frequency: String = row.get("frequency");
+        let timezone: String = row.get("timezone");
+        let run_at: DateTime<Utc> = row.get("next_run_at");
+        let tz = schedule::parse_timezone(&timezone).unwrap_or(Tz::UTC);
+
+        let request = ReportRequest {
+            group_id: row.get("fk_group_id"),
+            from: period_start(&frequency, tz, run_at),
+            to: run_at,
+            timezone: tz,
+            schedule_id: Some(schedule_id),
+        };
+
+        // advance first so a failing report is not retried every minute
+        let next_run = next_run_after(&frequency, tz, Utc::now().max(run_at));
+        pool.get()
+            .await?
+            .execute("UPDATE report_schedules SET next_run_at = $2 WHERE id = $1", &[&schedule_id, &next_run])
+            .await?;
+
+        match generate(pool, request).await {
+            Ok(report_id) => tracing::info!(report_id, schedule_id, "scheduled report generated"),
+            Err(e) => tracing::error!(schedule_id, error = %e, "scheduled report failed"),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn start_scheduler(pool: DbPool) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(SCHEDULER_INTERVAL_SECONDS));
+        loop {
+            interval.tick().await;
+            if let Err(e) = run_due_schedules(&pool).await {
+                tracing::error!(error = %e, "report scheduler error");
+            }
+        }
+    });
+}
+
+pub fn is_report_file(name: &str) -> bool {
+    REPORT_FILES.contains(&name)
+}
@@ -1,2 +1,76 @@
 pub mod pages;
-pub mod api; 
\ No newline at end of file
+pub mod api;
+pub mod devices;
+pub mod groups;
+pub mod schedules;
+pub mod reports;
+pub mod events;
+pub mod history;
+pub mod users;
+pub mod alerts;
+pub mod health;
+
+use axum::{http::StatusCode, response::Html};
+use crate::auth::Scope;
+
+// logs the underlying error and hides it from the client
+pub fn internal_error(e: impl std::fmt::Display) -> (StatusCode, String) {
+    tracing::error!(error = %e, "database query error");
+    (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string())
+}
+
+// pages and fragments are askama templates checked at compile time, a render error is a 500
+pub fn render(template: &impl askama::Template) -> Result<Html<String>, (StatusCode, String)> {
+    template.render().map(Html).map_err(|e| {
+        tracing::error!(error = %e, "template render error");
+        (StatusCode::INTERNAL_SERVER_ERROR, "template error".to_string())
+    })
+}
+
+// comma separated ids of a query parameter, `?devices=1,2`
+pub fn parse_ids(ids: Option<&str>) -> Result<Vec<i32>, String> {
+    ids.unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|id| !id.is_empty())
+        .map(|id| id.parse().map_err(|_| format!("invalid id {}", id)))
+        .collect()
+}
+
+// explicit device, members of a group, or none for every device the scope allows
+pub async fn resolve_devices(
+    client: &tokio_postgres::Client,
+    device_id: Option<i32>,
+    group_id: Option<i32>,
+    scope: &Scope,
+) -> Result<Option<Vec<i32>>, (StatusCode, String)> {
+    match (device_id, group_id) {
+        (Some(device_id), _) => Ok(Some(vec![device_id])),
+        (None, Some(group_id)) => {
+            let rows = client
+                .query("SELECT fk_device_id FROM device_group_members WHERE fk_group_id = $1", &[&group_id])
+                .await
+                .map_err(internal_error)?;
+            Ok(Some(rows.iter().map(|row| row.get("fk_device_id")).collect()))
+        }
+        (None, None) => Ok(scope.device_filter()),
+    }
+}
+
+// like `resolve_devices` with a `devices` list taking precedence, every listed device must be in scope
+pub async fn resolve_device_list(
+    client: &tokio_postgres::Client,
+    devices: Option<&str>,
+    device_id: Option<i32>,
+    group_id: Option<i32>,
+    scope: &Scope,
+) -> Result<Option<Vec<i32>>, (StatusCode, String)> {
+    let devices = parse_ids(devices).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    if devices.is_empty() {
+        return resolve_devices(client, device_id, group_id, scope).await;
+    }
+    if let Some(device_id) = devices.iter().find(|device_id| !scope.allows_device(**device_id)) {
+        return Err((StatusCode::FORBIDDEN, format!("no access to device {}", device_id)));
+    }
+    Ok(Some(devices))
+}
@@ -0,0 +1,385 @@
+use askama::Template;
+use axum::{
+    extract::{Json, Path, Query, State},
+    Extension,
+    http::StatusCode,
+    response::{Html, Json as JsonResponse},
+};
+use chrono::{DateTime, Duration, Utc};
+use crate::alerts;
+use crate::auth::{Permission, Principal, Scope};
+use crate::database::DbPool;
+use crate::routes::{internal_error, render, resolve_devices};
+use crate::websocket;
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+const PANEL_RECENT: i64 = 20;
+const MAX_SILENCE_MINUTES: i64 = 7 * 24 * 60;
+
+#[derive(Deserialize)]
+pub struct AlertQuery {
+    // only alerts that are still open
+    #[serde(default)]
+    pub open: bool,
+    pub device_id: Option<i32>,
+    pub group_id: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+#[derive(Deserialize)]
+pub struct AcknowledgeInput {
+    pub comment: Option<String>,
+}
+
+// a device, a schedule (rule) for every device, or a schedule on one device
+#[derive(Deserialize)]
+pub struct SilenceInput {
+    pub device_id: Option<i32>,
+    pub schedule_id: Option<i32>,
+    pub minutes: i64,
+    pub reason: Option<String>,
+}
+
+pub struct AlertRow {
+    pub id: i32,
+    pub device_id: i32,
+    pub device_name: Option<String>,
+    pub schedule_id: Option<i32>,
+    pub schedule: String,
+    pub period: String,
+    pub limit_db: f64,
+    pub peak_db: f64,
+    pub occurrences: i32,
+    pub started_at: DateTime<Utc>,
+    pub last_seen_at: DateTime<Utc>,
+    pub open: bool,
+    pub silence_id: Option<i32>,
+    pub acknowledged_at: Option<DateTime<Utc>>,
+    pub acknowledged_by: Option<String>,
+    pub comment: Option<String>,
+}
+
+impl AlertRow {
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "id": self.id,
+            "device_id": self.device_id,
+            "device_name": self.device_name,
+            "schedule_id": self.schedule_id,
+            "schedule": self.schedule,
+            "period": self.period,
+            "limit_db": self.limit_db,
+            "peak_db": self.peak_db,
+            "occurrences": self.occurrences,
+            "started_at": self.started_at.to_rfc3339(),
+            "last_seen_at": self.last_seen_at.to_rfc3339(),
+            "open": self.open,
+            "silence_id": self.silence_id,
+            "acknowledged_at": self.acknowledged_at.map(|at| at.to_rfc3339()),
+            "acknowledged_by": self.acknowledged_by,
+            "comment": self.comment,
+        })
+    }
+}
+
+// newest activity first, `devices` none for every device
+pub async fn query_alerts(
+    client: &tokio_postgres::Client,
+    devices: Option<Vec<i32>>,
+    open_only: bool,
+    limit: i64,
+) -> Result<Vec<AlertRow>, (StatusCode, String)> {
+    let open_since = Utc::now() - Duration::minutes(alerts::QUIET_MINUTES);
+    let rows = client
+        .query(
+            "SELECT a.id, a.fk_device_id, d.name AS device_name, a.fk_schedule_id, a.schedule, a.period, a.limit_db,
+                    a.peak_db, a.occurrences, a.started_at, a.last_seen_at, a.last_seen_at > $2 AS open,
+                    a.fk_silence_id, a.acknowledged_at, u.username AS acknowledged_by, a.acknowledge_comment
+             FROM alerts a
+             JOIN devices d ON d.id = a.fk_device_id
+             LEFT JOIN users u ON u.id = a.fk_acknowledged_by
+             WHERE ($1::int[] IS NULL OR a.fk_device_id = ANY($1)) AND (NOT $3 OR a.last_seen_at > $2)
+             ORDER BY a.last_seen_at DESC
+             LIMIT $4",
+            &[&devices, &open_since, &open_only, &limit],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    Ok(rows
+        .iter()
+        .map(|row| AlertRow {
+            id: row.get("id"),
+            device_id: row.get("fk_device_id"),
+            device_name: row.get("device_name"),
+            schedule_id: row.get("fk_schedule_id"),
+            schedule: row.get("schedule"),
+            period: row.get("period"),
+            limit_db: row.get("limit_db"),
+            peak_db: row.get("peak_db"),
+            occurrences: row.get("occurrences"),
+            started_at: row.get("started_at"),
+            last_seen_at: row.get("last_seen_at"),
+            open: row.get("open"),
+            silence_id: row.get("fk_silence_id"),
+            acknowledged_at: row.get("acknowledged_at"),
+            acknowledged_by: row.get("acknowledged_by"),
+            comment: row.get("acknowledge_comment"),
+        })
+        .collect())
+}
+
+pub async fn list_alerts(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<AlertQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let device_ids = resolve_devices(&client, query.device_id, query.group_id, &scope).await?;
+    let alerts: Vec<serde_json::Value> = query_alerts(&client, device_ids, query.open, limit)
+        .await?
+        .iter()
+        .map(AlertRow::to_json)
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "alerts": alerts,
+        "count": alerts.len()
+    })))
+}
+
+pub async fn acknowledge_alert(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+    Extension(scope): Extension<Scope>,
+    Path(alert_id): Path<i32>,
+    Json(input): Json<AcknowledgeInput>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let Some(alert) = client
+        .query_opt("SELECT fk_device_id, acknowledged_at FROM alerts WHERE id = $1", &[&alert_id])
+        .await
+        .map_err(internal_error)?
+    else {
+        return Err((StatusCode::NOT_FOUND, format!("alert {} not found", alert_id)));
+    };
+    let device_id: i32 = alert.get("fk_device_id");
+    if !scope.allows_device(device_id) {
+        return Err((StatusCode::FORBIDDEN, format!("no access to device {}", device_id)));
+    }
+    if alert.get::<_, Option<DateTime<Utc>>>("acknowledged_at").is_some() {
+        return Err((StatusCode::CONFLICT, format!("alert {} is already acknowledged", alert_id)));
+    }
+
+    let comment = input.comment.map(|comment| comment.trim().to_string()).filter(|comment| !comment.is_empty());
+    client
+        .execute(
+            "UPDATE alerts SET acknowledged_at = NOW(), fk_acknowledged_by = $2, acknowledge_comment = $3
+             WHERE id = $1 AND acknowledged_at IS NULL",
+            &[&alert_id, &principal.user_id, &comment],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    websocket::broadcast_alerts_changed(Some(device_id));
+    Ok(JsonResponse(json!({ "status": "success", "alert_id": alert_id })))
+}
+
+pub async fn list_silences(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+    let silences: Vec<serde_json::Value> = active_silences(&client, &scope)
+        .await?
+        .iter()
+        .map(|silence| json!({
+            "id": silence.id,
+            "device_id": silence.device_id,
+            "schedule_id": silence.schedule_id,
+            "schedule": silence.schedule,
+            "reason": silence.reason,
+            "starts_at": silence.starts_at.to_rfc3339(),
+            "ends_at": silence.ends_at.to_rfc3339(),
+            "created_by": silence.created_by,
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "silences": silences,
+        "count": silences.len()
+    })))
+}
+
+pub struct SilenceRow {
+    pub id: i32,
+    pub device_id: Option<i32>,
+    pub schedule_id: Option<i32>,
+    pub schedule: Option<String>,
+    pub reason: Option<String>,
+    pub starts_at: DateTime<Utc>,
+    pub ends_at: DateTime<Utc>,
+    pub created_by: Option<String>,
+}
+
+// silences that are not over or cancelled, schedule wide ones are shown to everyone
+async fn active_silences(client: &tokio_postgres::Client, scope: &Scope) -> Result<Vec<SilenceRow>, (StatusCode, String)> {
+    let rows = client
+        .query(
+            "SELECT s.id, s.fk_device_id, s.fk_schedule_id, n.name AS schedule, s.reason, s.starts_at, s.ends_at,
+                    u.username AS created_by
+             FROM alert_silences s
+             LEFT JOIN noise_schedules n ON n.id = s.fk_schedule_id
+             LEFT JOIN users u ON u.id = s.fk_created_by
+             WHERE s.cancelled_at IS NULL AND s.ends_at > NOW()
+               AND ($1::int[] IS NULL OR s.fk_device_id IS NULL OR s.fk_device_id = ANY($1))
+             ORDER BY s.ends_at",
+            &[&scope.device_filter()],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    Ok(rows
+        .iter()
+        .map(|row| SilenceRow {
+            id: row.get("id"),
+            device_id: row.get("fk_device_id"),
+            schedule_id: row.get("fk_schedule_id"),
+            schedule: row.get("schedule"),
+            reason: row.get("reason"),
+            starts_at: row.get("starts_at"),
+            ends_at: row.get("ends_at"),
+            created_by: row.get("created_by"),
+        })
+        .collect())
+}
+
+// silencing a schedule for every device reaches beyond any one site
+fn check_silence_target(scope: &Scope, device_id: Option<i32>) -> Result<(), (StatusCode, String)> {
+    match device_id {
+        Some(device_id) if !scope.allows_device(device_id) => {
+            Err((StatusCode::FORBIDDEN, format!("no access to device {}", device_id)))
+        }
+        None if !matches!(scope, Scope::All) => {
+            Err((StatusCode::FORBIDDEN, "silencing a schedule for every device needs a role without a site".to_string()))
+        }
+        _ => Ok(()),
+    }
+}
+
+pub async fn create_silence(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+    Extension(scope): Extension<Scope>,
+    Json(input): Json<SilenceInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    if input.device_id.is_none() && input.schedule_id.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "device_id, schedule_id or both are required".to_string()));
+    }
+    if !(1..=MAX_SILENCE_MINUTES).contains(&input.minutes) {
+        return Err((StatusCode::BAD_REQUEST, format!("minutes must be between 1 and {}", MAX_SILENCE_MINUTES)));
+    }
+    check_silence_target(&scope, input.device_id)?;
+
+    let ends_at = Utc::now() + Duration::minutes(input.minutes);
+    let reason = input.reason.map(|reason| reason.trim().to_string()).filter(|reason| !reason.is_empty());
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let row = client
+        .query_one(
+            "INSERT INTO alert_silences (fk_device_id, fk_schedule_id, reason, ends_at, fk_created_by)
+             VALUES ($1, $2, $3, $4, $5) RETURNING id",
+            &[&input.device_id, &input.schedule_id, &reason, &ends_at, &principal.user_id],
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("cannot create silence: {}", e)))?;
+
+    websocket::broadcast_alerts_changed(input.device_id);
+    Ok((StatusCode::CREATED, JsonResponse(json!({
+        "status": "success",
+        "silence_id": row.get::<_, i32>("id"),
+        "ends_at": ends_at.to_rfc3339(),
+    }))))
+}
+
+pub async fn cancel_silence(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+    Extension(scope): Extension<Scope>,
+    Path(silence_id): Path<i32>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let Some(silence) = client
+        .query_opt(
+            "SELECT fk_device_id FROM alert_silences WHERE id = $1 AND cancelled_at IS NULL AND ends_at > NOW()",
+            &[&silence_id],
+        )
+        .await
+        .map_err(internal_error)?
+    else {
+        return Err((StatusCode::NOT_FOUND, format!("silence {} not found", silence_id)));
+    };
+    let device_id: Option<i32> = silence.get("fk_device_id");
+    check_silence_target(&scope, device_id)?;
+
+    client
+        .execute(
+            "UPDATE alert_silences SET cancelled_at = NOW(), fk_cancelled_by = $2 WHERE id = $1",
+            &[&silence_id, &principal.user_id],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    websocket::broadcast_alerts_changed(device_id);
+    Ok(JsonResponse(json!({ "status": "success", "silence_id": silence_id })))
+}
+
+#[derive(Template)]
+#[template(path = "fragments/alerts.html")]
+struct AlertsPanelTemplate {
+    alerts: Vec<AlertRow>,
+    silences: Vec<SilenceRow>,
+    // the acknowledge_alerts scope of the user, actions are only offered where they would pass
+    act_scope: Scope,
+}
+
+impl AlertsPanelTemplate {
+    fn can_act(&self, device_id: &i32) -> bool {
+        self.act_scope.allows_device(*device_id)
+    }
+
+    fn can_silence_schedules(&self) -> bool {
+        matches!(self.act_scope, Scope::All)
+    }
+
+    fn can_cancel(&self, silence: &SilenceRow) -> bool {
+        check_silence_target(&self.act_scope, silence.device_id).is_ok()
+    }
+}
+
+// open alerts first, then the most recent closed ones, with the active silences
+pub async fn alerts_fragment(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+    Extension(scope): Extension<Scope>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+    let mut alerts = query_alerts(&client, scope.device_filter(), false, PANEL_RECENT).await?;
+    alerts.sort_by_key(|alert| !alert.open);
+    let silences = active_silences(&client, &scope).await?;
+
+    render(&AlertsPanelTemplate {
+        alerts,
+        silences,
+        act_scope: principal.scope(Permission::AcknowledgeAlerts),
+    })
+}
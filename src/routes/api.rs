@@ -1,12 +1,19 @@
+use askama::Template;
 use axum::{
     extract::{State, Json, Extension},
-    http::{StatusCode, header::{HeaderMap, HeaderName, HeaderValue}},
+    http::{StatusCode, header::{self, HeaderMap, HeaderName, HeaderValue}},
     response::Json as JsonResponse,
     response::{IntoResponse, Html},
 };
+use crate::auth::Scope;
+use crate::routes::render;
 use crate::database::DbPool;
 use crate::websocket;
 use crate::cache;
+use crate::schedule;
+use crate::dose;
+use crate::events;
+use crate::telemetry::{self, RequestId};
 use serde_json::json;
 use serde::Deserialize;
 use crate::token;
@@ -18,17 +25,18 @@ pub async fn db_status(State(pool): State<DbPool>) -> Result<JsonResponse<serde_
         Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
     };
     
-    match client.query("SELECT NOW() as current_time, version() as db_version", &[]).await {
+    match client.query("SELECT NOW() as current_time, version() as db_version, current_database() as database_name", &[]).await {
         Ok(rows) => {
             if let Some(row) = rows.first() {
                 let current_time: chrono::DateTime<chrono::Utc> = row.get("current_time");
                 let db_version: &str = row.get("db_version");
+                let database_name: &str = row.get("database_name");
                 
                 Ok(JsonResponse(json!({
                     "status": "connected",
                     "current_time": current_time.to_rfc3339(),
                     "database_version": db_version.split_whitespace().take(2).collect::<Vec<_>>().join(" "),
-                    "database_name": "dbmonitor"
+                    "database_name": database_name
                 })))
             } else {
                 Ok(JsonResponse(json!({
@@ -38,7 +46,7 @@ pub async fn db_status(State(pool): State<DbPool>) -> Result<JsonResponse<serde_
             }
         }
         Err(e) => {
-            eprintln!("database query error: {}", e);
+            tracing::error!(error = %e, "database query error");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
@@ -51,16 +59,20 @@ pub struct NewDecibelLog {
 
 pub async fn add_log(
     Extension(device_id): Extension<i32>,
+    request_id: Option<Extension<RequestId>>,
     State(_pool): State<DbPool>,
     Json(payload): Json<NewDecibelLog>,
 ) -> Result<JsonResponse<serde_json::Value>, StatusCode> {
     let timestamp = chrono::Utc::now();
     
     cache::update_device_reading(device_id, payload.decibels, timestamp).await;
+
+    dose::record_reading(device_id, payload.decibels, timestamp);
+    events::record_reading(device_id, payload.decibels, timestamp).await;
     
     websocket::broadcast_reading_update(payload.decibels, device_id).await;
     
-    cache::queue_insert(device_id, payload.decibels, timestamp).await;
+    cache::queue_insert(device_id, payload.decibels, timestamp, request_id.map(|Extension(id)| id)).await;
     
     Ok(JsonResponse(json!({
         "status": "success",
@@ -69,7 +81,10 @@ pub async fn add_log(
     })))
 }
 
-pub async fn get_logs(State(pool): State<DbPool>) -> Result<JsonResponse<serde_json::Value>, StatusCode> {
+pub async fn get_logs(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<JsonResponse<serde_json::Value>, StatusCode> {
     let client = match pool.get().await {
         Ok(conn) => conn,
         Err(_) => return Err(StatusCode::INTERNAL_SERVER_ERROR),
@@ -77,8 +92,10 @@ pub async fn get_logs(State(pool): State<DbPool>) -> Result<JsonResponse<serde_j
 
     match client
         .query(
-            "SELECT id, decibels, created_at, fk_device_id FROM decibel_logs ORDER BY created_at DESC LIMIT 10",
-            &[],
+            "SELECT id, decibels, created_at, fk_device_id FROM decibel_logs
+             WHERE ($1::int[] IS NULL OR fk_device_id = ANY($1))
+             ORDER BY created_at DESC LIMIT 10",
+            &[&scope.device_filter()],
         )
         .await
     {
@@ -102,12 +119,13 @@ pub async fn get_logs(State(pool): State<DbPool>) -> Result<JsonResponse<serde_j
             })))
         }
         Err(e) => {
-            eprintln!("database query error: {}", e);
+            tracing::error!(error = %e, "database query error");
             Err(StatusCode::INTERNAL_SERVER_ERROR)
         }
     }
 }
 
+// registers a device and returns its ingest token, `dbmonitor device create` does the same from the shell
 pub async fn auth(State(pool): State<DbPool>) -> Result<impl IntoResponse, StatusCode> {
     let client = match pool.get().await {
         Ok(conn) => conn,
@@ -115,12 +133,12 @@ pub async fn auth(State(pool): State<DbPool>) -> Result<impl IntoResponse, Statu
     };
 
     let row = match client
-        .query_one("INSERT INTO devices DEFAULT VALUES RETURNING id", &[])
+        .query_one("INSERT INTO devices (token_issued_at) VALUES (NOW()) RETURNING id", &[])
         .await
     {
         Ok(r) => r,
         Err(e) => {
-            eprintln!("database insert error: {}", e);
+            tracing::error!(error = %e, "database insert error");
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
@@ -130,7 +148,7 @@ pub async fn auth(State(pool): State<DbPool>) -> Result<impl IntoResponse, Statu
     let token_str = match token::generate_token(device_id) {
         Ok(t) => t,
         Err(e) => {
-            eprintln!("token generation error: {}", e);
+            tracing::error!(error = %e, "token generation error");
             return Err(StatusCode::INTERNAL_SERVER_ERROR);
         }
     };
@@ -144,66 +162,105 @@ pub async fn auth(State(pool): State<DbPool>) -> Result<impl IntoResponse, Statu
     Ok((headers, JsonResponse(json!({ "token": token_str, "device_id": device_id }))))
 }
 
-pub async fn active_devices_fragment(State(_pool): State<DbPool>) -> Result<Html<String>, StatusCode> {
-    let active_devices = cache::get_active_devices().await;
-    
-    if active_devices.is_empty() {
-        let html = r#"
-            <div class="text-center py-8 text-muted-foreground">
-                <div class="text-5xl mb-4 opacity-50">📱</div>
-                <div>No active devices (no readings in last minute)</div>
-            </div>
-        "#;
-        return Ok(Html(html.to_string()));
-    }
+struct ActiveDevice {
+    device_id: i32,
+    decibels: f64,
+    level_class: &'static str,
+    time_text: String,
+    limit_text: String,
+}
+
+#[derive(Template)]
+#[template(path = "fragments/active_devices.html")]
+struct ActiveDevicesTemplate {
+    devices: Vec<ActiveDevice>,
+}
+
+pub async fn active_devices_fragment(
+    State(_pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let mut active_devices = cache::get_active_devices().await;
+    active_devices.retain(|d| scope.allows_device(d.device_id));
+    active_devices.sort_by_key(|d| std::cmp::Reverse(d.timestamp));
 
-    let mut html = String::new();
     let now = Utc::now();
+    let devices = active_devices
+        .into_iter()
+        .map(|reading| {
+            let time_text = match (now - reading.timestamp).num_seconds() {
+                0 => "just now".to_string(),
+                1 => "1 second ago".to_string(),
+                n => format!("{} seconds ago", n),
+            };
 
-    let mut sorted_devices = active_devices;
-    sorted_devices.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
-    
-    for reading in sorted_devices {
-        let seconds_ago = (now - reading.timestamp).num_seconds();
-        let time_text = match seconds_ago {
-            0 => "just now".to_string(),
-            1 => "1 second ago".to_string(),
-            n => format!("{} seconds ago", n),
-        };
-
-        html.push_str(&format!(r#"
-            <div class="flex justify-between items-center p-4 border-b border-border hover:bg-card transition-all">
-                <div class="flex flex-col gap-1">
-                    <div class="font-bold text-card-foreground text-lg">Device {}</div>
-                    <div class="font-bold text-primary text-xl">{:.1} dB</div>
-                </div>
-                <div class="flex flex-col items-end gap-1">
-                    <div class="w-2 h-2 rounded-full bg-accent"></div>
-                    <div class="text-xs text-muted-foreground">{}</div>
-                </div>
-            </div>
-        "#, reading.device_id, reading.decibels, time_text));
-    }
+            // compare against the noise limit of the schedule period the reading falls in
+            let (level_class, limit_text) = match schedule::applicable_limit(reading.device_id, reading.timestamp) {
+                Some(limit) if reading.decibels > limit.limit_db => (
+                    "text-destructive",
+                    format!("over {} limit {:.1} dB", limit.period, limit.limit_db),
+                ),
+                Some(limit) => ("text-primary", format!("{} limit {:.1} dB", limit.period, limit.limit_db)),
+                None => ("text-primary", String::new()),
+            };
 
-    Ok(Html(html))
+            ActiveDevice { device_id: reading.device_id, decibels: reading.decibels, level_class, time_text, limit_text }
+        })
+        .collect();
+
+    render(&ActiveDevicesTemplate { devices })
 }
 
-pub async fn cache_status(State(_pool): State<DbPool>) -> Result<JsonResponse<serde_json::Value>, StatusCode> {
-    let active_devices = cache::get_active_devices().await;
+pub async fn cache_status(
+    State(_pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<JsonResponse<serde_json::Value>, StatusCode> {
+    let mut active_devices = cache::get_active_devices().await;
+    active_devices.retain(|d| scope.allows_device(d.device_id));
     let cache_size = cache::cache_size().await;
-    let (_, queue_active) = cache::is_queue_active().await;
+    let (queue_depth, queue_active) = cache::is_queue_active().await;
+    let ws_stats = websocket::stats();
     
     Ok(JsonResponse(json!({
         "cache_size": cache_size,
         "active_devices": active_devices.len(),
         "batch_processor": {
-            "active": queue_active
+            "active": queue_active,
+            "queue_depth": queue_depth
+        },
+        "websocket": {
+            "connected_clients": ws_stats.connected_clients,
+            "lagged_clients": ws_stats.lagged_clients,
+            "skipped_messages": ws_stats.skipped_messages,
+            "dropped_clients": ws_stats.dropped_clients
         },
         "devices": active_devices.iter().map(|d| json!({
             "device_id": d.device_id,
             "decibels": d.decibels,
             "timestamp": d.timestamp.to_rfc3339(),
-            "seconds_ago": (chrono::Utc::now() - d.timestamp).num_seconds()
+            "seconds_ago": (chrono::Utc::now() - d.timestamp).num_seconds(),
+            "limit": schedule::applicable_limit(d.device_id, d.timestamp).map(|limit| json!({
+                "schedule_id": limit.schedule_id,
+                "schedule": limit.schedule,
+                "period": limit.period,
+                "limit_db": limit.limit_db,
+                "exceeded": d.decibels > limit.limit_db
+            }))
         })).collect::<Vec<_>>()
     })))
-} 
\ No newline at end of file
+}
+
+// prometheus scrape target, per device series are not filtered so sites get 403
+pub async fn metrics(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    if !matches!(scope, Scope::All) {
+        return Err((StatusCode::FORBIDDEN, "metrics need a role without a site".to_string()));
+    }
+    let Some(body) = telemetry::render(&pool).await else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "metrics recorder not installed".to_string()));
+    };
+
+    Ok(([(header::CONTENT_TYPE, "text/plain; version=0.0.4; charset=utf-8")], body))
+}
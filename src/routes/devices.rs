@@ -0,0 +1,53 @@
+use axum::{
+    extract::{Path, State, Json},
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use chrono::{DateTime, Utc};
+use crate::database::DbPool;
+use crate::routes::internal_error;
+use serde::Deserialize;
+use serde_json::json;
+
+// replaces the descriptive fields of a device, missing fields are cleared
+#[derive(Deserialize)]
+pub struct DeviceInput {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub model: Option<String>,
+    pub serial_number: Option<String>,
+    pub calibration_offset_db: Option<f64>,
+    pub calibrated_at: Option<DateTime<Utc>>,
+}
+
+pub async fn update_device(
+    State(pool): State<DbPool>,
+    Path(device_id): Path<i32>,
+    Json(input): Json<DeviceInput>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let updated = client
+        .execute(
+            "UPDATE devices SET name = $2, location = $3, model = $4, serial_number = $5,
+                    calibration_offset_db = $6, calibrated_at = $7
+             WHERE id = $1 AND deleted_at IS NULL",
+            &[
+                &device_id,
+                &input.name,
+                &input.location,
+                &input.model,
+                &input.serial_number,
+                &input.calibration_offset_db,
+                &input.calibrated_at,
+            ],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    if updated == 0 {
+        return Err((StatusCode::NOT_FOUND, format!("device {} not found", device_id)));
+    }
+
+    Ok(JsonResponse(json!({ "status": "success", "device_id": device_id })))
+}
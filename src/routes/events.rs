@@ -0,0 +1,74 @@
+use axum::{
+    extract::{Query, State},
+    Extension,
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use chrono::{DateTime, Utc};
+use crate::auth::Scope;
+use crate::database::DbPool;
+use crate::routes::{internal_error, resolve_devices};
+use serde::Deserialize;
+use serde_json::json;
+
+const DEFAULT_LIMIT: i64 = 100;
+const MAX_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct EventQuery {
+    pub device_id: Option<i32>,
+    pub group_id: Option<i32>,
+    pub from: Option<DateTime<Utc>>,
+    pub to: Option<DateTime<Utc>>,
+    pub min_peak_db: Option<f64>,
+    pub min_duration_ms: Option<i32>,
+    pub limit: Option<i64>,
+}
+
+pub async fn list_events(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<EventQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT);
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let device_ids = resolve_devices(&client, query.device_id, query.group_id, &scope).await?;
+
+    let rows = client
+        .query(
+            "SELECT id, fk_device_id, started_at, ended_at, duration_ms, peak_db, leq_db, sel_db, background_db
+             FROM noise_events
+             WHERE ($1::int[] IS NULL OR fk_device_id = ANY($1))
+               AND ($2::timestamptz IS NULL OR started_at >= $2)
+               AND ($3::timestamptz IS NULL OR started_at < $3)
+               AND ($4::float8 IS NULL OR peak_db >= $4)
+               AND ($5::int IS NULL OR duration_ms >= $5)
+             ORDER BY started_at DESC
+             LIMIT $6",
+            &[&device_ids, &query.from, &query.to, &query.min_peak_db, &query.min_duration_ms, &limit],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let events: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| json!({
+            "id": row.get::<_, i32>("id"),
+            "device_id": row.get::<_, i32>("fk_device_id"),
+            "started_at": row.get::<_, DateTime<Utc>>("started_at").to_rfc3339(),
+            "ended_at": row.get::<_, DateTime<Utc>>("ended_at").to_rfc3339(),
+            "duration_ms": row.get::<_, i32>("duration_ms"),
+            "peak_db": row.get::<_, f64>("peak_db"),
+            "leq_db": row.get::<_, f64>("leq_db"),
+            "sel_db": row.get::<_, f64>("sel_db"),
+            "background_db": row.get::<_, f64>("background_db"),
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "events": events,
+        "count": events.len()
+    })))
+}
@@ -0,0 +1,160 @@
+use axum::{
+    extract::{Path, State, Json},
+    Extension,
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use crate::auth::Scope;
+use crate::database::DbPool;
+use crate::routes::internal_error;
+use crate::schedule;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct GroupInput {
+    pub name: String,
+    #[serde(default)]
+    pub device_ids: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct GroupMembersInput {
+    pub device_ids: Vec<i32>,
+}
+
+// replaces the members the caller can see, devices outside `scope` stay in the group untouched
+async fn write_members(
+    tx: &tokio_postgres::Transaction<'_>,
+    group_id: i32,
+    device_ids: &[i32],
+    scope: &Scope,
+) -> Result<(), (StatusCode, String)> {
+    tx.execute(
+        "DELETE FROM device_group_members WHERE fk_group_id = $1 AND ($2::int[] IS NULL OR fk_device_id = ANY($2))",
+        &[&group_id, &scope.device_filter()],
+    )
+    .await
+    .map_err(internal_error)?;
+
+    for device_id in device_ids {
+        tx.execute(
+            "INSERT INTO device_group_members (fk_group_id, fk_device_id) VALUES ($1, $2) ON CONFLICT DO NOTHING",
+            &[&group_id, device_id],
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("cannot add device {}: {}", device_id, e)))?;
+    }
+    Ok(())
+}
+
+pub async fn list_groups(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let rows = client
+        .query(
+            "SELECT g.id, g.name, COALESCE(array_agg(m.fk_device_id ORDER BY m.fk_device_id)
+                 FILTER (WHERE m.fk_device_id IS NOT NULL), '{}') AS device_ids
+             FROM device_groups g
+             LEFT JOIN device_group_members m ON m.fk_group_id = g.id
+             WHERE ($1::int[] IS NULL OR g.id = ANY($1))
+             GROUP BY g.id
+             ORDER BY g.id",
+            &[&scope.group_filter()],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let groups: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| json!({
+            "id": row.get::<_, i32>("id"),
+            "name": row.get::<_, String>("name"),
+            "device_ids": row.get::<_, Vec<i32>>("device_ids"),
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "groups": groups,
+        "count": groups.len()
+    })))
+}
+
+pub async fn create_group(
+    State(pool): State<DbPool>,
+    Json(input): Json<GroupInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    if input.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "group name is required".to_string()));
+    }
+
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let row = tx
+        .query_one("INSERT INTO device_groups (name) VALUES ($1) RETURNING id", &[&input.name])
+        .await
+        .map_err(|e| (StatusCode::CONFLICT, format!("cannot create group: {}", e)))?;
+    let group_id: i32 = row.get("id");
+
+    // a new group has no members to keep
+    write_members(&tx, group_id, &input.device_ids, &Scope::All).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    schedule::refresh(&pool).await;
+
+    Ok((StatusCode::CREATED, JsonResponse(json!({ "status": "success", "group_id": group_id }))))
+}
+
+pub async fn set_group_devices(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Path(group_id): Path<i32>,
+    Json(input): Json<GroupMembersInput>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    // site scoped operators can only move devices they already have access to, and only remove those
+    if let Some(device_id) = input.device_ids.iter().find(|id| !scope.allows_device(**id)) {
+        return Err((StatusCode::FORBIDDEN, format!("no access to device {}", device_id)));
+    }
+
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let exists = tx
+        .query_opt("SELECT id FROM device_groups WHERE id = $1", &[&group_id])
+        .await
+        .map_err(internal_error)?;
+    if exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "group not found".to_string()));
+    }
+
+    write_members(&tx, group_id, &input.device_ids, &scope).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    schedule::refresh(&pool).await;
+
+    Ok(JsonResponse(json!({ "status": "success", "group_id": group_id })))
+}
+
+pub async fn delete_group(
+    State(pool): State<DbPool>,
+    Path(group_id): Path<i32>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let deleted = client
+        .execute("DELETE FROM device_groups WHERE id = $1", &[&group_id])
+        .await
+        .map_err(internal_error)?;
+    if deleted == 0 {
+        return Err((StatusCode::NOT_FOUND, "group not found".to_string()));
+    }
+
+    schedule::refresh(&pool).await;
+
+    Ok(JsonResponse(json!({ "status": "success" })))
+}
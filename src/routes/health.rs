@@ -0,0 +1,102 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use chrono::Utc;
+use serde_json::{json, Value};
+use std::time::{Duration, Instant};
+use crate::cache;
+use crate::config;
+use crate::database::{self, DbPool};
+
+// a probe must answer before the orchestrator gives up, the pool itself waits database.connection_timeout_seconds
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+// liveness, the process is up and serving requests
+pub async fn healthz() -> JsonResponse<Value> {
+    JsonResponse(json!({ "status": "alive" }))
+}
+
+// reachability and schema of the database, answered within DATABASE_TIMEOUT
+async fn check_database(pool: &DbPool) -> (Value, Value) {
+    let start = Instant::now();
+    let checked = tokio::time::timeout(DATABASE_TIMEOUT, async {
+        let client = pool.get().await.map_err(|e| e.to_string())?;
+        client.query_one("SELECT 1", &[]).await.map_err(|e| e.to_string())?;
+        database::pending_migrations(&client).await.map_err(|e| e.to_string())
+    })
+    .await
+    .unwrap_or_else(|_| Err(format!("no answer within {} s", DATABASE_TIMEOUT.as_secs())));
+
+    match checked {
+        Ok(pending) => (
+            json!({ "ok": true, "latency_ms": start.elapsed().as_secs_f64() * 1000.0 }),
+            json!({
+                "ok": pending.is_empty(),
+                "pending": pending.iter().map(|(version, name)| format!("V{}__{}", version, name)).collect::<Vec<_>>()
+            }),
+        ),
+        Err(e) => (
+            json!({ "ok": false, "error": e }),
+            json!({ "ok": false, "error": "database unreachable" }),
+        ),
+    }
+}
+
+// every connection is checked out and none is idle
+fn check_pool(pool: &DbPool, max_connections: u32) -> Value {
+    let state = pool.state();
+    let saturated = state.connections >= max_connections && state.idle_connections == 0;
+    json!({
+        "ok": !saturated,
+        "connections": state.connections,
+        "idle_connections": state.idle_connections,
+        "max_connections": max_connections,
+        "get_timeouts": state.statistics.get_timed_out
+    })
+}
+
+// readiness, 503 with the failing checks when the server cannot ingest or serve
+pub async fn readyz(State(pool): State<DbPool>) -> (StatusCode, JsonResponse<Value>) {
+    let (database, migrations) = check_database(&pool).await;
+
+    let settings = config::get();
+    let pool_check = check_pool(&pool, settings.database.max_connections);
+    let ingest_pool_check = database::ingest_pool()
+        .map(|ingest| check_pool(ingest, settings.database.ingest_max_connections))
+        .unwrap_or_else(|| json!({ "ok": false, "error": "not initialized" }));
+
+    let (depth, running) = cache::is_queue_active().await;
+    // batches are written at least every ingest.max_wait_ms, queued readings waiting much longer mean the writer is stuck
+    let max_flush_age = settings.health.max_flush_age_seconds;
+    let max_depth = settings.health.max_queue_depth;
+    let flush_age = (Utc::now() - cache::last_flush().unwrap_or_else(cache::processor_started)).num_seconds();
+    let stalled = depth > 0 && flush_age > max_flush_age;
+    let batch_processor = json!({
+        "ok": running && !stalled,
+        "running": running,
+        "last_flush": cache::last_flush().map(|at| at.to_rfc3339()),
+        "seconds_since_flush": flush_age,
+        "max_seconds": max_flush_age
+    });
+    let queue = json!({ "ok": depth <= max_depth, "depth": depth, "limit": max_depth });
+
+    let checks = json!({
+        "database": database,
+        "pool": pool_check,
+        "ingest_pool": ingest_pool_check,
+        "batch_processor": batch_processor,
+        "queue": queue,
+        "migrations": migrations
+    });
+    let ready = checks
+        .as_object()
+        .is_some_and(|checks| checks.values().all(|check| check["ok"] == json!(true)));
+
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, JsonResponse(json!({
+        "status": if ready { "ready" } else { "degraded" },
+        "checks": checks
+    })))
+}
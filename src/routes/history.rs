@@ -0,0 +1,190 @@
+use axum::{
+    extract::{Query, State},
+    Extension,
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use chrono::{DateTime, Days, Duration, NaiveDateTime, Timelike, Utc};
+use crate::auth::Scope;
+use crate::database::DbPool;
+use crate::report;
+use crate::routes::{internal_error, resolve_device_list};
+use crate::schedule;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::BTreeMap;
+
+const DEFAULT_HEATMAP_DAYS: u64 = 14;
+const MAX_HEATMAP_DAYS: u64 = 92;
+
+#[derive(Deserialize, Clone, Copy, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryRange {
+    #[default]
+    Hour,
+    Day,
+    Week,
+}
+
+impl HistoryRange {
+    fn as_str(self) -> &'static str {
+        match self {
+            HistoryRange::Hour => "hour",
+            HistoryRange::Day => "day",
+            HistoryRange::Week => "week",
+        }
+    }
+
+    fn duration(self) -> Duration {
+        match self {
+            HistoryRange::Hour => Duration::hours(1),
+            HistoryRange::Day => Duration::days(1),
+            HistoryRange::Week => Duration::weeks(1),
+        }
+    }
+
+    // between 120 and 170 points per device whatever the range
+    fn bucket_seconds(self) -> i64 {
+        match self {
+            HistoryRange::Hour => 30,
+            HistoryRange::Day => 600,
+            HistoryRange::Week => 3600,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    #[serde(default)]
+    pub range: HistoryRange,
+    // `?devices=1,2,3` overlays several devices, takes precedence over device_id and group_id
+    pub devices: Option<String>,
+    pub device_id: Option<i32>,
+    pub group_id: Option<i32>,
+}
+
+// readings of the last hour, day or week averaged into fixed time buckets per device,
+// leq is the energy average so short loud readings are not averaged away
+pub async fn history(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let bucket_seconds = query.range.bucket_seconds();
+    let now = Utc::now();
+    // aligned to a bucket boundary so the first bucket is not a partial one
+    let start = now - query.range.duration();
+    let from = DateTime::from_timestamp(start.timestamp() - start.timestamp().rem_euclid(bucket_seconds), 0).unwrap_or(start);
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let device_ids = resolve_device_list(&client, query.devices.as_deref(), query.device_id, query.group_id, &scope).await?;
+
+    let rows = client
+        .query(
+            "SELECT fk_device_id,
+                    to_timestamp(floor(extract(epoch FROM created_at)::float8 / $3) * $3) AS bucket,
+                    10 * LOG(AVG(POWER(10, decibels / 10))) AS leq,
+                    MIN(decibels) AS lmin, MAX(decibels) AS lmax, COUNT(*) AS readings
+             FROM decibel_logs
+             WHERE created_at >= $1 AND ($2::int[] IS NULL OR fk_device_id = ANY($2))
+             GROUP BY fk_device_id, bucket
+             ORDER BY fk_device_id, bucket",
+            &[&from, &device_ids, &(bucket_seconds as f64)],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let mut devices: BTreeMap<i32, Vec<serde_json::Value>> = BTreeMap::new();
+    for row in &rows {
+        devices.entry(row.get("fk_device_id")).or_default().push(json!({
+            "timestamp": row.get::<_, DateTime<Utc>>("bucket").to_rfc3339(),
+            "leq": row.get::<_, f64>("leq"),
+            "min": row.get::<_, f64>("lmin"),
+            "max": row.get::<_, f64>("lmax"),
+            "readings": row.get::<_, i64>("readings"),
+        }));
+    }
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "range": query.range.as_str(),
+        "bucket_seconds": bucket_seconds,
+        "from": from.to_rfc3339(),
+        "to": now.to_rfc3339(),
+        "devices": devices
+            .into_iter()
+            .map(|(device_id, points)| json!({ "device_id": device_id, "points": points }))
+            .collect::<Vec<_>>()
+    })))
+}
+
+#[derive(Deserialize)]
+pub struct HeatmapQuery {
+    pub days: Option<u64>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    pub devices: Option<String>,
+    pub device_id: Option<i32>,
+    pub group_id: Option<i32>,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+// leq per device, local date and hour of day over the last `days` days including today
+pub async fn heatmap(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<HeatmapQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let Some(tz) = schedule::parse_timezone(&query.timezone) else {
+        return Err((StatusCode::BAD_REQUEST, format!("unknown time zone {}", query.timezone)));
+    };
+    let days = query.days.unwrap_or(DEFAULT_HEATMAP_DAYS);
+    if days == 0 || days > MAX_HEATMAP_DAYS {
+        return Err((StatusCode::BAD_REQUEST, format!("days must be between 1 and {}", MAX_HEATMAP_DAYS)));
+    }
+
+    let first_day = Utc::now().with_timezone(&tz).date_naive() - Days::new(days - 1);
+    let from = report::local_midnight(tz, first_day);
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let device_ids = resolve_device_list(&client, query.devices.as_deref(), query.device_id, query.group_id, &scope).await?;
+
+    let rows = client
+        .query(
+            "SELECT fk_device_id, date_trunc('hour', created_at AT TIME ZONE $3::text) AS local_hour,
+                    10 * LOG(AVG(POWER(10, decibels / 10))) AS leq, MAX(decibels) AS lmax, COUNT(*) AS readings
+             FROM decibel_logs
+             WHERE created_at >= $1 AND ($2::int[] IS NULL OR fk_device_id = ANY($2))
+             GROUP BY fk_device_id, local_hour
+             ORDER BY fk_device_id, local_hour",
+            &[&from, &device_ids, &tz.name()],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let mut devices: BTreeMap<i32, Vec<serde_json::Value>> = BTreeMap::new();
+    for row in &rows {
+        let local_hour: NaiveDateTime = row.get("local_hour");
+        devices.entry(row.get("fk_device_id")).or_default().push(json!({
+            "date": local_hour.date().to_string(),
+            "hour": local_hour.hour(),
+            "leq": row.get::<_, f64>("leq"),
+            "max": row.get::<_, f64>("lmax"),
+            "readings": row.get::<_, i64>("readings"),
+        }));
+    }
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "timezone": tz.name(),
+        "from": first_day.to_string(),
+        "days": days,
+        "devices": devices
+            .into_iter()
+            .map(|(device_id, cells)| json!({ "device_id": device_id, "cells": cells }))
+            .collect::<Vec<_>>()
+    })))
+}
@@ -1,29 +1,298 @@
+use askama::Template;
 use axum::{
-    http::{StatusCode, header},
+    extract::{Path, State},
+    http::{header, HeaderMap, HeaderValue, StatusCode},
     response::{Html, IntoResponse, Response},
+    Extension,
 };
-use std::fs;
+use chrono::{DateTime, Utc};
+use chrono_tz::Tz;
+use crate::assets;
+use crate::auth::{Principal, Scope};
+use crate::cache;
+use crate::config;
+use crate::database::DbPool;
+use crate::schedule;
+use super::alerts::{query_alerts, AlertRow};
+use super::{internal_error, render};
 
-pub async fn dashboard() -> Html<String> {
-    let html_content = fs::read_to_string("static/dashboard.html")
-        .unwrap_or_else(|_| "<h1>Error loading dashboard</h1>".to_string());
-    Html(html_content)
+const RECENT_EVENTS: i64 = 20;
+// devices checked when the compare page opens
+const COMPARE_PRESELECTED: usize = 4;
+
+#[derive(Template)]
+#[template(path = "dashboard.html")]
+struct DashboardTemplate {
+    username: String,
+}
+
+pub async fn dashboard(Extension(principal): Extension<Principal>) -> Result<Html<String>, (StatusCode, String)> {
+    render(&DashboardTemplate { username: principal.username })
+}
+
+// encodings the client accepts, `q=0` excludes one
+fn accepts(headers: &HeaderMap, encoding: &str) -> bool {
+    headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .split(',')
+        .any(|part| {
+            let mut params = part.split(';').map(str::trim);
+            params.next() == Some(encoding)
+                && params.all(|param| param.strip_prefix("q=").and_then(|q| q.parse::<f32>().ok()) != Some(0.0))
+        })
+}
+
+// embedded files under /static, with etag revalidation and the smallest encoding the client takes
+pub async fn serve_static(Path(path): Path<String>, headers: HeaderMap) -> Response {
+    if *assets::DEV_MODE {
+        return match assets::read_from_disk(&path).await {
+            Some((content_type, data)) => (
+                [(header::CONTENT_TYPE, content_type), (header::CACHE_CONTROL, "no-store")],
+                data,
+            ).into_response(),
+            None => not_found().await,
+        };
+    }
+
+    let Some(asset) = assets::get(&path) else {
+        return not_found().await;
+    };
+
+    let cache_control = if asset.immutable { "public, max-age=31536000, immutable" } else { "no-cache" };
+    let not_modified = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|tags| tags.split(',').any(|tag| tag.trim() == asset.etag || tag.trim() == "*"));
+    if not_modified {
+        return (
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, asset.etag.clone()), (header::CACHE_CONTROL, cache_control.to_string())],
+        ).into_response();
+    }
+
+    let (encoding, body) = match (&asset.brotli, &asset.gzip) {
+        (Some(brotli), _) if accepts(&headers, "br") => (Some("br"), brotli.clone()),
+        (_, Some(gzip)) if accepts(&headers, "gzip") => (Some("gzip"), gzip.clone()),
+        _ => (None, asset.identity.clone()),
+    };
+
+    let mut response = (
+        [
+            (header::CONTENT_TYPE, asset.content_type.to_string()),
+            (header::ETAG, asset.etag.clone()),
+            (header::CACHE_CONTROL, cache_control.to_string()),
+            (header::VARY, "accept-encoding".to_string()),
+        ],
+        body,
+    ).into_response();
+    if let Some(encoding) = encoding {
+        response.headers_mut().insert(header::CONTENT_ENCODING, HeaderValue::from_static(encoding));
+    }
+    response
+}
+
+#[derive(Template)]
+#[template(path = "compare.html")]
+struct CompareTemplate {
+    devices: Vec<(i32, String)>,
+    preselected: usize,
 }
 
-pub async fn serve_css() -> Response {
-    let css_content = fs::read_to_string("static/computed.css")
-        .unwrap_or_else(|_| "/* Error loading CSS */".to_string());
-    
-    (
-        StatusCode::OK,
-        [(header::CONTENT_TYPE, "text/css")],
-        css_content,
-    ).into_response()
+// overlay chart and hour of day heatmap for several devices, data from /api/history and /api/heatmap
+pub async fn compare(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+    let rows = client
+        .query(
+            "SELECT id, name FROM devices
+             WHERE deleted_at IS NULL AND ($1::int[] IS NULL OR id = ANY($1)) ORDER BY id",
+            &[&scope.device_filter()],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let devices = rows
+        .iter()
+        .map(|row| {
+            let device_id: i32 = row.get("id");
+            let name = row.get::<_, Option<String>>("name").unwrap_or_else(|| format!("Device {}", device_id));
+            (device_id, name)
+        })
+        .collect();
+
+    render(&CompareTemplate { devices, preselected: COMPARE_PRESELECTED })
 }
 
+#[derive(Template)]
+#[template(path = "error.html")]
+struct NotFoundTemplate;
+
 pub async fn not_found() -> Response {
-    let html_content = fs::read_to_string("static/error.html")
-        .unwrap_or_else(|_| "<h1>404 - Page Not Found</h1>".to_string());
-    
-    (StatusCode::NOT_FOUND, Html(html_content)).into_response()
-} 
\ No newline at end of file
+    match render(&NotFoundTemplate) {
+        Ok(html) => (StatusCode::NOT_FOUND, html).into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+fn level(decibels: Option<f64>) -> String {
+    decibels.map(|db| format!("{:.1} dB", db)).unwrap_or_else(|| "--".to_string())
+}
+
+fn optional(text: Option<String>) -> String {
+    text.unwrap_or_else(|| "not set".to_string())
+}
+
+struct EventRow {
+    started_at: String,
+    duration_s: f64,
+    peak_db: f64,
+    leq_db: f64,
+}
+
+#[derive(Template)]
+#[template(path = "device.html")]
+struct DeviceTemplate {
+    name: String,
+    device_id: i32,
+    live: String,
+    limit_text: String,
+    online: bool,
+    details: Vec<(&'static str, String)>,
+    timezone: &'static str,
+    stats: Vec<(&'static str, String)>,
+    alerts: Vec<AlertRow>,
+    events: Vec<EventRow>,
+}
+
+// server rendered overview of one device, live level and the chart are updated over the websocket
+pub async fn device_detail(
+    State(pool): State<DbPool>,
+    Path(device_id): Path<i32>,
+) -> Result<Response, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let Some(device) = client
+        .query_opt(
+            "SELECT name, location, model, serial_number, calibration_offset_db, calibrated_at, token_issued_at
+             FROM devices WHERE id = $1 AND deleted_at IS NULL",
+            &[&device_id],
+        )
+        .await
+        .map_err(internal_error)?
+    else {
+        return Ok(not_found().await);
+    };
+
+    let name = device.get::<_, Option<String>>("name").unwrap_or_else(|| format!("Device {}", device_id));
+    let now = Utc::now();
+
+    let cached = cache::get_device_reading(device_id);
+    let last_seen = match &cached {
+        Some(reading) => Some(reading.timestamp),
+        None => client
+            .query_one("SELECT MAX(created_at) AS last_seen FROM decibel_logs WHERE fk_device_id = $1", &[&device_id])
+            .await
+            .map_err(internal_error)?
+            .get("last_seen"),
+    };
+    let online = last_seen.is_some_and(|seen| (now - seen).num_seconds() < config::get().cache.active_window_seconds);
+
+    // today in the timezone of the device's noise schedule, utc without one
+    let tz = schedule::schedule_for_device(device_id).map(|schedule| schedule.timezone).unwrap_or(Tz::UTC);
+    let today = now
+        .with_timezone(&tz)
+        .date_naive()
+        .and_hms_opt(0, 0, 0)
+        .and_then(|midnight| midnight.and_local_timezone(tz).earliest())
+        .map(|midnight| midnight.with_timezone(&Utc))
+        .unwrap_or(now);
+
+    let stats = client
+        .query_one(
+            "SELECT 10 * LOG(AVG(POWER(10, decibels / 10))) AS leq, MAX(decibels) AS lmax,
+                    percentile_cont(0.1) WITHIN GROUP (ORDER BY decibels) AS l90, COUNT(*) AS readings
+             FROM decibel_logs WHERE fk_device_id = $1 AND created_at >= $2",
+            &[&device_id, &today],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let events = client
+        .query(
+            "SELECT started_at, duration_ms, peak_db, leq_db FROM noise_events
+             WHERE fk_device_id = $1 ORDER BY started_at DESC LIMIT $2",
+            &[&device_id, &RECENT_EVENTS],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let details = vec![
+        ("Location", optional(device.get("location"))),
+        ("Model", optional(device.get("model"))),
+        ("Serial number", optional(device.get("serial_number"))),
+        (
+            "Calibration",
+            match (device.get::<_, Option<f64>>("calibration_offset_db"), device.get::<_, Option<DateTime<Utc>>>("calibrated_at")) {
+                (Some(offset), Some(at)) => format!("{:+.1} dB on {}", offset, at.format("%Y-%m-%d")),
+                (Some(offset), None) => format!("{:+.1} dB", offset),
+                (None, Some(at)) => format!("calibrated on {}", at.format("%Y-%m-%d")),
+                (None, None) => "not calibrated".to_string(),
+            },
+        ),
+        (
+            "Device token",
+            match device.get::<_, Option<DateTime<Utc>>>("token_issued_at") {
+                Some(issued) => format!("issued {}", issued.format("%Y-%m-%d %H:%M UTC")),
+                None => "issued before tracking".to_string(),
+            },
+        ),
+        (
+            "Last seen",
+            match last_seen {
+                Some(seen) => format!("{} UTC", seen.format("%Y-%m-%d %H:%M:%S")),
+                None => "never".to_string(),
+            },
+        ),
+    ];
+
+    let event_rows = events
+        .iter()
+        .map(|event| EventRow {
+            started_at: event.get::<_, DateTime<Utc>>("started_at").format("%Y-%m-%d %H:%M:%S").to_string(),
+            duration_s: event.get::<_, i32>("duration_ms") as f64 / 1000.0,
+            peak_db: event.get("peak_db"),
+            leq_db: event.get("leq_db"),
+        })
+        .collect();
+
+    let alerts = query_alerts(&client, Some(vec![device_id]), false, RECENT_EVENTS).await?;
+
+    let limit_text = match schedule::applicable_limit(device_id, now) {
+        Some(limit) => format!("{} limit {:.1} dB", limit.period, limit.limit_db),
+        None => "no noise limit".to_string(),
+    };
+
+    let html = render(&DeviceTemplate {
+        name,
+        device_id,
+        live: cached.filter(|_| online).map(|reading| format!("{:.1}", reading.decibels)).unwrap_or_else(|| "--".to_string()),
+        limit_text,
+        online,
+        details,
+        timezone: tz.name(),
+        stats: vec![
+            ("Leq", level(stats.get("leq"))),
+            ("Lmax", level(stats.get("lmax"))),
+            ("L90", level(stats.get("l90"))),
+            ("Readings", stats.get::<_, i64>("readings").to_string()),
+        ],
+        alerts,
+        events: event_rows,
+    })?;
+
+    Ok(html.into_response())
+}
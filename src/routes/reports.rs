@@ -0,0 +1,693 @@
+use askama::Template;
+use axum::{
+    extract::{Json, Path, Query, State},
+    Extension,
+    http::{StatusCode, header},
+    response::{Html, IntoResponse, Json as JsonResponse, Response},
+};
+use chrono::{DateTime, Days, Duration, NaiveDate, NaiveTime, TimeZone, Timelike, Utc};
+use std::collections::BTreeMap;
+use crate::acoustics;
+use crate::auth::Scope;
+use crate::config;
+use crate::database::DbPool;
+use crate::dose::{self, DoseParams};
+use crate::report::{self, ReportRequest};
+use crate::routes::{internal_error, render, resolve_devices};
+use crate::schedule;
+use serde::Deserialize;
+use serde_json::json;
+
+const MAX_REPORT_DAYS: u64 = 366;
+
+#[derive(Deserialize)]
+pub struct LdenQuery {
+    pub from: NaiveDate,
+    pub to: NaiveDate,
+    pub device_id: Option<i32>,
+    pub group_id: Option<i32>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default = "default_day_start")]
+    pub day_start: NaiveTime,
+    #[serde(default = "default_evening_start")]
+    pub evening_start: NaiveTime,
+    #[serde(default = "default_night_start")]
+    pub night_start: NaiveTime,
+    pub format: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DoseQuery {
+    pub date: NaiveDate,
+    pub device_id: Option<i32>,
+    pub group_id: Option<i32>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    #[serde(default = "default_shift_start")]
+    pub shift_start: NaiveTime,
+    #[serde(default = "default_shift_hours")]
+    pub shift_hours: i64,
+    #[serde(default = "default_exchange_rate")]
+    pub exchange_rate: f64,
+    #[serde(default = "default_criterion_level")]
+    pub criterion_level: f64,
+    #[serde(default = "default_threshold_level")]
+    pub threshold_level: f64,
+}
+
+#[derive(Deserialize)]
+pub struct ReportInput {
+    pub group_id: i32,
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReportScheduleInput {
+    pub group_id: i32,
+    pub frequency: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+// eu environmental noise directive defaults, day 07-19, evening 19-23, night 23-07
+fn default_day_start() -> NaiveTime {
+    NaiveTime::from_hms_opt(7, 0, 0).unwrap()
+}
+
+fn default_evening_start() -> NaiveTime {
+    NaiveTime::from_hms_opt(19, 0, 0).unwrap()
+}
+
+fn default_night_start() -> NaiveTime {
+    NaiveTime::from_hms_opt(23, 0, 0).unwrap()
+}
+
+// the live gauge settings, so both report the same dose unless the query overrides them
+fn default_shift_start() -> NaiveTime {
+    config::get().dose.shift_start
+}
+
+fn default_shift_hours() -> i64 {
+    config::get().dose.shift_hours
+}
+
+fn default_exchange_rate() -> f64 {
+    config::get().dose.exchange_rate
+}
+
+fn default_criterion_level() -> f64 {
+    config::get().dose.criterion_level
+}
+
+fn default_threshold_level() -> f64 {
+    config::get().dose.threshold_level
+}
+
+// seconds after the start of the day period, the night period runs until the next day start
+fn offset_from(day_start: NaiveTime, time: NaiveTime) -> f64 {
+    let offset = time.num_seconds_from_midnight() as i64 - day_start.num_seconds_from_midnight() as i64;
+    offset.rem_euclid(86400) as f64
+}
+
+#[derive(Default)]
+struct DayLevels {
+    ld: Option<f64>,
+    le: Option<f64>,
+    ln: Option<f64>,
+    readings: i64,
+}
+
+pub async fn lden(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<LdenQuery>,
+) -> Result<Response, (StatusCode, String)> {
+    let Some(tz) = schedule::parse_timezone(&query.timezone) else {
+        return Err((StatusCode::BAD_REQUEST, format!("unknown time zone {}", query.timezone)));
+    };
+    if query.to < query.from {
+        return Err((StatusCode::BAD_REQUEST, "`to` must not be before `from`".to_string()));
+    }
+    if (query.to - query.from).num_days() as u64 >= MAX_REPORT_DAYS {
+        return Err((StatusCode::BAD_REQUEST, format!("reports are limited to {} days", MAX_REPORT_DAYS)));
+    }
+
+    let evening_offset = offset_from(query.day_start, query.evening_start);
+    let night_offset = offset_from(query.day_start, query.night_start);
+    if evening_offset == 0.0 || night_offset < evening_offset {
+        return Err((StatusCode::BAD_REQUEST, "periods must be ordered day, evening, night".to_string()));
+    }
+    let hours = (
+        evening_offset / 3600.0,
+        (night_offset - evening_offset) / 3600.0,
+        (86400.0 - night_offset) / 3600.0,
+    );
+
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let device_ids = resolve_devices(&client, query.device_id, query.group_id, &scope).await?;
+
+    // each report day starts at the local day start, so a night belongs to the day it started on
+    let local_bound = |date: NaiveDate| {
+        let naive = date.and_time(query.day_start);
+        tz.from_local_datetime(&naive)
+            .earliest()
+            .unwrap_or_else(|| tz.from_utc_datetime(&naive))
+            .with_timezone(&Utc)
+    };
+    let start = local_bound(query.from);
+    let end = local_bound(query.to + Days::new(1));
+
+    // the group-wide level is an extra grouping set with a null device id
+    let grouping = if query.group_id.is_some() && query.device_id.is_none() {
+        "GROUPING SETS ((fk_device_id, day, period), (day, period))"
+    } else {
+        "fk_device_id, day, period"
+    };
+
+    let sql = format!(
+        "WITH r AS (
+            SELECT fk_device_id, decibels,
+                   (created_at AT TIME ZONE $1) - $2 * INTERVAL '1 second' AS shifted
+            FROM decibel_logs
+            WHERE created_at >= $3 AND created_at < $4
+              AND ($5::int[] IS NULL OR fk_device_id = ANY($5))
+        ), p AS (
+            SELECT fk_device_id, decibels, shifted::date AS day,
+                   CASE WHEN EXTRACT(EPOCH FROM shifted::time)::float8 < $6 THEN 'day'
+                        WHEN EXTRACT(EPOCH FROM shifted::time)::float8 < $7 THEN 'evening'
+                        ELSE 'night' END AS period
+            FROM r
+        )
+        SELECT fk_device_id, day, period, COUNT(*) AS readings,
+               10 * LOG(AVG(POWER(10, decibels / 10))) AS leq
+        FROM p
+        GROUP BY {}
+        ORDER BY fk_device_id NULLS FIRST, day",
+        grouping
+    );
+
+    let day_start_seconds = query.day_start.num_seconds_from_midnight() as f64;
+    let rows = client
+        .query(
+            &sql,
+            &[&query.timezone, &day_start_seconds, &start, &end, &device_ids, &evening_offset, &night_offset],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let mut days: BTreeMap<(Option<i32>, NaiveDate), DayLevels> = BTreeMap::new();
+    for row in rows {
+        let device_id: Option<i32> = row.get("fk_device_id");
+        let day: NaiveDate = row.get("day");
+        let period: &str = row.get("period");
+        let leq: f64 = row.get("leq");
+
+        let levels = days.entry((device_id, day)).or_default();
+        levels.readings += row.get::<_, i64>("readings");
+        match period {
+            "day" => levels.ld = Some(leq),
+            "evening" => levels.le = Some(leq),
+            _ => levels.ln = Some(leq),
+        }
+    }
+
+    // a period without readings leaves the daily value undefined unless the period has zero length
+    let complete = |level: Option<f64>, period_hours: f64| match level {
+        Some(level) => Some(level),
+        None if period_hours == 0.0 => Some(0.0),
+        None => None,
+    };
+
+    let results: Vec<serde_json::Value> = days
+        .iter()
+        .map(|((device_id, day), levels)| {
+            let (ld, le, ln) = (
+                complete(levels.ld, hours.0),
+                complete(levels.le, hours.1),
+                complete(levels.ln, hours.2),
+            );
+            let (lden, ldn) = match (ld, le, ln) {
+                (Some(ld), Some(le), Some(ln)) => (
+                    Some(acoustics::lden(ld, le, ln, hours)),
+                    Some(acoustics::ldn(ld, le, ln, hours)),
+                ),
+                _ => (None, None),
+            };
+
+            json!({
+                "scope": if device_id.is_some() { "device" } else { "group" },
+                "device_id": device_id,
+                "group_id": query.group_id,
+                "date": day,
+                "ld": levels.ld,
+                "le": levels.le,
+                "ln": levels.ln,
+                "lden": lden,
+                "ldn": ldn,
+                "readings": levels.readings,
+            })
+        })
+        .collect();
+
+    if query.format.as_deref() == Some("csv") {
+        let mut csv = String::from("scope,device_id,group_id,date,ld,le,ln,lden,ldn,readings\n");
+        let field = |value: &serde_json::Value| match value {
+            serde_json::Value::Null => String::new(),
+            serde_json::Value::Number(n) => match n.as_f64() {
+                Some(f) if !n.is_i64() => format!("{:.1}", f),
+                _ => n.to_string(),
+            },
+            serde_json::Value::String(s) => s.clone(),
+            other => other.to_string(),
+        };
+        for result in &results {
+            let columns: Vec<String> = ["scope", "device_id", "group_id", "date", "ld", "le", "ln", "lden", "ldn", "readings"]
+                .iter()
+                .map(|key| field(&result[*key]))
+                .collect();
+            csv.push_str(&columns.join(","));
+            csv.push('\n');
+        }
+
+        let disposition = format!("attachment; filename=\"lden_{}_{}.csv\"", query.from, query.to);
+        return Ok((
+            [
+                (header::CONTENT_TYPE, "text/csv; charset=utf-8".to_string()),
+                (header::CONTENT_DISPOSITION, disposition),
+            ],
+            csv,
+        ).into_response());
+    }
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "timezone": query.timezone,
+        "periods": {
+            "day_start": query.day_start,
+            "evening_start": query.evening_start,
+            "night_start": query.night_start,
+            "hours": { "day": hours.0, "evening": hours.1, "night": hours.2 }
+        },
+        "days": results,
+        "count": results.len()
+    })).into_response())
+}
+
+// noise dose and 8 hour twa per device for each shift starting on the given local date
+pub async fn dose(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(query): Query<DoseQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let Some(tz) = schedule::parse_timezone(&query.timezone) else {
+        return Err((StatusCode::BAD_REQUEST, format!("unknown time zone {}", query.timezone)));
+    };
+    if !(1..=24).contains(&query.shift_hours) {
+        return Err((StatusCode::BAD_REQUEST, "shift_hours must be between 1 and 24".to_string()));
+    }
+    if query.exchange_rate <= 0.0 {
+        return Err((StatusCode::BAD_REQUEST, "exchange_rate must be positive".to_string()));
+    }
+
+    let params = DoseParams {
+        exchange_rate: query.exchange_rate,
+        criterion_level: query.criterion_level,
+        threshold_level: query.threshold_level,
+    };
+
+    // a local day is 23 or 25 hours long when dst changes, the last shift is cut or extended to match
+    let start = report::local_datetime(tz, query.date, query.shift_start);
+    let end = report::local_datetime(tz, query.date + Days::new(1), query.shift_start);
+    let shift_seconds = (query.shift_hours * 3600) as f64;
+    let last_shift = ((24 + query.shift_hours - 1) / query.shift_hours - 1) as i32;
+    let criterion_seconds = dose::CRITERION_HOURS * 3600.0;
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let device_ids = resolve_devices(&client, query.device_id, query.group_id, &scope).await?;
+
+    let rows = client
+        .query(
+            "WITH r AS (
+                SELECT fk_device_id, decibels,
+                       LEAST(FLOOR(EXTRACT(EPOCH FROM created_at - $1)::float8 / $3)::int, $10) AS shift,
+                       EXTRACT(EPOCH FROM LEAD(created_at) OVER w - created_at)::float8 AS seconds
+                FROM decibel_logs
+                WHERE created_at >= $1 AND created_at < $2
+                  AND ($4::int[] IS NULL OR fk_device_id = ANY($4))
+                WINDOW w AS (PARTITION BY fk_device_id ORDER BY created_at)
+            ), h AS (
+                SELECT fk_device_id, decibels, shift,
+                       CASE WHEN seconds IS NULL THEN 0 ELSE LEAST(seconds, $5) END AS held
+                FROM r
+            )
+            SELECT fk_device_id, shift, COUNT(*) AS readings, MAX(decibels) AS lmax,
+                   SUM(held) AS exposure_seconds,
+                   10 * LOG(AVG(POWER(10, decibels / 10))) AS leq,
+                   COALESCE(SUM(held * POWER(2, (decibels - $6) / $7) / $8) FILTER (WHERE decibels >= $9), 0) AS dose
+            FROM h
+            GROUP BY fk_device_id, shift
+            ORDER BY fk_device_id, shift",
+            &[
+                &start,
+                &end,
+                &shift_seconds,
+                &device_ids,
+                &dose::MAX_HOLD_SECONDS,
+                &params.criterion_level,
+                &params.exchange_rate,
+                &criterion_seconds,
+                &params.threshold_level,
+                &last_shift,
+            ],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let shifts: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let shift: i32 = row.get("shift");
+            let shift_start = start + Duration::hours(query.shift_hours * shift as i64);
+            let shift_end = if shift == last_shift { end } else { (shift_start + Duration::hours(query.shift_hours)).min(end) };
+            let dose_percent = row.get::<_, f64>("dose") * 100.0;
+
+            json!({
+                "device_id": row.get::<_, i32>("fk_device_id"),
+                "shift_start": shift_start.to_rfc3339(),
+                "shift_end": shift_end.to_rfc3339(),
+                "dose_percent": dose_percent,
+                "twa": params.twa(dose_percent),
+                "leq": row.get::<_, f64>("leq"),
+                "lmax": row.get::<_, f64>("lmax"),
+                "exposure_seconds": row.get::<_, f64>("exposure_seconds"),
+                "readings": row.get::<_, i64>("readings"),
+            })
+        })
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "date": query.date,
+        "timezone": query.timezone,
+        "parameters": {
+            "exchange_rate": params.exchange_rate,
+            "criterion_level": params.criterion_level,
+            "threshold_level": params.threshold_level,
+            "criterion_hours": dose::CRITERION_HOURS,
+            "shift_hours": query.shift_hours
+        },
+        "shifts": shifts,
+        "count": shifts.len()
+    })))
+}
+
+pub async fn live_dose(Extension(scope): Extension<Scope>) -> JsonResponse<serde_json::Value> {
+    let settings = &config::get().dose;
+    let params = DoseParams::live();
+    let doses: Vec<serde_json::Value> = dose::live_doses()
+        .iter()
+        .filter(|d| scope.allows_device(d.device_id))
+        .map(|d| json!({
+            "device_id": d.device_id,
+            "shift_start": d.shift_start.to_rfc3339(),
+            "dose_percent": d.dose_percent,
+            "twa": d.twa,
+            "exposure_seconds": d.exposure_seconds,
+        }))
+        .collect();
+
+    JsonResponse(json!({
+        "status": "success",
+        "parameters": {
+            "exchange_rate": params.exchange_rate,
+            "criterion_level": params.criterion_level,
+            "threshold_level": params.threshold_level,
+            "shift_start": settings.shift_start.format("%H:%M").to_string(),
+            "shift_hours": settings.shift_hours
+        },
+        "devices": doses
+    }))
+}
+
+// generates a compliance report bundle for a site (device group) right away
+pub async fn create_report(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Json(input): Json<ReportInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    if !scope.allows_group(input.group_id) {
+        return Err((StatusCode::FORBIDDEN, format!("no access to group {}", input.group_id)));
+    }
+    let Some(tz) = schedule::parse_timezone(&input.timezone) else {
+        return Err((StatusCode::BAD_REQUEST, format!("unknown time zone {}", input.timezone)));
+    };
+    if input.to <= input.from {
+        return Err((StatusCode::BAD_REQUEST, "`to` must be after `from`".to_string()));
+    }
+    if (input.to - input.from).num_days() as u64 >= MAX_REPORT_DAYS {
+        return Err((StatusCode::BAD_REQUEST, format!("reports are limited to {} days", MAX_REPORT_DAYS)));
+    }
+
+    let request = ReportRequest {
+        group_id: input.group_id,
+        from: input.from,
+        to: input.to,
+        timezone: tz,
+        schedule_id: None,
+    };
+
+    let report_id = report::generate(&pool, request).await.map_err(internal_error)?;
+
+    Ok((StatusCode::CREATED, JsonResponse(json!({
+        "status": "success",
+        "report_id": report_id,
+        "url": format!("/reports/{}/report.html", report_id),
+        "files": report::REPORT_FILES.iter().map(|f| format!("/reports/{}/{}", report_id, f)).collect::<Vec<_>>()
+    }))))
+}
+
+async fn report_rows(pool: &DbPool, scope: &Scope) -> Result<Vec<tokio_postgres::Row>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+    client
+        .query(
+            "SELECT r.id, r.fk_group_id, g.name AS group_name, r.fk_schedule_id, r.range_start, r.range_end,
+                    r.timezone, r.status, r.error, r.created_at
+             FROM compliance_reports r
+             JOIN device_groups g ON g.id = r.fk_group_id
+             WHERE ($1::int[] IS NULL OR r.fk_group_id = ANY($1))
+             ORDER BY r.created_at DESC
+             LIMIT 500",
+            &[&scope.group_filter()],
+        )
+        .await
+        .map_err(internal_error)
+}
+
+pub async fn list_reports(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let reports: Vec<serde_json::Value> = report_rows(&pool, &scope)
+        .await?
+        .iter()
+        .map(|row| {
+            let id: i32 = row.get("id");
+            json!({
+                "id": id,
+                "group_id": row.get::<_, i32>("fk_group_id"),
+                "group_name": row.get::<_, String>("group_name"),
+                "schedule_id": row.get::<_, Option<i32>>("fk_schedule_id"),
+                "from": row.get::<_, DateTime<Utc>>("range_start").to_rfc3339(),
+                "to": row.get::<_, DateTime<Utc>>("range_end").to_rfc3339(),
+                "timezone": row.get::<_, String>("timezone"),
+                "status": row.get::<_, String>("status"),
+                "error": row.get::<_, Option<String>>("error"),
+                "created_at": row.get::<_, DateTime<Utc>>("created_at").to_rfc3339(),
+                "url": format!("/reports/{}/report.html", id),
+            })
+        })
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "reports": reports,
+        "count": reports.len()
+    })))
+}
+
+struct ReportListing {
+    id: i32,
+    group_name: String,
+    from: String,
+    to: String,
+    timezone: String,
+    origin: &'static str,
+    ready: bool,
+    status: String,
+}
+
+#[derive(Template)]
+#[template(path = "reports.html")]
+struct ReportsTemplate {
+    reports: Vec<ReportListing>,
+    files: &'static [&'static str],
+}
+
+// download index of generated reports
+pub async fn reports_index(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<Html<String>, (StatusCode, String)> {
+    let rows = report_rows(&pool, &scope).await?;
+
+    let reports = rows
+        .iter()
+        .map(|row| {
+            let status: String = row.get("status");
+            ReportListing {
+                id: row.get("id"),
+                group_name: row.get("group_name"),
+                from: row.get::<_, DateTime<Utc>>("range_start").format("%Y-%m-%d %H:%M").to_string(),
+                to: row.get::<_, DateTime<Utc>>("range_end").format("%Y-%m-%d %H:%M").to_string(),
+                timezone: row.get("timezone"),
+                origin: if row.get::<_, Option<i32>>("fk_schedule_id").is_some() { "scheduled" } else { "on demand" },
+                ready: status == "ready",
+                status,
+            }
+        })
+        .collect();
+
+    render(&ReportsTemplate { reports, files: report::REPORT_FILES })
+}
+
+pub async fn report_file(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Path((report_id, file)): Path<(i32, String)>,
+) -> Response {
+    if !report::is_report_file(&file) {
+        return (StatusCode::NOT_FOUND, "unknown report file").into_response();
+    }
+
+    // site scoped users only get reports of their sites
+    if let Some(groups) = scope.group_filter() {
+        let client = match pool.get().await {
+            Ok(client) => client,
+            Err(e) => return internal_error(e).into_response(),
+        };
+        match client
+            .query_opt("SELECT 1 FROM compliance_reports WHERE id = $1 AND fk_group_id = ANY($2)", &[&report_id, &groups])
+            .await
+        {
+            Ok(Some(_)) => {}
+            Ok(None) => return (StatusCode::NOT_FOUND, "report file not found").into_response(),
+            Err(e) => return internal_error(e).into_response(),
+        }
+    }
+
+    match tokio::fs::read(report::report_dir(report_id).join(&file)).await {
+        Ok(content) => {
+            let (content_type, disposition) = if file.ends_with(".html") {
+                ("text/html; charset=utf-8", "inline".to_string())
+            } else {
+                ("text/csv; charset=utf-8", format!("attachment; filename=\"report_{}_{}\"", report_id, file))
+            };
+            (
+                [
+                    (header::CONTENT_TYPE, content_type.to_string()),
+                    (header::CONTENT_DISPOSITION, disposition),
+                ],
+                content,
+            ).into_response()
+        }
+        Err(_) => (StatusCode::NOT_FOUND, "report file not found").into_response(),
+    }
+}
+
+pub async fn list_report_schedules(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let rows = client
+        .query(
+            "SELECT id, fk_group_id, frequency, timezone, next_run_at FROM report_schedules
+             WHERE ($1::int[] IS NULL OR fk_group_id = ANY($1)) ORDER BY id",
+            &[&scope.group_filter()],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let schedules: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| json!({
+            "id": row.get::<_, i32>("id"),
+            "group_id": row.get::<_, i32>("fk_group_id"),
+            "frequency": row.get::<_, String>("frequency"),
+            "timezone": row.get::<_, String>("timezone"),
+            "next_run_at": row.get::<_, DateTime<Utc>>("next_run_at").to_rfc3339(),
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "schedules": schedules,
+        "count": schedules.len()
+    })))
+}
+
+pub async fn create_report_schedule(
+    State(pool): State<DbPool>,
+    Json(input): Json<ReportScheduleInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    let Some(tz) = schedule::parse_timezone(&input.timezone) else {
+        return Err((StatusCode::BAD_REQUEST, format!("unknown time zone {}", input.timezone)));
+    };
+    if !report::FREQUENCIES.contains(&input.frequency.as_str()) {
+        return Err((StatusCode::BAD_REQUEST, format!("frequency must be one of {}", report::FREQUENCIES.join(", "))));
+    }
+
+    let next_run = report::next_run_after(&input.frequency, tz, Utc::now());
+
+    let client = pool.get().await.map_err(internal_error)?;
+    let row = client
+        .query_one(
+            "INSERT INTO report_schedules (fk_group_id, frequency, timezone, next_run_at) VALUES ($1, $2, $3, $4) RETURNING id",
+            &[&input.group_id, &input.frequency, &input.timezone, &next_run],
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("cannot create report schedule: {}", e)))?;
+
+    Ok((StatusCode::CREATED, JsonResponse(json!({
+        "status": "success",
+        "schedule_id": row.get::<_, i32>("id"),
+        "next_run_at": next_run.to_rfc3339()
+    }))))
+}
+
+pub async fn delete_report_schedule(
+    State(pool): State<DbPool>,
+    Path(schedule_id): Path<i32>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let deleted = client
+        .execute("DELETE FROM report_schedules WHERE id = $1", &[&schedule_id])
+        .await
+        .map_err(internal_error)?;
+    if deleted == 0 {
+        return Err((StatusCode::NOT_FOUND, "report schedule not found".to_string()));
+    }
+
+    Ok(JsonResponse(json!({ "status": "success" })))
+}
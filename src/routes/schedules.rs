@@ -0,0 +1,324 @@
+use axum::{
+    extract::{Path, Query, State, Json},
+    Extension,
+    http::StatusCode,
+    response::Json as JsonResponse,
+};
+use chrono::{DateTime, NaiveTime, Utc};
+use crate::auth::Scope;
+use crate::database::DbPool;
+use crate::schedule;
+use crate::routes::internal_error;
+use serde::Deserialize;
+use serde_json::json;
+
+#[derive(Deserialize)]
+pub struct SchedulePeriodInput {
+    pub name: String,
+    #[serde(default = "all_weekdays")]
+    pub weekdays: Vec<i16>,
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub limit_db: f64,
+}
+
+#[derive(Deserialize)]
+pub struct ScheduleInput {
+    pub name: String,
+    #[serde(default = "default_timezone")]
+    pub timezone: String,
+    pub periods: Vec<SchedulePeriodInput>,
+}
+
+#[derive(Deserialize)]
+pub struct AssignmentInput {
+    #[serde(default)]
+    pub device_ids: Vec<i32>,
+    #[serde(default)]
+    pub group_ids: Vec<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct ComplianceQuery {
+    pub from: DateTime<Utc>,
+    pub to: DateTime<Utc>,
+    pub device_id: Option<i32>,
+}
+
+fn all_weekdays() -> Vec<i16> {
+    vec![1, 2, 3, 4, 5, 6, 7]
+}
+
+fn default_timezone() -> String {
+    "UTC".to_string()
+}
+
+fn validate(input: &ScheduleInput) -> Result<(), (StatusCode, String)> {
+    if input.name.trim().is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "schedule name is required".to_string()));
+    }
+    if schedule::parse_timezone(&input.timezone).is_none() {
+        return Err((StatusCode::BAD_REQUEST, format!("unknown time zone {}", input.timezone)));
+    }
+    for period in &input.periods {
+        if period.name.trim().is_empty() {
+            return Err((StatusCode::BAD_REQUEST, "period name is required".to_string()));
+        }
+        if period.weekdays.is_empty() || period.weekdays.iter().any(|d| !(1..=7).contains(d)) {
+            return Err((StatusCode::BAD_REQUEST, format!("period {} needs weekdays between 1 (monday) and 7 (sunday)", period.name)));
+        }
+        if !period.limit_db.is_finite() {
+            return Err((StatusCode::BAD_REQUEST, format!("period {} has an invalid limit", period.name)));
+        }
+    }
+    Ok(())
+}
+
+pub async fn list_schedules(State(pool): State<DbPool>) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let schedule_rows = client
+        .query("SELECT id, name, timezone FROM noise_schedules ORDER BY id", &[])
+        .await
+        .map_err(internal_error)?;
+
+    let period_rows = client
+        .query(
+            "SELECT id, fk_schedule_id, name, weekdays, start_time, end_time, limit_db
+             FROM noise_schedule_periods ORDER BY id",
+            &[],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let assignment_rows = client
+        .query("SELECT fk_schedule_id, fk_device_id, fk_group_id FROM noise_schedule_assignments", &[])
+        .await
+        .map_err(internal_error)?;
+
+    let schedules: Vec<serde_json::Value> = schedule_rows
+        .iter()
+        .map(|row| {
+            let id: i32 = row.get("id");
+            let periods: Vec<serde_json::Value> = period_rows
+                .iter()
+                .filter(|p| p.get::<_, i32>("fk_schedule_id") == id)
+                .map(|p| json!({
+                    "id": p.get::<_, i32>("id"),
+                    "name": p.get::<_, String>("name"),
+                    "weekdays": p.get::<_, Vec<i16>>("weekdays"),
+                    "start_time": p.get::<_, NaiveTime>("start_time"),
+                    "end_time": p.get::<_, NaiveTime>("end_time"),
+                    "limit_db": p.get::<_, f64>("limit_db"),
+                }))
+                .collect();
+            let assigned = assignment_rows.iter().filter(|a| a.get::<_, i32>("fk_schedule_id") == id);
+            let device_ids: Vec<i32> = assigned.clone().filter_map(|a| a.get("fk_device_id")).collect();
+            let group_ids: Vec<i32> = assigned.filter_map(|a| a.get("fk_group_id")).collect();
+
+            json!({
+                "id": id,
+                "name": row.get::<_, String>("name"),
+                "timezone": row.get::<_, String>("timezone"),
+                "periods": periods,
+                "device_ids": device_ids,
+                "group_ids": group_ids,
+                "effective_device_ids": schedule::devices_for_schedule(id),
+            })
+        })
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "schedules": schedules,
+        "count": schedules.len()
+    })))
+}
+
+async fn write_periods(
+    tx: &tokio_postgres::Transaction<'_>,
+    schedule_id: i32,
+    periods: &[SchedulePeriodInput],
+) -> Result<(), tokio_postgres::Error> {
+    tx.execute("DELETE FROM noise_schedule_periods WHERE fk_schedule_id = $1", &[&schedule_id]).await?;
+    for period in periods {
+        tx.execute(
+            "INSERT INTO noise_schedule_periods (fk_schedule_id, name, weekdays, start_time, end_time, limit_db)
+             VALUES ($1, $2, $3, $4, $5, $6)",
+            &[&schedule_id, &period.name, &period.weekdays, &period.start_time, &period.end_time, &period.limit_db],
+        )
+        .await?;
+    }
+    Ok(())
+}
+
+pub async fn create_schedule(
+    State(pool): State<DbPool>,
+    Json(input): Json<ScheduleInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    validate(&input)?;
+
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let row = tx
+        .query_one(
+            "INSERT INTO noise_schedules (name, timezone) VALUES ($1, $2) RETURNING id",
+            &[&input.name, &input.timezone],
+        )
+        .await
+        .map_err(internal_error)?;
+    let schedule_id: i32 = row.get("id");
+
+    write_periods(&tx, schedule_id, &input.periods).await.map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    schedule::refresh(&pool).await;
+
+    Ok((StatusCode::CREATED, JsonResponse(json!({ "status": "success", "schedule_id": schedule_id }))))
+}
+
+pub async fn update_schedule(
+    State(pool): State<DbPool>,
+    Path(schedule_id): Path<i32>,
+    Json(input): Json<ScheduleInput>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    validate(&input)?;
+
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let updated = tx
+        .execute(
+            "UPDATE noise_schedules SET name = $2, timezone = $3 WHERE id = $1",
+            &[&schedule_id, &input.name, &input.timezone],
+        )
+        .await
+        .map_err(internal_error)?;
+    if updated == 0 {
+        return Err((StatusCode::NOT_FOUND, "schedule not found".to_string()));
+    }
+
+    write_periods(&tx, schedule_id, &input.periods).await.map_err(internal_error)?;
+    tx.commit().await.map_err(internal_error)?;
+
+    schedule::refresh(&pool).await;
+
+    Ok(JsonResponse(json!({ "status": "success", "schedule_id": schedule_id })))
+}
+
+pub async fn delete_schedule(
+    State(pool): State<DbPool>,
+    Path(schedule_id): Path<i32>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let deleted = client
+        .execute("DELETE FROM noise_schedules WHERE id = $1", &[&schedule_id])
+        .await
+        .map_err(internal_error)?;
+    if deleted == 0 {
+        return Err((StatusCode::NOT_FOUND, "schedule not found".to_string()));
+    }
+
+    schedule::refresh(&pool).await;
+
+    Ok(JsonResponse(json!({ "status": "success" })))
+}
+
+// replaces the devices and groups a schedule applies to, moving them off any previous schedule
+pub async fn set_assignments(
+    State(pool): State<DbPool>,
+    Path(schedule_id): Path<i32>,
+    Json(input): Json<AssignmentInput>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let exists = tx
+        .query_opt("SELECT id FROM noise_schedules WHERE id = $1", &[&schedule_id])
+        .await
+        .map_err(internal_error)?;
+    if exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, "schedule not found".to_string()));
+    }
+
+    tx.execute(
+        "DELETE FROM noise_schedule_assignments
+         WHERE fk_schedule_id = $1 OR fk_device_id = ANY($2) OR fk_group_id = ANY($3)",
+        &[&schedule_id, &input.device_ids, &input.group_ids],
+    )
+    .await
+    .map_err(internal_error)?;
+
+    for device_id in &input.device_ids {
+        tx.execute(
+            "INSERT INTO noise_schedule_assignments (fk_schedule_id, fk_device_id) VALUES ($1, $2)",
+            &[&schedule_id, device_id],
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("cannot assign device {}: {}", device_id, e)))?;
+    }
+    for group_id in &input.group_ids {
+        tx.execute(
+            "INSERT INTO noise_schedule_assignments (fk_schedule_id, fk_group_id) VALUES ($1, $2)",
+            &[&schedule_id, group_id],
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("cannot assign group {}: {}", group_id, e)))?;
+    }
+
+    tx.commit().await.map_err(internal_error)?;
+
+    schedule::refresh(&pool).await;
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "effective_device_ids": schedule::devices_for_schedule(schedule_id)
+    })))
+}
+
+// time above the applicable limit and number of exceedance events per device and period
+pub async fn compliance(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Path(schedule_id): Path<i32>,
+    Query(query): Query<ComplianceQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    if query.to <= query.from {
+        return Err((StatusCode::BAD_REQUEST, "`to` must be after `from`".to_string()));
+    }
+
+    let device_ids = match query.device_id {
+        Some(id) => vec![id],
+        None => schedule::devices_for_schedule(schedule_id),
+    };
+    let device_ids: Vec<i32> = device_ids.into_iter().filter(|id| scope.allows_device(*id)).collect();
+
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let periods: Vec<serde_json::Value> = schedule::compliance(&client, schedule_id, &device_ids, query.from, query.to)
+        .await
+        .map_err(internal_error)?
+        .iter()
+        .map(|c| json!({
+            "device_id": c.device_id,
+            "period": c.period,
+            "limit_db": c.limit_db,
+            "readings": c.readings,
+            "max_db": c.max_db,
+            "seconds_total": c.seconds_total,
+            "seconds_over": c.seconds_over,
+            "fraction_over": c.fraction_over(),
+            "events": c.events,
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "schedule_id": schedule_id,
+        "from": query.from.to_rfc3339(),
+        "to": query.to.to_rfc3339(),
+        "periods": periods
+    })))
+}
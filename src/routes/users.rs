@@ -0,0 +1,434 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, uri::PathAndQuery, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Json as JsonResponse, Redirect, Response},
+    Extension, Form, Json,
+};
+use askama::Template;
+use serde::Deserialize;
+use serde_json::json;
+use crate::audit::{self, AuditEntry};
+use crate::auth::{self, Principal, Role};
+use crate::database::DbPool;
+use super::{internal_error, render};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    pub username: String,
+    pub password: String,
+    #[serde(default)]
+    pub next: String,
+}
+
+// `?error=1&next=/reports` after a failed sign in or a redirect from a protected page
+#[derive(Deserialize)]
+pub struct LoginQuery {
+    pub error: Option<String>,
+    #[serde(default)]
+    pub next: String,
+}
+
+#[derive(Deserialize)]
+pub struct UserInput {
+    pub username: String,
+    pub password: String,
+    // new users are viewers everywhere unless roles are given
+    pub roles: Option<Vec<RoleInput>>,
+}
+
+#[derive(Deserialize)]
+pub struct RoleInput {
+    pub role: String,
+    pub group_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct AuditQuery {
+    pub limit: Option<i64>,
+    pub user_id: Option<i32>,
+}
+
+#[derive(Deserialize)]
+pub struct TokenInput {
+    pub name: String,
+}
+
+#[derive(Template)]
+#[template(path = "login.html")]
+struct LoginTemplate<'a> {
+    error: bool,
+    next: &'a str,
+}
+
+pub async fn login_page(Query(query): Query<LoginQuery>) -> Result<Html<String>, (StatusCode, String)> {
+    render(&LoginTemplate {
+        error: query.error.is_some(),
+        next: safe_next(&query.next),
+    })
+}
+
+// only same-site paths are followed after login. browsers read `\` as `/`, so `/\evil.com` (or its
+// encoded form) would leave the site like `//evil.com` does
+fn safe_next(next: &str) -> &str {
+    let relative = next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.contains('\\')
+        && !next.chars().any(char::is_control)
+        && !next.to_ascii_lowercase().contains("%5c")
+        && next.parse::<PathAndQuery>().is_ok_and(|path| path.as_str() == next);
+    if relative { next } else { "/" }
+}
+
+pub async fn login(State(pool): State<DbPool>, Form(form): Form<LoginForm>) -> Result<Response, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let row = client
+        .query_opt(
+            "SELECT id, password_hash FROM users WHERE username = $1 AND disabled_at IS NULL",
+            &[&form.username],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let user_id = match row {
+        Some(row) if auth::verify_password(&form.password, row.get("password_hash")).await => row.get::<_, i32>("id"),
+        _ => {
+            audit_login(None, &form.username, "login_failed", StatusCode::UNAUTHORIZED).await;
+            let next = safe_next(&form.next);
+            return Ok(Redirect::to(&format!("/login?error=1&next={}", next.replace('&', "%26"))).into_response());
+        }
+    };
+
+    let secret = auth::create_session(&client, user_id).await.map_err(internal_error)?;
+    audit_login(Some(user_id), &form.username, "login", StatusCode::OK).await;
+
+    Ok((
+        [(header::SET_COOKIE, auth::session_set_cookie(&secret))],
+        Redirect::to(safe_next(&form.next)),
+    ).into_response())
+}
+
+async fn audit_login(user_id: Option<i32>, username: &str, action: &str, status: StatusCode) {
+    audit::record(AuditEntry {
+        user_id,
+        username: Some(username.to_string()),
+        action: action.to_string(),
+        path: Some("/login".to_string()),
+        permission: None,
+        status: status.as_u16() as i32,
+    }).await;
+}
+
+// a role and the group it is limited to, if any
+type Assignment = (Role, Option<i32>);
+
+fn parse_roles(roles: &[RoleInput]) -> Result<Vec<Assignment>, (StatusCode, String)> {
+    roles
+        .iter()
+        .map(|input| match Role::parse(&input.role) {
+            Some(role) => Ok((role, input.group_id)),
+            None => Err((StatusCode::BAD_REQUEST, format!("unknown role {}, expected admin, operator or viewer", input.role))),
+        })
+        .collect()
+}
+
+async fn write_roles(
+    tx: &tokio_postgres::Transaction<'_>,
+    user_id: i32,
+    roles: &[Assignment],
+) -> Result<(), (StatusCode, String)> {
+    tx.execute("DELETE FROM user_roles WHERE fk_user_id = $1", &[&user_id])
+        .await
+        .map_err(internal_error)?;
+
+    for (role, group_id) in roles {
+        tx.execute(
+            "INSERT INTO user_roles (fk_user_id, role, fk_group_id) VALUES ($1, $2, $3) ON CONFLICT DO NOTHING",
+            &[&user_id, &role.as_str(), group_id],
+        )
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, format!("cannot grant {}: {}", role.as_str(), e)))?;
+    }
+    Ok(())
+}
+
+fn grants_json(grants: &[auth::RoleGrant]) -> Vec<serde_json::Value> {
+    grants
+        .iter()
+        .map(|grant| json!({ "role": grant.role.as_str(), "group_id": grant.group_id }))
+        .collect()
+}
+
+pub async fn logout(State(pool): State<DbPool>, headers: HeaderMap) -> Result<Response, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+    auth::delete_session(&client, &headers).await.map_err(internal_error)?;
+
+    Ok((
+        [(header::SET_COOKIE, auth::session_clear_cookie())],
+        Redirect::to("/login"),
+    ).into_response())
+}
+
+pub async fn me(Extension(principal): Extension<Principal>) -> JsonResponse<serde_json::Value> {
+    JsonResponse(json!({
+        "user_id": principal.user_id,
+        "username": principal.username,
+        "read_only": principal.read_only,
+        "roles": grants_json(&principal.grants)
+    }))
+}
+
+pub async fn list_users(State(pool): State<DbPool>) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let rows = client
+        .query(
+            "SELECT u.id, u.username, u.created_at, u.disabled_at,
+                    COALESCE(array_agg(r.role ORDER BY r.id) FILTER (WHERE r.id IS NOT NULL), '{}') AS roles,
+                    COALESCE(array_agg(r.fk_group_id ORDER BY r.id) FILTER (WHERE r.id IS NOT NULL), '{}') AS role_groups
+             FROM users u
+             LEFT JOIN user_roles r ON r.fk_user_id = u.id
+             GROUP BY u.id
+             ORDER BY u.id",
+            &[],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let users: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| {
+            let roles: Vec<String> = row.get("roles");
+            let role_groups: Vec<Option<i32>> = row.get("role_groups");
+            json!({
+                "id": row.get::<_, i32>("id"),
+                "username": row.get::<_, String>("username"),
+                "created_at": row.get::<_, chrono::DateTime<chrono::Utc>>("created_at").to_rfc3339(),
+                "disabled": row.get::<_, Option<chrono::DateTime<chrono::Utc>>>("disabled_at").is_some(),
+                "roles": roles
+                    .iter()
+                    .zip(&role_groups)
+                    .map(|(role, group_id)| json!({ "role": role, "group_id": group_id }))
+                    .collect::<Vec<_>>(),
+            })
+        })
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "users": users,
+        "count": users.len()
+    })))
+}
+
+pub async fn create_user(
+    State(pool): State<DbPool>,
+    Json(input): Json<UserInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    let username = input.username.trim();
+    if username.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "username must not be empty".to_string()));
+    }
+    if input.password.len() < MIN_PASSWORD_LENGTH {
+        return Err((StatusCode::BAD_REQUEST, format!("password must be at least {} characters", MIN_PASSWORD_LENGTH)));
+    }
+
+    let roles = match &input.roles {
+        Some(roles) => parse_roles(roles)?,
+        None => vec![(Role::Viewer, None)],
+    };
+
+    let password_hash = auth::hash_password(&input.password).await.map_err(internal_error)?;
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let row = tx
+        .query_opt(
+            "INSERT INTO users (username, password_hash) VALUES ($1, $2)
+             ON CONFLICT (username) DO NOTHING RETURNING id",
+            &[&username, &password_hash],
+        )
+        .await
+        .map_err(internal_error)?
+        .ok_or((StatusCode::CONFLICT, format!("user {} already exists", username)))?;
+    let user_id: i32 = row.get("id");
+
+    write_roles(&tx, user_id, &roles).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok((StatusCode::CREATED, JsonResponse(json!({ "status": "success", "user_id": user_id }))))
+}
+
+// replaces every grant of the user, an empty list leaves the user without access
+pub async fn set_roles(
+    State(pool): State<DbPool>,
+    Path(user_id): Path<i32>,
+    Json(input): Json<Vec<RoleInput>>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let roles = parse_roles(&input)?;
+
+    let mut client = pool.get().await.map_err(internal_error)?;
+    let tx = client.transaction().await.map_err(internal_error)?;
+
+    let exists = tx
+        .query_opt("SELECT id FROM users WHERE id = $1", &[&user_id])
+        .await
+        .map_err(internal_error)?;
+    if exists.is_none() {
+        return Err((StatusCode::NOT_FOUND, format!("user {} not found", user_id)));
+    }
+
+    write_roles(&tx, user_id, &roles).await?;
+    tx.commit().await.map_err(internal_error)?;
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "user_id": user_id,
+        "roles": roles.iter().map(|(role, group_id)| json!({ "role": role.as_str(), "group_id": group_id })).collect::<Vec<_>>()
+    })))
+}
+
+pub async fn audit_log(
+    State(pool): State<DbPool>,
+    Query(query): Query<AuditQuery>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let limit = query.limit.unwrap_or(DEFAULT_AUDIT_LIMIT).clamp(1, MAX_AUDIT_LIMIT);
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let rows = client
+        .query(
+            "SELECT id, fk_user_id, username, action, path, permission, status, created_at FROM audit_log
+             WHERE ($1::int IS NULL OR fk_user_id = $1)
+             ORDER BY created_at DESC, id DESC
+             LIMIT $2",
+            &[&query.user_id, &limit],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let entries: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| json!({
+            "id": row.get::<_, i64>("id"),
+            "user_id": row.get::<_, Option<i32>>("fk_user_id"),
+            "username": row.get::<_, Option<String>>("username"),
+            "action": row.get::<_, String>("action"),
+            "path": row.get::<_, Option<String>>("path"),
+            "permission": row.get::<_, Option<String>>("permission"),
+            "status": row.get::<_, i32>("status"),
+            "created_at": row.get::<_, chrono::DateTime<chrono::Utc>>("created_at").to_rfc3339(),
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "entries": entries,
+        "count": entries.len()
+    })))
+}
+
+pub async fn list_tokens(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let rows = client
+        .query(
+            "SELECT id, name, created_at, last_used_at FROM api_tokens
+             WHERE fk_user_id = $1 AND revoked_at IS NULL ORDER BY id",
+            &[&principal.user_id],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    let tokens: Vec<serde_json::Value> = rows
+        .iter()
+        .map(|row| json!({
+            "id": row.get::<_, i32>("id"),
+            "name": row.get::<_, String>("name"),
+            "created_at": row.get::<_, chrono::DateTime<chrono::Utc>>("created_at").to_rfc3339(),
+            "last_used_at": row.get::<_, Option<chrono::DateTime<chrono::Utc>>>("last_used_at").map(|t| t.to_rfc3339()),
+        }))
+        .collect();
+
+    Ok(JsonResponse(json!({
+        "status": "success",
+        "tokens": tokens,
+        "count": tokens.len()
+    })))
+}
+
+// the token is only ever shown in this response
+pub async fn create_token(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+    Json(input): Json<TokenInput>,
+) -> Result<(StatusCode, JsonResponse<serde_json::Value>), (StatusCode, String)> {
+    let name = input.name.trim();
+    if name.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "token name must not be empty".to_string()));
+    }
+
+    let token = format!("{}{}", auth::API_TOKEN_PREFIX, auth::generate_secret());
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let row = client
+        .query_one(
+            "INSERT INTO api_tokens (fk_user_id, name, token_hash) VALUES ($1, $2, $3) RETURNING id",
+            &[&principal.user_id, &name, &auth::hash_secret(&token)],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    Ok((StatusCode::CREATED, JsonResponse(json!({
+        "status": "success",
+        "token_id": row.get::<_, i32>("id"),
+        "token": token,
+        "read_only": true
+    }))))
+}
+
+pub async fn revoke_token(
+    State(pool): State<DbPool>,
+    Extension(principal): Extension<Principal>,
+    Path(id): Path<i32>,
+) -> Result<JsonResponse<serde_json::Value>, (StatusCode, String)> {
+    let client = pool.get().await.map_err(internal_error)?;
+
+    let revoked = client
+        .execute(
+            "UPDATE api_tokens SET revoked_at = NOW() WHERE id = $1 AND fk_user_id = $2 AND revoked_at IS NULL",
+            &[&id, &principal.user_id],
+        )
+        .await
+        .map_err(internal_error)?;
+
+    if revoked == 0 {
+        return Err((StatusCode::NOT_FOUND, format!("token {} not found", id)));
+    }
+
+    Ok(JsonResponse(json!({ "status": "success", "token_id": id })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::safe_next;
+
+    #[test]
+    fn next_keeps_same_site_paths() {
+        assert_eq!(safe_next("/"), "/");
+        assert_eq!(safe_next("/devices/4?range=day"), "/devices/4?range=day");
+    }
+
+    #[test]
+    fn next_rejects_other_sites() {
+        for next in ["", "x", "https://x", "//x", "/\\x", "\\/x", "/%5cx", "/%5Cx", "/\tx", "/x\r\nLocation: //x"] {
+            assert_eq!(safe_next(next), "/", "{:?}", next);
+        }
+    }
+}
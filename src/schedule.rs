@@ -0,0 +1,347 @@
+use chrono::{DateTime, Datelike, NaiveTime, Utc};
+use chrono_tz::Tz;
+use dashmap::DashMap;
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock};
+use crate::database::DbPool;
+
+#[derive(Clone, Debug)]
+pub struct SchedulePeriod {
+    pub name: String,
+    pub weekdays: Vec<i16>, // iso weekday numbers of the day the period starts on
+    pub start_time: NaiveTime,
+    pub end_time: NaiveTime,
+    pub limit_db: f64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    pub id: i32,
+    pub name: String,
+    pub timezone: Tz,
+    pub periods: Vec<SchedulePeriod>,
+}
+
+#[derive(Clone, Debug)]
+pub struct ApplicableLimit {
+    pub schedule_id: i32,
+    pub schedule: String,
+    pub period: String,
+    pub limit_db: f64,
+}
+
+// readings further apart than this are treated as a gap rather than continuous coverage
+pub const MAX_READING_GAP_SECONDS: f64 = 60.0;
+
+#[derive(Clone, Debug)]
+pub struct PeriodCompliance {
+    pub device_id: i32,
+    pub period: String,
+    pub limit_db: f64,
+    pub readings: i64,
+    pub max_db: f64,
+    pub seconds_total: f64,
+    pub seconds_over: f64,
+    pub events: i64,
+}
+
+impl PeriodCompliance {
+    pub fn fraction_over(&self) -> f64 {
+        if self.seconds_total > 0.0 { self.seconds_over / self.seconds_total } else { 0.0 }
+    }
+}
+
+// resolved schedule per device, device assignments win over group assignments
+static DEVICE_SCHEDULES: LazyLock<DashMap<i32, Arc<Schedule>>> = LazyLock::new(|| {
+    DashMap::new()
+});
+
+// sql condition matching a local timestamp `r.local_ts` against period `p`, mirrors `SchedulePeriod::contains`
+pub const PERIOD_MATCH_SQL: &str = "
+    CASE
+        WHEN p.start_time < p.end_time THEN
+            r.local_ts::time >= p.start_time AND r.local_ts::time < p.end_time
+            AND EXTRACT(ISODOW FROM r.local_ts)::smallint = ANY(p.weekdays)
+        WHEN p.start_time = p.end_time THEN
+            EXTRACT(ISODOW FROM r.local_ts)::smallint = ANY(p.weekdays)
+        ELSE
+            (r.local_ts::time >= p.start_time AND EXTRACT(ISODOW FROM r.local_ts)::smallint = ANY(p.weekdays))
+            OR (r.local_ts::time < p.end_time AND EXTRACT(ISODOW FROM r.local_ts - INTERVAL '1 day')::smallint = ANY(p.weekdays))
+    END";
+
+impl SchedulePeriod {
+    fn contains(&self, weekday: i16, time: NaiveTime) -> bool {
+        let previous_day = if weekday == 1 { 7 } else { weekday - 1 };
+
+        if self.start_time < self.end_time {
+            time >= self.start_time && time < self.end_time && self.weekdays.contains(&weekday)
+        } else if self.start_time == self.end_time {
+            // equal bounds cover the whole day
+            self.weekdays.contains(&weekday)
+        } else {
+            // period wraps past midnight, the early morning part belongs to the previous day
+            (time >= self.start_time && self.weekdays.contains(&weekday))
+                || (time < self.end_time && self.weekdays.contains(&previous_day))
+        }
+    }
+}
+
+impl Schedule {
+    // first matching period wins when periods overlap
+    pub fn period_at(&self, timestamp: DateTime<Utc>) -> Option<&SchedulePeriod> {
+        let local = timestamp.with_timezone(&self.timezone);
+        let weekday = local.weekday().number_from_monday() as i16;
+        let time = local.time();
+
+        self.periods.iter().find(|period| period.contains(weekday, time))
+    }
+}
+
+pub fn applicable_limit(device_id: i32, timestamp: DateTime<Utc>) -> Option<ApplicableLimit> {
+    let schedule = DEVICE_SCHEDULES.get(&device_id)?.value().clone();
+    let period = schedule.period_at(timestamp)?;
+
+    Some(ApplicableLimit {
+        schedule_id: schedule.id,
+        schedule: schedule.name.clone(),
+        period: period.name.clone(),
+        limit_db: period.limit_db,
+    })
+}
+
+pub fn schedule_for_device(device_id: i32) -> Option<Arc<Schedule>> {
+    DEVICE_SCHEDULES.get(&device_id).map(|entry| entry.value().clone())
+}
+
+pub fn devices_for_schedule(schedule_id: i32) -> Vec<i32> {
+    let mut devices: Vec<i32> = DEVICE_SCHEDULES
+        .iter()
+        .filter(|entry| entry.value().id == schedule_id)
+        .map(|entry| *entry.key())
+        .collect();
+    devices.sort_unstable();
+    devices
+}
+
+pub fn parse_timezone(name: &str) -> Option<Tz> {
+    name.parse::<Tz>().ok()
+}
+
+// time above the applicable limit and number of exceedance events per device and period
+pub async fn compliance(
+    client: &tokio_postgres::Client,
+    schedule_id: i32,
+    device_ids: &[i32],
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<PeriodCompliance>, tokio_postgres::Error> {
+    let sql = format!(
+        "WITH r AS (
+            SELECT l.fk_device_id, l.decibels, l.created_at,
+                   l.created_at AT TIME ZONE s.timezone AS local_ts,
+                   EXTRACT(EPOCH FROM LEAD(l.created_at) OVER w - l.created_at)::float8 AS seconds,
+                   EXTRACT(EPOCH FROM l.created_at - LAG(l.created_at) OVER w)::float8 AS gap
+            FROM decibel_logs l
+            JOIN noise_schedules s ON s.id = $1
+            WHERE l.fk_device_id = ANY($2) AND l.created_at >= $3 AND l.created_at < $4
+            WINDOW w AS (PARTITION BY l.fk_device_id ORDER BY l.created_at)
+        ), matched AS (
+            SELECT r.fk_device_id, r.decibels, r.created_at, r.gap, p.name AS period, p.limit_db,
+                   CASE WHEN r.seconds IS NULL THEN 0 ELSE LEAST(r.seconds, $5) END AS seconds,
+                   r.decibels > p.limit_db AS over
+            FROM r
+            JOIN LATERAL (
+                SELECT p.id, p.name, p.limit_db FROM noise_schedule_periods p
+                WHERE p.fk_schedule_id = $1 AND ({})
+                ORDER BY p.id LIMIT 1
+            ) p ON TRUE
+        ), flagged AS (
+            SELECT *, LAG(over) OVER w AS prev_over, LAG(period) OVER w AS prev_period
+            FROM matched
+            WINDOW w AS (PARTITION BY fk_device_id ORDER BY created_at)
+        )
+        SELECT fk_device_id, period, MAX(limit_db) AS limit_db, COUNT(*) AS readings, MAX(decibels) AS max_db,
+               COALESCE(SUM(seconds), 0) AS seconds_total,
+               COALESCE(SUM(seconds) FILTER (WHERE over), 0) AS seconds_over,
+               COUNT(*) FILTER (WHERE over AND (prev_over IS NOT TRUE OR gap > $5 OR prev_period IS DISTINCT FROM period)) AS events
+        FROM flagged
+        GROUP BY fk_device_id, period
+        ORDER BY fk_device_id, period",
+        PERIOD_MATCH_SQL
+    );
+
+    let rows = client
+        .query(&sql, &[&schedule_id, &device_ids, &from, &to, &MAX_READING_GAP_SECONDS])
+        .await?;
+
+    Ok(rows
+        .iter()
+        .map(|row| PeriodCompliance {
+            device_id: row.get("fk_device_id"),
+            period: row.get("period"),
+            limit_db: row.get("limit_db"),
+            readings: row.get("readings"),
+            max_db: row.get("max_db"),
+            seconds_total: row.get("seconds_total"),
+            seconds_over: row.get("seconds_over"),
+            events: row.get("events"),
+        })
+        .collect())
+}
+
+
+// reloads all schedules and device assignments from the database, called at startup and after every change
+pub async fn reload(pool: &DbPool) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let client = pool.get().await?;
+
+    let schedule_rows = client
+        .query("SELECT id, name, timezone FROM noise_schedules", &[])
+        .await?;
+
+    let period_rows = client
+        .query(
+            "SELECT fk_schedule_id, name, weekdays, start_time, end_time, limit_db
+             FROM noise_schedule_periods ORDER BY id",
+            &[],
+        )
+        .await?;
+
+    let assignment_rows = client
+        .query(
+            "SELECT DISTINCT ON (d.id) d.id AS device_id, a.fk_schedule_id
+             FROM devices d
+             JOIN noise_schedule_assignments a
+                 ON a.fk_device_id = d.id
+                 OR a.fk_group_id IN (SELECT fk_group_id FROM device_group_members WHERE fk_device_id = d.id)
+             WHERE d.deleted_at IS NULL
+             ORDER BY d.id, a.fk_device_id IS NULL, a.fk_group_id",
+            &[],
+        )
+        .await?;
+
+    let mut schedules: HashMap<i32, Schedule> = HashMap::new();
+    for row in schedule_rows {
+        let id: i32 = row.get("id");
+        let timezone_name: String = row.get("timezone");
+        let timezone = parse_timezone(&timezone_name).unwrap_or_else(|| {
+            tracing::warn!(schedule_id = id, timezone = %timezone_name, "schedule has unknown time zone, using utc");
+            Tz::UTC
+        });
+
+        schedules.insert(id, Schedule {
+            id,
+            name: row.get("name"),
+            timezone,
+            periods: Vec::new(),
+        });
+    }
+
+    for row in period_rows {
+        let schedule_id: i32 = row.get("fk_schedule_id");
+        if let Some(schedule) = schedules.get_mut(&schedule_id) {
+            schedule.periods.push(SchedulePeriod {
+                name: row.get("name"),
+                weekdays: row.get("weekdays"),
+                start_time: row.get("start_time"),
+                end_time: row.get("end_time"),
+                limit_db: row.get("limit_db"),
+            });
+        }
+    }
+
+    let schedules: HashMap<i32, Arc<Schedule>> = schedules
+        .into_iter()
+        .map(|(id, schedule)| (id, Arc::new(schedule)))
+        .collect();
+
+    DEVICE_SCHEDULES.clear();
+    for row in assignment_rows {
+        let device_id: i32 = row.get("device_id");
+        let schedule_id: i32 = row.get("fk_schedule_id");
+        if let Some(schedule) = schedules.get(&schedule_id) {
+            DEVICE_SCHEDULES.insert(device_id, schedule.clone());
+        }
+    }
+
+    Ok(())
+}
+
+// reload that only logs failures, used after changes to schedules, assignments or groups
+pub async fn refresh(pool: &DbPool) {
+    if let Err(e) = reload(pool).await {
+        tracing::error!(error = %e, "failed to reload noise schedules");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn period(weekdays: &[i16], start: (u32, u32), end: (u32, u32)) -> SchedulePeriod {
+        SchedulePeriod {
+            name: "test".to_string(),
+            weekdays: weekdays.to_vec(),
+            start_time: NaiveTime::from_hms_opt(start.0, start.1, 0).unwrap(),
+            end_time: NaiveTime::from_hms_opt(end.0, end.1, 0).unwrap(),
+            limit_db: 55.0,
+        }
+    }
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    #[test]
+    fn day_period_includes_start_and_excludes_end() {
+        let weekdays = period(&[1, 2, 3, 4, 5], (7, 0), (19, 0));
+        assert!(weekdays.contains(1, at(7, 0)));
+        assert!(weekdays.contains(5, at(18, 59)));
+        assert!(!weekdays.contains(5, at(19, 0)));
+        assert!(!weekdays.contains(3, at(6, 59)));
+        assert!(!weekdays.contains(6, at(12, 0)));
+    }
+
+    #[test]
+    fn period_wrapping_midnight_belongs_to_the_day_it_starts() {
+        // friday and saturday nights, 23:00 to 07:00
+        let nights = period(&[5, 6], (23, 0), (7, 0));
+        assert!(nights.contains(5, at(23, 0)));
+        assert!(nights.contains(6, at(6, 59)));
+        assert!(nights.contains(7, at(3, 0)));
+        assert!(!nights.contains(7, at(7, 0)));
+        assert!(!nights.contains(7, at(23, 30)));
+        assert!(!nights.contains(5, at(3, 0)));
+    }
+
+    #[test]
+    fn sunday_night_continues_into_monday() {
+        let sunday = period(&[7], (22, 0), (6, 0));
+        assert!(sunday.contains(7, at(22, 0)));
+        assert!(sunday.contains(1, at(5, 59)));
+        assert!(!sunday.contains(1, at(22, 0)));
+        assert!(!sunday.contains(7, at(5, 0)));
+    }
+
+    #[test]
+    fn equal_bounds_cover_the_whole_day() {
+        let weekend = period(&[6, 7], (0, 0), (0, 0));
+        assert!(weekend.contains(6, at(0, 0)));
+        assert!(weekend.contains(7, at(23, 59)));
+        assert!(!weekend.contains(1, at(0, 0)));
+    }
+
+    #[test]
+    fn period_at_uses_the_schedule_time_zone() {
+        let schedule = Schedule {
+            id: 1,
+            name: "test".to_string(),
+            timezone: "Europe/Berlin".parse().unwrap(),
+            periods: vec![period(&[1, 2, 3, 4, 5, 6, 7], (22, 0), (6, 0))],
+        };
+        // 21:30 utc is 23:30 in berlin during summer time
+        let night = "2026-07-06T21:30:00Z".parse().unwrap();
+        let day = "2026-07-06T12:00:00Z".parse().unwrap();
+        assert!(schedule.period_at(night).is_some());
+        assert!(schedule.period_at(day).is_none());
+    }
+}
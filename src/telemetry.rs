@@ -0,0 +1,184 @@
+use axum::http::{HeaderMap, HeaderValue, Method, StatusCode};
+use metrics::{counter, describe_counter, describe_gauge, describe_histogram, gauge, Unit};
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::fmt;
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::EnvFilter;
+use crate::cache;
+use crate::config;
+use crate::database::{self, DbPool};
+use crate::websocket;
+
+pub const REQUESTS: &str = "dbmonitor_http_requests_total";
+pub const REQUEST_DURATION: &str = "dbmonitor_http_request_duration_seconds";
+pub const READINGS: &str = "dbmonitor_readings_ingested_total";
+pub const BATCH_SIZE: &str = "dbmonitor_insert_batch_size";
+pub const FLUSH_DURATION: &str = "dbmonitor_insert_flush_duration_seconds";
+pub const INSERT_FAILURES: &str = "dbmonitor_insert_failures_total";
+
+const LATENCY_BUCKETS: &[f64] = &[0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+static PROMETHEUS: OnceLock<PrometheusHandle> = OnceLock::new();
+
+pub const REQUEST_ID_HEADER: &str = "x-request-id";
+const INGEST_ROUTE: &str = "/api/logs";
+
+static INGEST_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+// logging.level takes tracing directives (`info,dbmonitor::cache=debug`), logging.format = "json" switches
+// from the human readable output to one json object per line. admin commands log to stderr so their
+// output can be piped
+pub fn init_logging(stderr: bool) {
+    let settings = &config::get().logging;
+    let filter = EnvFilter::try_new(&settings.level).unwrap_or_else(|_| EnvFilter::new("info"));
+    let (writer, ansi) = if stderr {
+        (BoxMakeWriter::new(std::io::stderr), std::io::stderr().is_terminal())
+    } else {
+        (BoxMakeWriter::new(std::io::stdout), std::io::stdout().is_terminal())
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(ansi);
+    match settings.format.as_str() {
+        "json" => builder.json().flatten_event(true).with_current_span(true).with_span_list(false).init(),
+        _ => builder.init(),
+    }
+}
+
+// the id of one http request, kept from a sane incoming X-Request-Id or generated
+#[derive(Clone, Debug)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let incoming = headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| {
+                (1..=64).contains(&id.len())
+                    && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+            });
+        match incoming {
+            Some(id) => RequestId(id.into()),
+            None => RequestId(format!("{:016x}", rand::random::<u64>()).into()),
+        }
+    }
+
+    pub fn header_value(&self) -> HeaderValue {
+        HeaderValue::from_str(&self.0).expect("request ids are validated ascii")
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+// successful ingestion is by far the most frequent request and only sampled, everything else is logged
+pub fn should_log_request(method: &Method, route: &str, status: StatusCode) -> bool {
+    if method != Method::POST || route != INGEST_ROUTE || !status.is_success() {
+        return true;
+    }
+    // logging.ingest_sample = n logs one in n successful readings, 1 logs all of them and 0 none
+    match config::get().logging.ingest_sample {
+        0 => false,
+        every => INGEST_REQUESTS.fetch_add(1, Ordering::Relaxed).is_multiple_of(every),
+    }
+}
+
+// 1, 2, 5, 10, 20, 50, ... up to ingest.batch_size, which is the last bucket so full batches stand out
+fn batch_buckets() -> Vec<f64> {
+    let batch_size = config::get().ingest.batch_size as f64;
+    let mut buckets: Vec<f64> = [1.0, 2.0, 5.0]
+        .into_iter()
+        .cycle()
+        .enumerate()
+        .map(|(i, step)| step * 10f64.powi(i as i32 / 3))
+        .take_while(|bucket| *bucket < batch_size)
+        .collect();
+    buckets.push(batch_size);
+    buckets
+}
+
+// installs the global recorder, metrics recorded before this are lost
+pub fn init() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Full(REQUEST_DURATION.to_string()), LATENCY_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full(FLUSH_DURATION.to_string()), LATENCY_BUCKETS)?
+        .set_buckets_for_metric(Matcher::Full(BATCH_SIZE.to_string()), &batch_buckets())?
+        .install_recorder()?;
+
+    describe_counter!(REQUESTS, "HTTP requests by method, route and status");
+    describe_histogram!(REQUEST_DURATION, Unit::Seconds, "HTTP request latency by method, route and status");
+    describe_counter!(READINGS, "Readings accepted on POST /api/logs by device");
+    describe_histogram!(BATCH_SIZE, "Rows per decibel_logs insert batch");
+    describe_histogram!(FLUSH_DURATION, Unit::Seconds, "Time to write one insert batch, connection checkout included");
+    describe_counter!(INSERT_FAILURES, "Readings lost or delayed by failed inserts, by reason");
+    describe_gauge!("dbmonitor_insert_queue_depth", "Readings waiting to be written to decibel_logs");
+    describe_gauge!("dbmonitor_active_devices", "Devices in the reading cache");
+    describe_gauge!("dbmonitor_websocket_clients", "Connected websocket and server-sent events clients");
+    describe_counter!("dbmonitor_websocket_lagged_total", "Times a client fell behind and was resynced");
+    describe_counter!("dbmonitor_websocket_skipped_messages_total", "Messages skipped for lagging clients");
+    describe_counter!("dbmonitor_websocket_dropped_clients_total", "Clients dropped after a send or pong timeout");
+    describe_gauge!("dbmonitor_db_pool_connections", "Connections held by the database pool");
+    describe_gauge!("dbmonitor_db_pool_idle_connections", "Idle connections in the database pool");
+    describe_gauge!("dbmonitor_db_pool_max_connections", "Database pool size limit");
+    describe_counter!("dbmonitor_db_pool_gets_total", "Connection checkouts, by whether they had to wait");
+    describe_counter!("dbmonitor_db_pool_get_timeouts_total", "Connection checkouts that timed out");
+    describe_counter!("dbmonitor_db_pool_wait_milliseconds_total", Unit::Milliseconds, "Time spent waiting for a connection");
+
+    // histograms are only drained into their buckets during upkeep
+    let upkeep = handle.clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(std::time::Duration::from_secs(5));
+        loop {
+            interval.tick().await;
+            upkeep.run_upkeep();
+        }
+    });
+
+    PROMETHEUS.set(handle).map_err(|_| "metrics recorder already installed")?;
+    Ok(())
+}
+
+// state that is read at scrape time rather than recorded as it changes
+async fn sample(pool: &DbPool) {
+    gauge!("dbmonitor_insert_queue_depth").set(cache::queue_depth() as f64);
+    gauge!("dbmonitor_active_devices").set(cache::cache_size().await as f64);
+
+    let ws = websocket::stats();
+    gauge!("dbmonitor_websocket_clients").set(ws.connected_clients as f64);
+    counter!("dbmonitor_websocket_lagged_total").absolute(ws.lagged_clients);
+    counter!("dbmonitor_websocket_skipped_messages_total").absolute(ws.skipped_messages);
+    counter!("dbmonitor_websocket_dropped_clients_total").absolute(ws.dropped_clients);
+
+    let database = &config::get().database;
+    sample_pool("api", pool, database.max_connections);
+    if let Some(ingest) = database::ingest_pool() {
+        sample_pool("ingest", ingest, database.ingest_max_connections);
+    }
+}
+
+fn sample_pool(name: &'static str, pool: &DbPool, max_connections: u32) {
+    let state = pool.state();
+    gauge!("dbmonitor_db_pool_connections", "pool" => name).set(state.connections as f64);
+    gauge!("dbmonitor_db_pool_idle_connections", "pool" => name).set(state.idle_connections as f64);
+    gauge!("dbmonitor_db_pool_max_connections", "pool" => name).set(max_connections as f64);
+    counter!("dbmonitor_db_pool_gets_total", "pool" => name, "waited" => "false").absolute(state.statistics.get_direct);
+    counter!("dbmonitor_db_pool_gets_total", "pool" => name, "waited" => "true").absolute(state.statistics.get_waited);
+    counter!("dbmonitor_db_pool_get_timeouts_total", "pool" => name).absolute(state.statistics.get_timed_out);
+    counter!("dbmonitor_db_pool_wait_milliseconds_total", "pool" => name)
+        .absolute(state.statistics.get_wait_time.as_millis() as u64);
+}
+
+// prometheus text exposition of everything recorded so far
+pub async fn render(pool: &DbPool) -> Option<String> {
+    let handle = PROMETHEUS.get()?;
+    sample(pool).await;
+    Some(handle.render())
+}
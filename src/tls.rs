@@ -0,0 +1,227 @@
+use axum::extract::connect_info::Connected;
+use axum::serve::{IncomingStream, Listener};
+use notify::{EventKind, RecursiveMode, Watcher};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use rustls::{RootCertStore, ServerConfig};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::mpsc;
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+use crate::config::{self, TlsConfig};
+
+// a client that connects and never finishes the handshake only holds its own task
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// certificate tools write the key and the chain one after the other, reload once both are there
+const RELOAD_DELAY: Duration = Duration::from_millis(500);
+const ACCEPT_BACKLOG: usize = 128;
+
+// the connection a request arrived on, device_id is set when a verified client certificate names a device
+#[derive(Clone, Debug)]
+pub struct TlsPeer {
+    pub device_id: Option<i32>,
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for TlsPeer {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        stream.remote_addr().clone()
+    }
+}
+
+// hands out connections that finished their handshake, handshakes run in their own tasks
+pub struct TlsListener {
+    connections: mpsc::Receiver<(TlsStream<TcpStream>, TlsPeer)>,
+}
+
+impl Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = TlsPeer;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.connections.recv().await {
+            Some(connection) => connection,
+            // the accept loop never returns, so this only happens while shutting down
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(TlsPeer { device_id: None })
+    }
+}
+
+// binds the address and starts serving tls with the configured certificates, reloaded when their files
+// change or on SIGHUP
+pub async fn bind(addr: SocketAddr) -> Result<TlsListener, Box<dyn std::error::Error + Send + Sync>> {
+    let settings = &config::get().tls;
+    let current = Arc::new(RwLock::new(load(settings)?));
+    let listener = TcpListener::bind(addr).await?;
+
+    tokio::spawn(reload_certificates(current.clone()));
+
+    let (tx, connections) = mpsc::channel(ACCEPT_BACKLOG);
+    tokio::spawn(accept_loop(listener, current, tx));
+    Ok(TlsListener { connections })
+}
+
+async fn accept_loop(
+    listener: TcpListener,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+    connections: mpsc::Sender<(TlsStream<TcpStream>, TlsPeer)>,
+) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(e) => {
+                // usually out of file descriptors, give open connections a moment to close
+                tracing::error!(error = %e, "accepting a connection failed");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+
+        let acceptor = TlsAcceptor::from(current.read().expect("tls config lock poisoned").clone());
+        let connections = connections.clone();
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::debug!(%addr, error = %e, "tls handshake failed");
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!(%addr, "tls handshake timed out");
+                    return;
+                }
+            };
+            let device_id = client_device(&stream);
+            let _ = connections.send((stream, TlsPeer { device_id })).await;
+        });
+    }
+}
+
+// the device named by the client certificate, which the verifier already checked against tls.client_ca
+fn client_device(stream: &TlsStream<TcpStream>) -> Option<i32> {
+    let cert = stream.get_ref().1.peer_certificates()?.first()?;
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+    common_name
+        .strip_prefix(&config::get().tls.client_cert_prefix)?
+        .parse()
+        .ok()
+}
+
+fn load(settings: &TlsConfig) -> Result<Arc<ServerConfig>, String> {
+    let (Some(cert_path), Some(key_path)) = (&settings.cert, &settings.key) else {
+        return Err("tls.cert and tls.key must be set".to_string());
+    };
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("reading {}: {}", cert_path.display(), e))?;
+    if certs.is_empty() {
+        return Err(format!("no certificates in {}", cert_path.display()));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| format!("reading {}: {}", key_path.display(), e))?;
+
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| e.to_string())?;
+    let builder = match &settings.client_ca {
+        Some(ca_path) => {
+            let mut roots = RootCertStore::empty();
+            let cas = CertificateDer::pem_file_iter(ca_path)
+                .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| format!("reading {}: {}", ca_path.display(), e))?;
+            for ca in cas {
+                roots.add(ca).map_err(|e| format!("{}: {}", ca_path.display(), e))?;
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+            // browsers and token devices connect without a certificate unless it is required
+            let verifier = if settings.require_client_cert { verifier } else { verifier.allow_unauthenticated() };
+            builder.with_client_cert_verifier(verifier.build().map_err(|e| e.to_string())?)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder
+        .with_single_cert(certs, key)
+        .map_err(|e| format!("{}: {}", cert_path.display(), e))?;
+    // axum is built without http2, and websockets need http/1.1 anyway
+    server_config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(Arc::new(server_config))
+}
+
+// a failed reload keeps the certificates in use, connections that are already open are not touched
+async fn reload_certificates(current: Arc<RwLock<Arc<ServerConfig>>>) {
+    let settings = &config::get().tls;
+    let files: Vec<&Path> = [&settings.cert, &settings.key, &settings.client_ca]
+        .into_iter()
+        .flatten()
+        .map(|path| path.as_path())
+        .collect();
+
+    // renewals usually replace files or symlinks, so the directories are watched rather than the files
+    let names: HashSet<_> = files.iter().filter_map(|path| path.file_name()).map(|name| name.to_owned()).collect();
+    let (changed_tx, mut changed) = mpsc::unbounded_channel();
+    let watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        // reading the files reports access events, reloading on those would never stop
+        if let Ok(event) = event
+            && !matches!(event.kind, EventKind::Access(_))
+            && event.paths.iter().any(|path| path.file_name().is_some_and(|name| names.contains(name)))
+        {
+            let _ = changed_tx.send(());
+        }
+    });
+    let _watcher = match watcher {
+        Ok(mut watcher) => {
+            let dirs: HashSet<&Path> = files
+                .iter()
+                .map(|path| path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new(".")))
+                .collect();
+            for dir in dirs {
+                if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                    tracing::warn!(dir = %dir.display(), error = %e, "cannot watch certificate directory");
+                }
+            }
+            Some(watcher)
+        }
+        Err(e) => {
+            tracing::warn!(error = %e, "certificate file watching unavailable, reload with SIGHUP");
+            None
+        }
+    };
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => Some(hangup),
+        Err(e) => {
+            tracing::warn!(error = %e, "cannot listen for SIGHUP");
+            None
+        }
+    };
+
+    loop {
+        let reason = tokio::select! {
+            Some(()) = changed.recv() => "file change",
+            Some(()) = async { hangup.as_mut()?.recv().await } => "SIGHUP",
+            else => return,
+        };
+        tokio::time::sleep(RELOAD_DELAY).await;
+        while changed.try_recv().is_ok() {}
+
+        match load(settings) {
+            Ok(server_config) => {
+                *current.write().expect("tls config lock poisoned") = server_config;
+                tracing::info!(reason, "tls certificates reloaded");
+            }
+            Err(e) => tracing::error!(reason, error = %e, "tls reload failed, keeping the previous certificates"),
+        }
+    }
+}
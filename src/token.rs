@@ -1,15 +1,24 @@
+use dashmap::DashMap;
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use std::sync::LazyLock;
-use std::env;
+use std::time::{Duration, Instant};
+use crate::config;
+use crate::database::DbPool;
+
+// tokens never expire, deleting the device is what revokes them. the answer is cached so readings do not
+// cost a query each, `dbmonitor device delete` runs in another process and takes effect within this long
+const DEVICE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 static SECRET_KEY: LazyLock<String> = LazyLock::new(|| {
-    env::var("DEVICE_TOKEN_SECRET").unwrap_or_else(|_| {
-        eprintln!("DEVICE_TOKEN_SECRET not set, using insecure default key");
+    config::get().auth.device_token_secret.clone().unwrap_or_else(|| {
+        tracing::warn!("auth.device_token_secret (DEVICE_TOKEN_SECRET) not set, using insecure default key");
         "69420".to_string()
     })
 });
 
+static DEVICE_CHECKS: LazyLock<DashMap<i32, (bool, Instant)>> = LazyLock::new(DashMap::new);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Claims {
     pub device_id: i32,
@@ -34,4 +43,33 @@ pub fn verify_token(token: &str) -> Result<Claims, jsonwebtoken::errors::Error>
         &validation,
     )?;
     Ok(token_data.claims)
-} 
\ No newline at end of file
+} 
+// whether the device exists and is not deleted, None when the database cannot be asked and the device
+// was never checked. a failed check keeps the previous answer so an outage does not stop ingestion
+pub async fn device_active(pool: &DbPool, device_id: i32) -> Option<bool> {
+    let cached = DEVICE_CHECKS.get(&device_id).map(|entry| *entry.value());
+    if let Some((active, checked_at)) = cached
+        && checked_at.elapsed() < DEVICE_CHECK_INTERVAL
+    {
+        return Some(active);
+    }
+
+    let checked = match pool.get().await {
+        Ok(client) => client
+            .query_opt("SELECT 1 FROM devices WHERE id = $1 AND deleted_at IS NULL", &[&device_id])
+            .await
+            .map(|row| row.is_some())
+            .map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    };
+    match checked {
+        Ok(active) => {
+            DEVICE_CHECKS.insert(device_id, (active, Instant::now()));
+            Some(active)
+        }
+        Err(e) => {
+            tracing::warn!(device_id, error = %e, "cannot check whether the device is deleted");
+            cached.map(|(active, _)| active)
+        }
+    }
+}
@@ -1,19 +1,38 @@
+mod protocol;
+mod replay;
+mod sse;
+
 use axum::{
     extract::{
         ws::{Message, WebSocket, WebSocketUpgrade},
-        State,
+        Query, State,
     },
-    response::Response,
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
 };
 use futures_util::{sink::SinkExt, stream::StreamExt};
-use tokio::sync::broadcast;
+use tokio::sync::{broadcast, mpsc, watch};
+use crate::alerts;
+use crate::auth::{self, Permission, Scope};
+use crate::config;
 use crate::database::DbPool;
-use std::collections::HashMap;
+use crate::dose;
+use crate::events::NoiseEvent;
+use crate::schedule;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
 use tokio::sync::RwLock;
-use std::sync::LazyLock;
+use std::sync::{Arc, LazyLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
 use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+pub use replay::cleanup_old_entries;
+pub use sse::stream_handler;
+
+use protocol::{Alert, DeviceState, DoseInfo, LimitInfo, OutputFormat, WsMessage, WsUpdate, PROTOCOL_VERSION, SUBPROTOCOL_HTML, SUBPROTOCOL_JSON};
 
-static BROADCAST: LazyLock<broadcast::Sender<String>> = 
+static BROADCAST: LazyLock<broadcast::Sender<Arc<WsUpdate>>> = 
     LazyLock::new(|| {
         let (tx, _) = broadcast::channel(1000);
         
@@ -22,6 +41,40 @@ static BROADCAST: LazyLock<broadcast::Sender<String>> =
         tx
     });
 
+// a client whose socket does not accept a message within this time is disconnected
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+// pings keep idle connections open through proxies, a client silent for PONG_TIMEOUT is dropped
+const PING_INTERVAL: Duration = Duration::from_secs(20);
+const PONG_TIMEOUT: Duration = Duration::from_secs(60);
+
+// newest reading per device, sent as a resync snapshot to clients that fell behind
+static LATEST_READINGS: LazyLock<DashMap<i32, Arc<WsUpdate>>> = LazyLock::new(|| {
+    DashMap::new()
+});
+
+static CONNECTED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+static LAGGED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+static SKIPPED_MESSAGES: AtomicU64 = AtomicU64::new(0);
+static DROPPED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Clone, Copy, Debug)]
+pub struct WsStats {
+    pub connected_clients: u64,
+    pub lagged_clients: u64,
+    pub skipped_messages: u64,
+    pub dropped_clients: u64,
+}
+
+// lagged counts every time a client fell behind and was resynced, dropped counts send or pong timeouts
+pub fn stats() -> WsStats {
+    WsStats {
+        connected_clients: CONNECTED_CLIENTS.load(Ordering::Relaxed),
+        lagged_clients: LAGGED_CLIENTS.load(Ordering::Relaxed),
+        skipped_messages: SKIPPED_MESSAGES.load(Ordering::Relaxed),
+        dropped_clients: DROPPED_CLIENTS.load(Ordering::Relaxed),
+    }
+}
+
 #[derive(Clone, Debug)]
 struct ThrottledReading {
     device_id: i32,
@@ -32,9 +85,13 @@ struct ThrottledReading {
 static PENDING_READINGS: LazyLock<RwLock<HashMap<i32, ThrottledReading>>> = 
     LazyLock::new(|| RwLock::new(HashMap::new()));
 
-fn start_throttling_processor(sender: broadcast::Sender<String>) {
+fn start_throttling_processor(sender: broadcast::Sender<Arc<WsUpdate>>) {
     tokio::spawn(async move {
-        let mut interval = tokio::time::interval(std::time::Duration::from_millis(100));
+        let settings = &config::get().websocket;
+        let mut interval = tokio::time::interval(std::time::Duration::from_millis(settings.throttle_ms));
+        // last reading time of devices reported online, and when devices over their limit last signalled it
+        let mut online: HashMap<i32, DateTime<Utc>> = HashMap::new();
+        let mut over_limit: HashMap<i32, DateTime<Utc>> = HashMap::new();
         
         loop {
             interval.tick().await;
@@ -48,32 +105,98 @@ fn start_throttling_processor(sender: broadcast::Sender<String>) {
             
             // send updates for all devices that had readings in this window
             for reading in readings {
+                let device_id = reading.device_id;
                 let timestamp = reading.timestamp;
                 
-                // current reading oob swap, updates display
-                let current_reading_fragment = format!(r#"
-                    <div id="current-decibels" class="text-6xl font-bold text-primary mb-2" hx-swap-oob="true" data-decibels="{:.1}" data-timestamp="{}" data-device-id="{}">{:.1}</div>"#, 
-                    reading.decibels, timestamp.to_rfc3339(), reading.device_id, reading.decibels);
+                if online.insert(device_id, timestamp).is_none() {
+                    replay::publish(&sender, WsMessage::DeviceStatus {
+                        device_id,
+                        status: DeviceState::Online,
+                        last_seen: timestamp,
+                    });
+                }
                 
-                // chart data oob fragment, hidden element with data for chart updates
-                let chart_data_fragment = format!(r#"
-                    <div id="chart-update" hx-swap-oob="true" 
-                        data-decibels="{:.1}" 
-                        data-timestamp="{}" 
-                        data-device-id="{}" 
-                        style="display:none">
-                    </div>"#, 
-                    reading.decibels, timestamp.to_rfc3339(), reading.device_id);
+                let applicable = schedule::applicable_limit(device_id, timestamp);
                 
-                // combine fragments
-                let combined_message = format!("{}\n{}", current_reading_fragment, chart_data_fragment);
+                // the alert writer decides whether a crossing is a new alert or part of an open one
+                match &applicable {
+                    Some(limit) if reading.decibels > limit.limit_db => {
+                        let crossing = !over_limit.contains_key(&device_id);
+                        let due = over_limit
+                            .get(&device_id)
+                            .is_none_or(|last| (timestamp - *last).num_seconds() >= alerts::REFRESH_SECONDS);
+                        if due {
+                            over_limit.insert(device_id, timestamp);
+                            alerts::record(alerts::LimitSignal {
+                                device_id,
+                                timestamp,
+                                decibels: reading.decibels,
+                                limit: limit.clone(),
+                                crossing,
+                            }).await;
+                        }
+                    }
+                    _ => {
+                        over_limit.remove(&device_id);
+                    }
+                }
                 
-                let _ = sender.send(combined_message);
+                let limit = applicable.map(|limit| LimitInfo::new(limit, reading.decibels));
+                let update = replay::publish(&sender, WsMessage::Reading {
+                    device_id,
+                    decibels: reading.decibels,
+                    timestamp,
+                    limit,
+                    dose: dose::live_dose(device_id).map(DoseInfo::from),
+                });
+                LATEST_READINGS.insert(device_id, update);
             }
+            
+            // a device is reported offline once it has been silent for websocket.device_offline_seconds
+            let cutoff = Utc::now() - chrono::Duration::seconds(settings.device_offline_seconds);
+            online.retain(|&device_id, &mut last_seen| {
+                if last_seen > cutoff {
+                    return true;
+                }
+                over_limit.remove(&device_id);
+                LATEST_READINGS.remove(&device_id);
+                replay::publish(&sender, WsMessage::DeviceStatus {
+                    device_id,
+                    status: DeviceState::Offline,
+                    last_seen,
+                });
+                false
+            });
         }
     });
 }
 
+// a new, unsilenced alert from the alert writer
+pub fn broadcast_limit_alert(alert_id: i32, signal: &alerts::LimitSignal) {
+    replay::publish(&BROADCAST, WsMessage::Alert {
+        device_id: signal.device_id,
+        alert: Alert::LimitExceeded {
+            alert_id,
+            timestamp: signal.timestamp,
+            decibels: signal.decibels,
+            limit: LimitInfo::new(signal.limit.clone(), signal.decibels),
+        },
+    });
+}
+
+// an alert was acknowledged or a silence changed, alert panels reload. none for every device
+pub fn broadcast_alerts_changed(device_id: Option<i32>) {
+    replay::publish(&BROADCAST, WsMessage::AlertsChanged { device_id });
+}
+
+// events are rare, so they skip the throttle and go out immediately
+pub fn broadcast_event(event: &NoiseEvent) {
+    replay::publish(&BROADCAST, WsMessage::Alert {
+        device_id: event.device_id,
+        alert: Alert::from(event),
+    });
+}
+
 pub async fn broadcast_reading_update(decibels: f64, device_id: i32) {
     let timestamp = chrono::Utc::now();
     
@@ -88,33 +211,276 @@ pub async fn broadcast_reading_update(decibels: f64, device_id: i32) {
     pending.insert(device_id, reading);
 }
 
+// sockets receive every device until they subscribe to specific devices or groups
+#[derive(Clone, Debug)]
+enum Subscription {
+    All,
+    Devices(HashSet<i32>),
+}
+
+impl Subscription {
+    fn includes(&self, device_id: i32) -> bool {
+        match self {
+            Subscription::All => true,
+            Subscription::Devices(devices) => devices.contains(&device_id),
+        }
+    }
+
+    // site scoped users only ever subscribe to the devices of their sites
+    fn restrict(&mut self, scope: &Scope) {
+        match (&mut *self, scope.device_filter()) {
+            (_, None) => {}
+            (Subscription::All, Some(allowed)) => *self = Subscription::Devices(allowed.into_iter().collect()),
+            (Subscription::Devices(devices), Some(_)) => devices.retain(|id| scope.allows_device(*id)),
+        }
+    }
+
+    fn to_message(&self) -> WsMessage {
+        match self {
+            Subscription::All => WsMessage::Subscription { all: true, devices: Vec::new() },
+            Subscription::Devices(devices) => {
+                let mut devices: Vec<i32> = devices.iter().copied().collect();
+                devices.sort_unstable();
+                WsMessage::Subscription { all: false, devices }
+            }
+        }
+    }
+}
+
+// `{"action": "subscribe" | "unsubscribe", "devices": [1, 2], "groups": [3]}`,
+// `"all": true` subscribes to every device again, or unsubscribes from everything.
+// `{"action": "resume", "seq": 123}` replays what the subscribed devices sent after `seq`
+#[derive(Deserialize)]
+struct ClientMessage {
+    action: String,
+    #[serde(default)]
+    devices: Vec<i32>,
+    #[serde(default)]
+    groups: Vec<i32>,
+    #[serde(default)]
+    all: bool,
+    seq: Option<u64>,
+}
+
+async fn resolve_group_devices(pool: &DbPool, groups: &[i32]) -> Vec<i32> {
+    if groups.is_empty() {
+        return Vec::new();
+    }
+
+    let client = match pool.get().await {
+        Ok(conn) => conn,
+        Err(e) => {
+            tracing::error!(error = %e, "failed to get database connection for websocket subscription");
+            return Vec::new();
+        }
+    };
+
+    match client
+        .query("SELECT fk_device_id FROM device_group_members WHERE fk_group_id = ANY($1)", &[&groups])
+        .await
+    {
+        Ok(rows) => rows.iter().map(|row| row.get("fk_device_id")).collect(),
+        Err(e) => {
+            tracing::error!(error = %e, "database query error");
+            Vec::new()
+        }
+    }
+}
+
+fn reply(message: WsMessage) -> Vec<Arc<WsUpdate>> {
+    vec![Arc::new(WsUpdate::new(message))]
+}
+
+// applies a subscription change or resume and returns the replies for the client
+async fn apply_client_message(
+    text: &str,
+    subscription: &watch::Sender<Subscription>,
+    scope: &Scope,
+    pool: &DbPool,
+) -> Vec<Arc<WsUpdate>> {
+    let message = match serde_json::from_str::<ClientMessage>(text) {
+        Ok(message) => message,
+        Err(e) => return reply(WsMessage::Error { message: format!("invalid message: {}", e) }),
+    };
+
+    if message.action == "resume" {
+        let Some(since) = message.seq else {
+            return reply(WsMessage::Error { message: "resume needs the last seen seq".to_string() });
+        };
+        let current = subscription.borrow().clone();
+        return replay::resume(pool, &current, since).await;
+    }
+
+    if message.action != "subscribe" && message.action != "unsubscribe" {
+        return reply(WsMessage::Error { message: format!("unknown action: {}", message.action) });
+    }
+
+    let mut devices: HashSet<i32> = message.devices.into_iter().collect();
+    devices.extend(resolve_group_devices(pool, &message.groups).await);
+
+    subscription.send_modify(|current| {
+        match message.action.as_str() {
+            "subscribe" if message.all => *current = Subscription::All,
+            "subscribe" => match current {
+                Subscription::All => *current = Subscription::Devices(devices),
+                Subscription::Devices(existing) => existing.extend(devices),
+            },
+            "unsubscribe" if message.all => *current = Subscription::Devices(HashSet::new()),
+            _ => match current {
+                Subscription::All => {}
+                Subscription::Devices(existing) => existing.retain(|id| !devices.contains(id)),
+            },
+        }
+        current.restrict(scope);
+    });
+
+    reply(subscription.borrow().to_message())
+}
+
+#[derive(Deserialize)]
+pub struct WsParams {
+    format: Option<String>,
+    v: Option<u32>,
+    access_token: Option<String>,
+}
+
+// the subprotocol wins over the query parameters, the dashboard connects without either and gets html.
+// the dashboard authenticates with its session cookie, scripts with an api token
 pub async fn websocket_handler(
     ws: WebSocketUpgrade,
-    State(_pool): State<DbPool>,
+    Query(params): Query<WsParams>,
+    headers: HeaderMap,
+    State(pool): State<DbPool>,
 ) -> Response {
-    ws.on_upgrade(handle_socket)
+    let Some(principal) = auth::authenticate(&pool, &headers, params.access_token.as_deref()).await else {
+        return (StatusCode::UNAUTHORIZED, "login required").into_response();
+    };
+    let scope = principal.scope(Permission::ViewData);
+    if matches!(scope, Scope::None) {
+        return (StatusCode::FORBIDDEN, "missing permission view_data").into_response();
+    }
+
+    let ws = ws.protocols([SUBPROTOCOL_JSON, SUBPROTOCOL_HTML]);
+
+    let format = match ws.selected_protocol().and_then(|p| p.to_str().ok()) {
+        Some(protocol) => OutputFormat::from_subprotocol(protocol).unwrap_or(OutputFormat::Html),
+        None => {
+            if let Some(v) = params.v && v != PROTOCOL_VERSION {
+                return (StatusCode::BAD_REQUEST, format!("unsupported protocol version {}, expected {}", v, PROTOCOL_VERSION)).into_response();
+            }
+            match params.format.as_deref().map(OutputFormat::from_query) {
+                None => OutputFormat::Html,
+                Some(Some(format)) => format,
+                Some(None) => return (StatusCode::BAD_REQUEST, "format must be html or json").into_response(),
+            }
+        }
+    };
+
+    ws.on_upgrade(move |socket| handle_socket(socket, pool, format, scope))
 }
 
-async fn handle_socket(socket: WebSocket) {
+// newest reading of every subscribed device, replaces whatever the client missed while lagging
+fn resync_snapshot(subscription: &Subscription, skipped: u64) -> WsMessage {
+    let mut readings: Vec<WsMessage> = LATEST_READINGS
+        .iter()
+        .filter(|entry| subscription.includes(*entry.key()))
+        .map(|entry| entry.value().message.clone())
+        .collect();
+    readings.sort_by_key(|reading| reading.device_id());
+
+    WsMessage::Resync { skipped, readings }
+}
+
+async fn handle_socket(socket: WebSocket, pool: DbPool, format: OutputFormat, scope: Scope) {
     let (mut sender, mut receiver) = socket.split();
     
     let mut rx = BROADCAST.subscribe();
+    let mut initial = Subscription::All;
+    initial.restrict(&scope);
+    let (subscription_tx, subscription_rx) = watch::channel(initial);
+    // replies to this client only, subscription acks and errors
+    let (reply_tx, mut reply_rx) = mpsc::unbounded_channel::<Arc<WsUpdate>>();
+    
+    // milliseconds since `connected` at which the client last sent anything, pongs included
+    let connected = Instant::now();
+    let last_seen = Arc::new(AtomicU64::new(0));
+    
+    CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+    
+    if format == OutputFormat::Json {
+        let _ = reply_tx.send(Arc::new(WsUpdate::new(WsMessage::Hello { format: "json" })));
+    }
     
+    let send_last_seen = last_seen.clone();
     let mut send_task = tokio::spawn(async move {
-        while let Ok(msg) = rx.recv().await {
-            if sender.send(Message::Text(msg.into())).await.is_err() {
-                break;
+        let mut ping = tokio::time::interval(PING_INTERVAL);
+        ping.tick().await;
+        
+        loop {
+            let message = tokio::select! {
+                Some(reply) = reply_rx.recv() => {
+                    let Some(text) = reply.render(format).map(str::to_string) else {
+                        continue;
+                    };
+                    Message::Text(text.into())
+                }
+                received = rx.recv() => match received {
+                    Ok(update) => {
+                        if let Some(device_id) = update.message.device_id()
+                            && !subscription_rx.borrow().includes(device_id)
+                        {
+                            continue;
+                        }
+                        let Some(text) = update.render(format) else {
+                            continue;
+                        };
+                        Message::Text(text.into())
+                    }
+                    // skip the backlog entirely and catch up from the newest state per device
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        LAGGED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+                        SKIPPED_MESSAGES.fetch_add(skipped, Ordering::Relaxed);
+                        rx = rx.resubscribe();
+                        let snapshot = resync_snapshot(&subscription_rx.borrow(), skipped);
+                        let Some(text) = WsUpdate::new(snapshot).render(format).map(str::to_string) else {
+                            continue;
+                        };
+                        Message::Text(text.into())
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                },
+                _ = ping.tick() => {
+                    let idle = (connected.elapsed().as_millis() as u64).saturating_sub(send_last_seen.load(Ordering::Relaxed));
+                    if idle > PONG_TIMEOUT.as_millis() as u64 {
+                        DROPPED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+                        break;
+                    }
+                    Message::Ping(Vec::new().into())
+                }
+            };
+            
+            match tokio::time::timeout(SEND_TIMEOUT, sender.send(message)).await {
+                Ok(Ok(())) => {}
+                Ok(Err(_)) => break,
+                Err(_) => {
+                    DROPPED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+                    break;
+                }
             }
         }
     });
     
     let mut recv_task = tokio::spawn(async move {
         while let Some(msg) = receiver.next().await {
+            last_seen.store(connected.elapsed().as_millis() as u64, Ordering::Relaxed);
             match msg {
-                Ok(Message::Text(_)) => {
-
+                Ok(Message::Text(text)) => {
+                    for reply in apply_client_message(&text, &subscription_tx, &scope, &pool).await {
+                        let _ = reply_tx.send(reply);
+                    }
                 }
                 Ok(Message::Close(_)) => break,
+                Err(_) => break,
                 _ => {}
             }
         }
@@ -128,4 +494,6 @@ async fn handle_socket(socket: WebSocket) {
             send_task.abort();
         }
     }
-} 
\ No newline at end of file
+    
+    CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
+}
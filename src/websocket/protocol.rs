@@ -0,0 +1,287 @@
+use askama::Template;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::OnceLock;
+use crate::dose::LiveDose;
+use crate::events::NoiseEvent;
+use crate::schedule::ApplicableLimit;
+
+// bumped whenever a json message changes shape incompatibly
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// `Sec-WebSocket-Protocol` values, the query parameters `format` and `v` select the same thing
+pub const SUBPROTOCOL_JSON: &str = "dbmonitor.v1.json";
+pub const SUBPROTOCOL_HTML: &str = "dbmonitor.v1.html";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Html,
+    Json,
+}
+
+impl OutputFormat {
+    pub fn from_subprotocol(protocol: &str) -> Option<Self> {
+        match protocol {
+            SUBPROTOCOL_JSON => Some(OutputFormat::Json),
+            SUBPROTOCOL_HTML => Some(OutputFormat::Html),
+            _ => None,
+        }
+    }
+
+    pub fn from_query(format: &str) -> Option<Self> {
+        match format {
+            "json" => Some(OutputFormat::Json),
+            "html" => Some(OutputFormat::Html),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct LimitInfo {
+    pub schedule_id: i32,
+    pub schedule: String,
+    pub period: String,
+    pub limit_db: f64,
+    pub exceeded: bool,
+}
+
+impl LimitInfo {
+    pub fn new(limit: ApplicableLimit, decibels: f64) -> Self {
+        Self {
+            exceeded: decibels > limit.limit_db,
+            schedule_id: limit.schedule_id,
+            schedule: limit.schedule,
+            period: limit.period,
+            limit_db: limit.limit_db,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct DoseInfo {
+    pub shift_start: DateTime<Utc>,
+    pub dose_percent: f64,
+    pub twa: Option<f64>,
+    pub exposure_seconds: f64,
+}
+
+impl From<LiveDose> for DoseInfo {
+    fn from(live: LiveDose) -> Self {
+        Self {
+            shift_start: live.shift_start,
+            dose_percent: live.dose_percent,
+            twa: live.twa,
+            exposure_seconds: live.exposure_seconds,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeviceState {
+    Online,
+    Offline,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Alert {
+    NoiseEvent {
+        started_at: DateTime<Utc>,
+        ended_at: DateTime<Utc>,
+        duration_ms: i64,
+        peak_db: f64,
+        leq_db: f64,
+        sel_db: f64,
+        background_db: f64,
+    },
+    LimitExceeded {
+        alert_id: i32,
+        timestamp: DateTime<Utc>,
+        decibels: f64,
+        #[serde(flatten)]
+        limit: LimitInfo,
+    },
+}
+
+impl From<&NoiseEvent> for Alert {
+    fn from(event: &NoiseEvent) -> Self {
+        Alert::NoiseEvent {
+            started_at: event.started_at,
+            ended_at: event.ended_at,
+            duration_ms: event.duration_ms(),
+            peak_db: event.peak_db,
+            leq_db: event.leq_db,
+            sel_db: event.sel_db,
+            background_db: event.background_db,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsMessage {
+    Hello {
+        format: &'static str,
+    },
+    Reading {
+        device_id: i32,
+        decibels: f64,
+        timestamp: DateTime<Utc>,
+        limit: Option<LimitInfo>,
+        dose: Option<DoseInfo>,
+    },
+    Alert {
+        device_id: i32,
+        #[serde(flatten)]
+        alert: Alert,
+    },
+    DeviceStatus {
+        device_id: i32,
+        status: DeviceState,
+        last_seen: DateTime<Utc>,
+    },
+    // acknowledgements and silences, `device_id` is none when a silence covers every device
+    AlertsChanged {
+        device_id: Option<i32>,
+    },
+    Subscription {
+        all: bool,
+        devices: Vec<i32>,
+    },
+    // sent after the client fell behind, `skipped` messages were dropped and `readings` is the newest state
+    Resync {
+        skipped: u64,
+        readings: Vec<WsMessage>,
+    },
+    // closes a resume, counts what was replayed from memory and from decibel_logs
+    Replay {
+        since: u64,
+        buffered: usize,
+        from_database: usize,
+        truncated: bool,
+    },
+    Error {
+        message: String,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    v: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(flatten)]
+    message: &'a WsMessage,
+}
+
+impl WsMessage {
+    pub fn device_id(&self) -> Option<i32> {
+        match self {
+            WsMessage::Reading { device_id, .. }
+            | WsMessage::Alert { device_id, .. }
+            | WsMessage::DeviceStatus { device_id, .. } => Some(*device_id),
+            WsMessage::AlertsChanged { device_id } => *device_id,
+            _ => None,
+        }
+    }
+
+    pub fn to_json(&self, seq: Option<u64>) -> String {
+        serde_json::to_string(&Envelope { v: PROTOCOL_VERSION, seq, message: self })
+            .unwrap_or_else(|e| format!(r#"{{"v":{},"type":"error","message":"{}"}}"#, PROTOCOL_VERSION, e))
+    }
+
+    // htmx oob fragments for the dashboard, messages it has no element for render to nothing
+    pub fn to_html(&self) -> Option<String> {
+        match self {
+            WsMessage::Reading { device_id, decibels, timestamp, dose, .. } => {
+                render_fragment(&ReadingFragment { device_id: *device_id, decibels: *decibels, timestamp, dose })
+            }
+            WsMessage::Alert { device_id, alert: Alert::NoiseEvent { started_at, ended_at, peak_db, sel_db, .. } } => {
+                render_fragment(&NoiseEventFragment {
+                    device_id: *device_id,
+                    started_at,
+                    ended_at,
+                    peak_db: *peak_db,
+                    sel_db: *sel_db,
+                })
+            }
+            WsMessage::Alert { device_id, alert: Alert::LimitExceeded { .. } } => {
+                render_fragment(&AlertUpdateFragment { device_id: Some(*device_id) })
+            }
+            WsMessage::AlertsChanged { device_id } => render_fragment(&AlertUpdateFragment { device_id: *device_id }),
+            WsMessage::Resync { readings, .. } => {
+                Some(readings.iter().filter_map(WsMessage::to_html).collect::<Vec<_>>().join("\n"))
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "fragments/reading.html")]
+struct ReadingFragment<'a> {
+    device_id: i32,
+    decibels: f64,
+    timestamp: &'a DateTime<Utc>,
+    dose: &'a Option<DoseInfo>,
+}
+
+#[derive(Template)]
+#[template(path = "fragments/noise_event.html")]
+struct NoiseEventFragment<'a> {
+    device_id: i32,
+    started_at: &'a DateTime<Utc>,
+    ended_at: &'a DateTime<Utc>,
+    peak_db: f64,
+    sel_db: f64,
+}
+
+#[derive(Template)]
+#[template(path = "fragments/alert_update.html")]
+struct AlertUpdateFragment {
+    device_id: Option<i32>,
+}
+
+fn render_fragment(fragment: &impl Template) -> Option<String> {
+    fragment
+        .render()
+        .map_err(|e| tracing::error!(error = %e, "websocket fragment render error"))
+        .ok()
+}
+
+// a broadcast message with each output format rendered at most once, shared by every socket.
+// broadcast messages carry a sequence number, replies to a single client do not
+#[derive(Debug)]
+pub struct WsUpdate {
+    pub seq: Option<u64>,
+    pub message: WsMessage,
+    html: OnceLock<Option<String>>,
+    json: OnceLock<String>,
+}
+
+impl WsUpdate {
+    pub fn new(message: WsMessage) -> Self {
+        Self {
+            seq: None,
+            message,
+            html: OnceLock::new(),
+            json: OnceLock::new(),
+        }
+    }
+
+    pub fn with_seq(message: WsMessage, seq: u64) -> Self {
+        Self {
+            seq: Some(seq),
+            ..Self::new(message)
+        }
+    }
+
+    pub fn render(&self, format: OutputFormat) -> Option<&str> {
+        match format {
+            OutputFormat::Html => self.html.get_or_init(|| self.message.to_html()).as_deref(),
+            OutputFormat::Json => Some(self.json.get_or_init(|| self.message.to_json(self.seq))),
+        }
+    }
+}
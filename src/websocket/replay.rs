@@ -0,0 +1,310 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock};
+use tokio::sync::broadcast;
+use crate::database::DbPool;
+use super::protocol::{WsMessage, WsUpdate};
+use super::Subscription;
+
+// messages kept per device for resuming clients, about a minute and a half of throttled readings
+pub const RING_CAPACITY: usize = 1000;
+// rings of devices silent this long are dropped, resuming past them falls back to the database
+pub const RING_RETENTION_MINUTES: i64 = 10;
+// most readings a single resume loads from decibel_logs
+pub const MAX_REPLAY_ROWS: i64 = 10_000;
+
+// sequence numbers are microseconds since the epoch, made strictly increasing, so they keep
+// increasing across restarts and a sequence number tells where to look in decibel_logs
+static LAST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+// everything up to this sequence number is no longer buffered, it starts at the first
+// sequence number of this process and rises when rings of idle devices are dropped
+static FORGOTTEN_UP_TO: LazyLock<AtomicU64> = LazyLock::new(|| AtomicU64::new(now_micros()));
+
+struct Ring {
+    evicted_up_to: u64,
+    entries: VecDeque<Arc<WsUpdate>>,
+}
+
+impl Ring {
+    // the buffered messages after `since`, and where the buffer starts when it does not reach back that far
+    fn after(&self, since: u64) -> (Vec<Arc<WsUpdate>>, Option<DateTime<Utc>>) {
+        let gap = (self.evicted_up_to > since).then(|| {
+            self.entries
+                .front()
+                .and_then(|update| update.seq)
+                .map(seq_time)
+                .unwrap_or_else(Utc::now)
+        });
+        let after = self.entries.iter().filter(|update| update.seq > Some(since)).cloned().collect();
+        (after, gap)
+    }
+}
+
+static RINGS: LazyLock<DashMap<i32, Ring>> = LazyLock::new(|| {
+    DashMap::new()
+});
+
+fn now_micros() -> u64 {
+    Utc::now().timestamp_micros().max(0) as u64
+}
+
+fn next_seq() -> u64 {
+    let now = now_micros();
+    let previous = LAST_SEQ
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1)))
+        .unwrap_or_default();
+    now.max(previous + 1)
+}
+
+fn seq_time(seq: u64) -> DateTime<Utc> {
+    DateTime::from_timestamp_micros(seq as i64).unwrap_or_default()
+}
+
+// numbers, buffers and broadcasts a device message. the ring stays locked while numbering
+// so a device's messages are buffered and sent in sequence order
+pub fn publish(sender: &broadcast::Sender<Arc<WsUpdate>>, message: WsMessage) -> Arc<WsUpdate> {
+    let forgotten_up_to = FORGOTTEN_UP_TO.load(Ordering::Relaxed);
+
+    let Some(device_id) = message.device_id() else {
+        let update = Arc::new(WsUpdate::with_seq(message, next_seq()));
+        let _ = sender.send(update.clone());
+        return update;
+    };
+
+    let mut ring = RINGS.entry(device_id).or_insert_with(|| Ring {
+        evicted_up_to: forgotten_up_to,
+        entries: VecDeque::new(),
+    });
+
+    let update = Arc::new(WsUpdate::with_seq(message, next_seq()));
+    if ring.entries.len() >= RING_CAPACITY
+        && let Some(evicted) = ring.entries.pop_front()
+    {
+        ring.evicted_up_to = evicted.seq.unwrap_or_default();
+    }
+    ring.entries.push_back(update.clone());
+
+    let _ = sender.send(update.clone());
+    update
+}
+
+// drops rings of devices that have been silent for RING_RETENTION_MINUTES
+pub fn cleanup_old_entries() {
+    let cutoff = now_micros().saturating_sub(RING_RETENTION_MINUTES as u64 * 60 * 1_000_000);
+
+    RINGS.retain(|_, ring| {
+        let newest = ring.entries.back().and_then(|update| update.seq).unwrap_or_default();
+        if newest > cutoff {
+            return true;
+        }
+        FORGOTTEN_UP_TO.fetch_max(newest, Ordering::Relaxed);
+        false
+    });
+}
+
+// devices without a ring only need the database when `since` predates what is buffered at all
+fn forgotten_since(since: u64) -> bool {
+    FORGOTTEN_UP_TO.load(Ordering::Relaxed) > since
+}
+
+// messages after `since` for the subscribed devices, buffered ones where the ring reaches back
+// far enough and readings from decibel_logs for the rest, followed by a replay summary
+pub async fn resume(pool: &DbPool, subscription: &Subscription, since: u64) -> Vec<Arc<WsUpdate>> {
+    let mut buffered: Vec<Arc<WsUpdate>> = Vec::new();
+    // ring devices missing history after `since`, with the time their buffered history starts
+    let mut gap_devices: Vec<i32> = Vec::new();
+    let mut gap_until: Vec<DateTime<Utc>> = Vec::new();
+    let mut ring_devices: Vec<i32> = Vec::new();
+
+    for ring in RINGS.iter() {
+        let device_id = *ring.key();
+        if !subscription.includes(device_id) {
+            continue;
+        }
+        ring_devices.push(device_id);
+
+        let (after, gap) = ring.after(since);
+        if let Some(until) = gap {
+            gap_devices.push(device_id);
+            gap_until.push(until);
+        }
+        buffered.extend(after);
+    }
+    buffered.sort_by_key(|update| update.seq);
+
+    let forgotten = forgotten_since(since);
+    let subscribed: Option<Vec<i32>> = match subscription {
+        Subscription::All => None,
+        Subscription::Devices(devices) => Some(devices.iter().copied().collect()),
+    };
+
+    let mut replay = Vec::new();
+    let mut truncated = false;
+
+    if !gap_devices.is_empty() || forgotten {
+        match load_readings(pool, seq_time(since), &gap_devices, &gap_until, forgotten, &ring_devices, &subscribed).await {
+            Ok(readings) => {
+                truncated = readings.len() as i64 >= MAX_REPLAY_ROWS;
+                replay = readings;
+            }
+            Err(e) => {
+                tracing::error!(error = %e, "database query error");
+                replay.push(Arc::new(WsUpdate::new(WsMessage::Error {
+                    message: "replay from the database failed, only buffered messages follow".to_string(),
+                })));
+            }
+        }
+    }
+
+    let from_database = replay.len();
+    let buffered_count = buffered.len();
+    replay.extend(buffered);
+    replay.push(Arc::new(WsUpdate::new(WsMessage::Replay {
+        since,
+        buffered: buffered_count,
+        from_database,
+        truncated,
+    })));
+
+    replay
+}
+
+async fn load_readings(
+    pool: &DbPool,
+    since: DateTime<Utc>,
+    gap_devices: &[i32],
+    gap_until: &[DateTime<Utc>],
+    forgotten: bool,
+    ring_devices: &[i32],
+    subscribed: &Option<Vec<i32>>,
+) -> Result<Vec<Arc<WsUpdate>>, String> {
+    let client = pool.get().await.map_err(|e| e.to_string())?;
+
+    // gap devices up to where their ring starts, devices without a ring up to now
+    let rows = client
+        .query(
+            "SELECT l.fk_device_id, l.decibels, l.created_at
+             FROM decibel_logs l
+             LEFT JOIN unnest($2::int[], $3::timestamptz[]) AS gap(device_id, until) ON gap.device_id = l.fk_device_id
+             WHERE l.created_at > $1
+               AND (l.created_at < gap.until
+                    OR ($4 AND gap.device_id IS NULL
+                        AND l.fk_device_id <> ALL($5)
+                        AND ($6::int[] IS NULL OR l.fk_device_id = ANY($6))))
+             ORDER BY l.created_at
+             LIMIT $7",
+            &[&since, &gap_devices, &gap_until, &forgotten, &ring_devices, subscribed, &MAX_REPLAY_ROWS],
+        )
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(rows
+        .iter()
+        .map(|row| {
+            let timestamp: DateTime<Utc> = row.get("created_at");
+            Arc::new(WsUpdate::with_seq(
+                WsMessage::Reading {
+                    device_id: row.get("fk_device_id"),
+                    decibels: row.get("decibels"),
+                    timestamp,
+                    limit: None,
+                    dose: None,
+                },
+                timestamp.timestamp_micros().max(0) as u64,
+            ))
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reading(device_id: i32) -> WsMessage {
+        WsMessage::Reading { device_id, decibels: 50.0, timestamp: Utc::now(), limit: None, dose: None }
+    }
+
+    fn seqs(updates: &[Arc<WsUpdate>]) -> Vec<u64> {
+        updates.iter().filter_map(|update| update.seq).collect()
+    }
+
+    // a ring holding `seqs`, everything up to `evicted_up_to` fell out of it
+    fn ring(evicted_up_to: u64, seqs: &[u64]) -> Ring {
+        Ring {
+            evicted_up_to,
+            entries: seqs.iter().map(|seq| Arc::new(WsUpdate::with_seq(reading(1), *seq))).collect(),
+        }
+    }
+
+    #[test]
+    fn sequence_numbers_are_increasing_epoch_micros() {
+        let before = now_micros();
+        let first = next_seq();
+        let second = next_seq();
+        assert!(first >= before && second > first);
+        assert!((seq_time(first) - Utc::now()).num_seconds().abs() < 5);
+    }
+
+    #[test]
+    fn resume_inside_the_ring_needs_no_database() {
+        let ring = ring(100, &[110, 120, 130, 140]);
+        let (after, gap) = ring.after(120);
+        assert_eq!(seqs(&after), vec![130, 140]);
+        assert_eq!(gap, None);
+        // the last evicted message was seen, the ring covers everything after it
+        let (after, gap) = ring.after(100);
+        assert_eq!(seqs(&after), vec![110, 120, 130, 140]);
+        assert_eq!(gap, None);
+    }
+
+    #[test]
+    fn resume_from_before_the_ring_loads_the_gap() {
+        let ring = ring(100, &[110, 120]);
+        let (after, gap) = ring.after(50);
+        assert_eq!(seqs(&after), vec![110, 120]);
+        // the database fills in up to where the ring starts
+        assert_eq!(gap, Some(seq_time(110)));
+    }
+
+    #[test]
+    fn zero_sequence_replays_the_ring_and_the_database() {
+        let ring = ring(100, &[110, 120]);
+        let (after, gap) = ring.after(0);
+        assert_eq!(seqs(&after), vec![110, 120]);
+        assert_eq!(gap, Some(seq_time(110)));
+        assert!(forgotten_since(0));
+    }
+
+    #[test]
+    fn future_sequence_replays_nothing() {
+        let ring = ring(100, &[110, 120]);
+        let (after, gap) = ring.after(u64::MAX);
+        assert!(after.is_empty());
+        assert_eq!(gap, None);
+        assert!(!forgotten_since(u64::MAX));
+        assert!(!forgotten_since(next_seq()));
+    }
+
+    #[test]
+    fn full_ring_evicts_the_oldest_message() {
+        // a device id no other test publishes to
+        let device_id = -34;
+        let (sender, _receiver) = broadcast::channel(RING_CAPACITY * 2);
+        let published: Vec<u64> = (0..RING_CAPACITY + 5)
+            .map(|_| publish(&sender, reading(device_id)).seq.unwrap())
+            .collect();
+
+        let ring = RINGS.get(&device_id).unwrap();
+        assert_eq!(ring.entries.len(), RING_CAPACITY);
+        assert_eq!(ring.evicted_up_to, published[4]);
+
+        let (after, gap) = ring.after(published[4]);
+        assert_eq!(after.len(), RING_CAPACITY);
+        assert_eq!(gap, None);
+        let (_, gap) = ring.after(published[3]);
+        assert_eq!(gap, Some(seq_time(published[5])));
+    }
+}
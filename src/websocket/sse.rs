@@ -0,0 +1,122 @@
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension,
+};
+use futures_util::stream;
+use serde::Deserialize;
+use std::collections::{HashSet, VecDeque};
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::sync::atomic::Ordering;
+use tokio::sync::broadcast;
+use crate::auth::Scope;
+use crate::database::DbPool;
+use crate::routes::parse_ids;
+use super::protocol::{OutputFormat, WsUpdate};
+use super::{replay, resolve_group_devices, resync_snapshot, Subscription, BROADCAST, LAGGED_CLIENTS, SKIPPED_MESSAGES};
+
+// `?devices=1,2&groups=3`, every device the user can see when both are missing
+#[derive(Deserialize)]
+pub struct StreamParams {
+    devices: Option<String>,
+    groups: Option<String>,
+}
+
+struct StreamState {
+    rx: broadcast::Receiver<Arc<WsUpdate>>,
+    subscription: Subscription,
+    // replayed messages are sent before anything live
+    pending: VecDeque<Arc<WsUpdate>>,
+    // live messages that were already part of the replay
+    replayed: HashSet<u64>,
+}
+
+fn to_event(update: &WsUpdate) -> Option<Event> {
+    let data = update.render(OutputFormat::Json)?;
+    let event = Event::default().data(data);
+    Some(match update.seq {
+        Some(seq) => event.id(seq.to_string()),
+        None => event,
+    })
+}
+
+async fn next_event(mut state: StreamState) -> Option<(Result<Event, Infallible>, StreamState)> {
+    loop {
+        if let Some(update) = state.pending.pop_front() {
+            if let Some(event) = to_event(&update) {
+                return Some((Ok(event), state));
+            }
+            continue;
+        }
+
+        match state.rx.recv().await {
+            Ok(update) => {
+                if let Some(device_id) = update.message.device_id()
+                    && !state.subscription.includes(device_id)
+                {
+                    continue;
+                }
+                if update.seq.is_some_and(|seq| state.replayed.contains(&seq)) {
+                    continue;
+                }
+                if let Some(event) = to_event(&update) {
+                    return Some((Ok(event), state));
+                }
+            }
+            // same as the websocket, skip the backlog and send the newest reading per device
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                LAGGED_CLIENTS.fetch_add(1, Ordering::Relaxed);
+                SKIPPED_MESSAGES.fetch_add(skipped, Ordering::Relaxed);
+                state.rx = state.rx.resubscribe();
+                let snapshot = resync_snapshot(&state.subscription, skipped);
+                state.pending.push_back(Arc::new(WsUpdate::new(snapshot)));
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+// the websocket json messages as server-sent events, for clients behind proxies that break upgrades.
+// a reconnecting EventSource sends Last-Event-ID and gets what it missed, like a websocket resume
+pub async fn stream_handler(
+    State(pool): State<DbPool>,
+    Extension(scope): Extension<Scope>,
+    Query(params): Query<StreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    let (devices, groups) = match (parse_ids(params.devices.as_deref()), parse_ids(params.groups.as_deref())) {
+        (Ok(devices), Ok(groups)) => (devices, groups),
+        (Err(e), _) | (_, Err(e)) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    let last_event_id = match headers.get("last-event-id").map(|value| value.to_str().ok().and_then(|id| id.trim().parse().ok())) {
+        None => None,
+        Some(Some(seq)) => Some(seq),
+        Some(None) => return (StatusCode::BAD_REQUEST, "Last-Event-ID must be a sequence number").into_response(),
+    };
+
+    let mut subscription = if devices.is_empty() && groups.is_empty() {
+        Subscription::All
+    } else {
+        let mut subscribed: HashSet<i32> = devices.into_iter().collect();
+        subscribed.extend(resolve_group_devices(&pool, &groups).await);
+        Subscription::Devices(subscribed)
+    };
+    subscription.restrict(&scope);
+
+    // subscribe before replaying so nothing published in between is lost
+    let rx = BROADCAST.subscribe();
+    let pending: VecDeque<Arc<WsUpdate>> = match last_event_id {
+        Some(since) => replay::resume(&pool, &subscription, since).await.into(),
+        None => VecDeque::new(),
+    };
+    let replayed: HashSet<u64> = pending.iter().filter_map(|update| update.seq).collect();
+
+    let state = StreamState { rx, subscription, pending, replayed };
+    Sse::new(stream::unfold(state, next_event)).keep_alive(KeepAlive::default()).into_response()
+}